@@ -0,0 +1,24 @@
+//! Feeds arbitrary bytes to `Trie::read_from` and `FrozenTrie::new`, which must either reject
+//! them or produce a trie that's safe to use.
+//!
+//! Run with `cargo +nightly fuzz run read_from`.
+#![no_main]
+
+use baobab::{BytesCodec, FrozenTrie, Trie};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(trie) = Trie::<Vec<u8>>::read_from(data, BytesCodec) {
+        trie.check_invariants().unwrap();
+        let mut buf = vec![];
+        trie.write_to(&mut buf, BytesCodec).unwrap();
+        // Each trie has a single encoding, so it writes back out to the bytes it was read from.
+        assert!(data.starts_with(&buf));
+    }
+    if let Ok(frozen) = FrozenTrie::new(data) {
+        assert_eq!(frozen.iter().count(), frozen.len());
+        for (key, value) in frozen.iter() {
+            assert_eq!(frozen.get(&key), Some(value));
+        }
+    }
+});
@@ -0,0 +1,188 @@
+//! Runs arbitrary sequences of operations against a `Trie` and a `BTreeMap` model, checking that
+//! they agree and that the trie's invariants hold after every step.  The values track their own
+//! drops, so a value that the trie's packing code drops twice or leaks fails the run too.
+//!
+//! Run with `cargo +nightly fuzz run trie_ops`.
+#![no_main]
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::ops::Bound;
+
+use arbitrary::Arbitrary;
+use baobab::{FrozenTrie, Trie, ValueCodec};
+use libfuzzer_sys::fuzz_target;
+
+thread_local! {
+    // The ids of the `Tracked` values that are currently alive.
+    static LIVE: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+#[derive(Debug)]
+struct Tracked {
+    id: u64,
+    bytes: Vec<u8>,
+}
+
+impl Tracked {
+    fn new(bytes: Vec<u8>) -> Self {
+        let id = NEXT_ID.with(|next_id| next_id.replace(next_id.get() + 1));
+        LIVE.with(|live| live.borrow_mut().insert(id));
+        Self { id, bytes }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let was_live = LIVE.with(|live| live.borrow_mut().remove(&self.id));
+        assert!(was_live, "value {} was dropped twice", self.id);
+    }
+}
+
+struct TrackedCodec;
+
+impl ValueCodec<Tracked> for TrackedCodec {
+    fn encode(&self, value: &Tracked, out: &mut Vec<u8>) {
+        out.extend_from_slice(&value.bytes);
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Tracked, Box<dyn Error + Send + Sync>> {
+        Ok(Tracked::new(bytes.to_owned()))
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Key {
+    New(Vec<u8>),
+    // One of the keys in the model, so we hit overwrites and removals.
+    Existing(u16),
+    // A prefix of one of the keys in the model with a new suffix, so we split and branch nodes.
+    Branch { existing: u16, len: u16, suffix: Vec<u8> },
+}
+
+impl Key {
+    fn resolve(self, model: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+        let existing = |i: u16| model.keys().nth(i as usize % model.len().max(1)).cloned();
+        match self {
+            Key::New(key) => key,
+            Key::Existing(i) => existing(i).unwrap_or_default(),
+            Key::Branch { existing: i, len, suffix } => {
+                let mut key = existing(i).unwrap_or_default();
+                key.truncate(len as usize);
+                key.extend_from_slice(&suffix);
+                key
+            }
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(Key, Vec<u8>),
+    Get(Key),
+    GetMut(Key, Vec<u8>),
+    Remove(Key),
+    Iter,
+    // `Trie` doesn't have range or prefix queries of its own yet, so these check the ones on a
+    // `FrozenTrie` written from it.
+    Range { start: Key, end: Key, inclusive: bool },
+    Prefix(Key),
+    // Save and reload the trie, and bulk load a copy of it.
+    Reload,
+}
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn entries(trie: &Trie<Tracked>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    trie.iter().map(|(k, v)| (k, v.bytes.clone())).collect()
+}
+
+fn model_entries<'a>(entries: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>) -> Model {
+    entries.map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+fn step(trie: &mut Trie<Tracked>, model: &mut Model, op: Op) {
+    match op {
+        Op::Insert(key, value) => {
+            let key = key.resolve(model);
+            let old_value = trie.insert(&key, Tracked::new(value.clone()));
+            assert_eq!(old_value.map(|v| v.bytes.clone()), model.insert(key, value));
+        }
+        Op::Get(key) => {
+            let key = key.resolve(model);
+            assert_eq!(trie.get(&key).map(|v| &v.bytes), model.get(&key));
+        }
+        Op::GetMut(key, value) => {
+            let key = key.resolve(model);
+            match (trie.get_mut(&key), model.get_mut(&key)) {
+                (Some(v), Some(m)) => {
+                    v.bytes = value.clone();
+                    *m = value;
+                }
+                (None, None) => (),
+                (v, m) => panic!("get_mut mismatch for {:?}: {:?} vs {:?}", key, v, m),
+            }
+        }
+        Op::Remove(key) => {
+            let key = key.resolve(model);
+            assert_eq!(trie.remove(&key).map(|v| v.bytes.clone()), model.remove(&key));
+        }
+        Op::Iter => {
+            assert_eq!(entries(trie), model_entries(model.iter()).into_iter().collect::<Vec<_>>());
+        }
+        Op::Range { start, end, inclusive } => {
+            let (mut start, mut end) = (start.resolve(model), end.resolve(model));
+            if start > end {
+                std::mem::swap(&mut start, &mut end);
+            }
+            let end_bound = if inclusive { Bound::Included(&end) } else { Bound::Excluded(&end) };
+            let bounds = (Bound::Included(&start), end_bound);
+            let mut buf = vec![];
+            trie.write_frozen(&mut buf, TrackedCodec).unwrap();
+            let frozen = FrozenTrie::new(&buf).unwrap();
+            let actual = frozen.range::<Vec<u8>, _>(bounds).map(|(k, v)| (k, v.to_vec()));
+            assert_eq!(actual.collect::<Model>(), model_entries(model.range::<Vec<u8>, _>(bounds)));
+        }
+        Op::Prefix(key) => {
+            let prefix = key.resolve(model);
+            let mut buf = vec![];
+            trie.write_frozen(&mut buf, TrackedCodec).unwrap();
+            let frozen = FrozenTrie::new(&buf).unwrap();
+            let actual: Model = frozen.iter_prefix(&prefix).map(|(k, v)| (k, v.to_vec())).collect();
+            let expected = model_entries(model.iter().filter(|(k, _)| k.starts_with(&prefix)));
+            assert_eq!(actual, expected);
+        }
+        Op::Reload => {
+            let mut buf = vec![];
+            trie.write_to(&mut buf, TrackedCodec).unwrap();
+            let reloaded = Trie::read_from(&buf[..], TrackedCodec).unwrap();
+            reloaded.check_invariants().unwrap();
+            assert_eq!(entries(&reloaded), entries(trie));
+
+            let sorted = model.iter().map(|(k, v)| (k, Tracked::new(v.clone())));
+            let bulk_loaded = Trie::from_sorted(sorted);
+            bulk_loaded.check_invariants().unwrap();
+            assert_eq!(entries(&bulk_loaded), entries(trie));
+            *trie = bulk_loaded;
+        }
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    {
+        let mut trie = Trie::new();
+        let mut model = BTreeMap::new();
+        for op in ops {
+            step(&mut trie, &mut model, op);
+            trie.check_invariants().unwrap();
+            assert_eq!(trie.len(), model.len());
+        }
+        assert_eq!(entries(&trie), model.into_iter().collect::<Vec<_>>());
+    }
+    LIVE.with(|live| {
+        let live = live.borrow();
+        assert!(live.is_empty(), "leaked {} values", live.len());
+    });
+});
@@ -0,0 +1,362 @@
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::ptr::{self, NonNull};
+
+/// Allocates the buffers that back a trie's nodes.  Nodes don't hold on to their allocator, so
+/// the trie passes it back in whenever a node is freed.
+///
+/// # Safety
+/// `alloc_zeroed` must return zeroed memory that satisfies `layout` and stays valid until it's
+/// passed back to `dealloc` or the allocator is dropped.
+pub unsafe trait Allocator {
+    /// Allocators whose `dealloc` doesn't do anything, like `Bump`, can set this to let a trie
+    /// skip walking its nodes on drop when its values don't need dropping either.
+    const NOOP_DEALLOC: bool = false;
+
+    /// Allocate a zeroed buffer for `layout`, which always has a nonzero size.  Returns `None`
+    /// if the allocation failed.
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// Free a buffer previously returned by `alloc_zeroed`.
+    ///
+    /// # Safety
+    /// `ptr` must have come from this allocator's `alloc_zeroed` with the same `layout`, and it
+    /// can't be used again afterwards.
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+unsafe impl<A: Allocator> Allocator for &A {
+    const NOOP_DEALLOC: bool = A::NOOP_DEALLOC;
+
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        (**self).alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        (**self).dealloc(ptr, layout)
+    }
+}
+
+/// The error returned by `Trie::try_insert` and friends when the allocator couldn't allocate a
+/// node.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AllocError {
+    layout: Layout,
+}
+
+impl AllocError {
+    pub(crate) fn new(layout: Layout) -> Self {
+        Self { layout }
+    }
+
+    /// The layout of the node buffer that couldn't be allocated.
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "memory allocation of {} bytes failed", self.layout.size())
+    }
+}
+
+impl Error for AllocError {}
+
+/// The global allocator, as used by `Box` and `Vec`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        alloc::dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+const BUMP_CHUNK_SIZE: usize = 64 * 1024;
+const BUMP_CHUNK_ALIGN: usize = 16;
+
+/// An arena that hands out node buffers from large chunks and never frees them individually.
+/// All of its memory is released at once when the arena is dropped, so it's a good fit for tries
+/// that are built up, queried, and then thrown away.  Use `&Bump` to share one arena between
+/// several tries.
+pub struct Bump {
+    chunks: RefCell<Vec<(NonNull<u8>, Layout)>>,
+    cursor: Cell<*mut u8>,
+    end: Cell<*mut u8>,
+}
+
+impl Bump {
+    pub fn new() -> Self {
+        Self {
+            chunks: RefCell::new(vec![]),
+            cursor: Cell::new(ptr::null_mut()),
+            end: Cell::new(ptr::null_mut()),
+        }
+    }
+
+    /// Total size of the chunks the arena has allocated.
+    pub fn allocated_bytes(&self) -> usize {
+        self.chunks.borrow().iter().map(|(_, layout)| layout.size()).sum()
+    }
+
+    fn alloc_chunk(&self, layout: Layout) -> Option<()> {
+        let size = cmp::max(BUMP_CHUNK_SIZE, layout.size() + layout.align());
+        let chunk_layout = Layout::from_size_align(size, BUMP_CHUNK_ALIGN).ok()?;
+        let chunk = NonNull::new(unsafe { alloc::alloc_zeroed(chunk_layout) })?;
+        self.chunks.borrow_mut().push((chunk, chunk_layout));
+        self.cursor.set(chunk.as_ptr());
+        self.end.set(unsafe { chunk.as_ptr().add(size) });
+        Some(())
+    }
+}
+
+// Like `Slab`, a `Bump` owns its chunks, but bumping through `&Bump` isn't synchronized.
+unsafe impl Send for Bump {}
+
+impl Default for Bump {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl Allocator for Bump {
+    const NOOP_DEALLOC: bool = true;
+
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let fits = |cursor: *mut u8| {
+            let offset = cursor.align_offset(layout.align());
+            let remaining = self.end.get() as usize - cursor as usize;
+            if offset.checked_add(layout.size())? <= remaining {
+                Some(offset)
+            } else {
+                None
+            }
+        };
+        let offset = match fits(self.cursor.get()) {
+            Some(offset) => offset,
+            None => {
+                self.alloc_chunk(layout)?;
+                fits(self.cursor.get())?
+            }
+        };
+        // Chunks start out zeroed and we never reuse memory, so there's nothing to clear here.
+        let p = unsafe { self.cursor.get().add(offset) };
+        self.cursor.set(unsafe { p.add(layout.size()) });
+        NonNull::new(p)
+    }
+
+    unsafe fn dealloc(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+}
+
+impl Drop for Bump {
+    fn drop(&mut self) {
+        for (chunk, layout) in self.chunks.get_mut().drain(..) {
+            unsafe { alloc::dealloc(chunk.as_ptr(), layout) };
+        }
+    }
+}
+
+/// Wraps another allocator, keeping track of how much it's currently holding on to.  This is
+/// mostly useful in tests for checking that nodes aren't leaked.
+pub struct Counting<A = Global> {
+    inner: A,
+    allocations: Cell<usize>,
+    deallocations: Cell<usize>,
+    live_bytes: Cell<usize>,
+    peak_bytes: Cell<usize>,
+}
+
+impl<A: Allocator> Counting<A> {
+    pub fn new(inner: A) -> Self {
+        Self {
+            inner,
+            allocations: Cell::new(0),
+            deallocations: Cell::new(0),
+            live_bytes: Cell::new(0),
+            peak_bytes: Cell::new(0),
+        }
+    }
+
+    /// Total number of allocations made, including ones that have since been freed.
+    pub fn total_allocations(&self) -> usize {
+        self.allocations.get()
+    }
+
+    /// Number of allocations that haven't been freed yet.
+    pub fn live_allocations(&self) -> usize {
+        self.allocations.get() - self.deallocations.get()
+    }
+
+    /// Bytes in allocations that haven't been freed yet.
+    pub fn live_bytes(&self) -> usize {
+        self.live_bytes.get()
+    }
+
+    /// High water mark of `live_bytes`.
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes.get()
+    }
+}
+
+impl Default for Counting<Global> {
+    fn default() -> Self {
+        Self::new(Global)
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for Counting<A> {
+    const NOOP_DEALLOC: bool = A::NOOP_DEALLOC;
+
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let p = self.inner.alloc_zeroed(layout)?;
+        self.allocations.set(self.allocations.get() + 1);
+        self.live_bytes.set(self.live_bytes.get() + layout.size());
+        self.peak_bytes.set(cmp::max(self.peak_bytes.get(), self.live_bytes.get()));
+        Some(p)
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.deallocations.set(self.deallocations.get() + 1);
+        self.live_bytes.set(self.live_bytes.get() - layout.size());
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// Wraps another allocator, making its allocations fail on demand.  This is for testing how code
+/// copes with running out of memory: run an operation with `fail_after(0)`, `fail_after(1)`, and
+/// so on until it succeeds to hit every allocation it makes.
+pub struct Failing<A = Global> {
+    inner: A,
+    // Allocations left before we start failing, or `None` if we never fail.
+    remaining: Cell<Option<usize>>,
+    failures: Cell<usize>,
+}
+
+impl<A: Allocator> Failing<A> {
+    pub fn new(inner: A) -> Self {
+        Self { inner, remaining: Cell::new(None), failures: Cell::new(0) }
+    }
+
+    /// Let the next `n` allocations through and fail every one after that.
+    pub fn fail_after(&self, n: usize) {
+        self.remaining.set(Some(n));
+    }
+
+    /// Stop failing allocations.
+    pub fn never_fail(&self) {
+        self.remaining.set(None);
+    }
+
+    /// Number of allocations that have failed so far.
+    pub fn failures(&self) -> usize {
+        self.failures.get()
+    }
+}
+
+impl Default for Failing<Global> {
+    fn default() -> Self {
+        Self::new(Global)
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for Failing<A> {
+    const NOOP_DEALLOC: bool = A::NOOP_DEALLOC;
+
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        match self.remaining.get() {
+            Some(0) => {
+                self.failures.set(self.failures.get() + 1);
+                return None;
+            }
+            Some(n) => self.remaining.set(Some(n - 1)),
+            None => (),
+        }
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bump, Counting};
+    use crate::header::NodeHeader;
+    use crate::packable::Header;
+    use crate::Trie;
+
+    fn keys() -> impl Iterator<Item = Vec<u8>> {
+        (0..2000u32).map(|i| format!("key/{}/{}", i % 7, i).into_bytes())
+    }
+
+    #[test]
+    fn test_counting() {
+        let alloc = Counting::default();
+        let mut t = Trie::new_in(&alloc);
+        let node_size = |prefix_len, num_children, has_value| {
+            NodeHeader::<usize>::new(prefix_len, num_children, has_value).layout().size()
+        };
+        // A key too long to be stored inline gets a leaf of its own.
+        t.insert(b"key/0/0", 0);
+        assert_eq!(alloc.live_allocations(), 1);
+        assert_eq!(alloc.live_bytes(), node_size(7, 0, true));
+        // Adding a sibling replaces it with their shared prefix and two leaves below it.
+        t.insert(b"key/0/1", 1);
+        assert_eq!(alloc.live_allocations(), 3);
+        let two_keys = node_size(6, 2, false) + 2 * node_size(0, 0, true);
+        assert_eq!(alloc.live_bytes(), two_keys);
+        // The old leaf was freed before its replacements were allocated.
+        assert_eq!(alloc.peak_bytes(), two_keys);
+        // Removing the sibling merges the other leaf back into a single node.
+        assert_eq!(t.remove(b"key/0/1"), Some(1));
+        assert_eq!(alloc.total_allocations(), 5);
+        assert_eq!(alloc.live_bytes(), node_size(7, 0, true));
+        assert_eq!(alloc.peak_bytes(), two_keys);
+        assert_eq!(t.remove(b"key/0/0"), Some(0));
+        assert_eq!(alloc.live_bytes(), 0);
+
+        for k in keys() {
+            t.insert(&k, k.len());
+        }
+        let peak_bytes = alloc.peak_bytes();
+        for k in keys().step_by(2) {
+            assert_eq!(t.remove(&k), Some(k.len()));
+        }
+        for k in keys().skip(1).step_by(2) {
+            assert_eq!(t.get(&k), Some(&k.len()));
+        }
+        assert!(alloc.live_bytes() < peak_bytes);
+        drop(t);
+        assert_eq!(alloc.live_allocations(), 0);
+        assert_eq!(alloc.live_bytes(), 0);
+    }
+
+    #[test]
+    fn test_bump() {
+        let bump = Bump::new();
+        let mut t = Trie::new_in(&bump);
+        for k in keys() {
+            t.insert(&k, k.clone());
+        }
+        for k in keys() {
+            assert_eq!(t.get(&k), Some(&k));
+        }
+        assert!(bump.allocated_bytes() > 0);
+
+        let counting = Counting::new(Bump::new());
+        let mut t = Trie::new_in(counting);
+        for k in keys() {
+            t.insert(&k, ());
+        }
+        assert!(t.allocator().live_bytes() > 0);
+    }
+}
@@ -0,0 +1,400 @@
+// `Trie::search` runs an automaton over the trie rather than over each key separately: the walk
+// feeds each byte on the way down to a node through the automaton once, and a node's children
+// all start from the state it left off in.  Nodes' compressed prefixes get fed byte by byte too,
+// so a subtree is skipped as soon as the automaton can't match any key in it, even partway
+// through a prefix.
+
+use crate::allocator::Allocator;
+use crate::packed_node::PackedNode;
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+/// A deterministic automaton over key bytes, which picks out the keys that `Trie::search`
+/// returns.
+pub trait Automaton {
+    /// Where the automaton is after reading some bytes.
+    type State: Clone;
+
+    /// The state before reading any bytes.
+    fn start(&self) -> Self::State;
+
+    /// The state after reading `byte` in `state`.
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State;
+
+    /// Whether the bytes that led to `state` make up a matching key.
+    fn is_match(&self, state: &Self::State) -> bool;
+
+    /// Whether any key starting with the bytes that led to `state` can match.  When this is
+    /// false, `Trie::search` skips the rest of the subtree, so automata should return false as
+    /// early as they can.
+    fn can_match(&self, _state: &Self::State) -> bool {
+        true
+    }
+
+    /// Whether every key starting with the bytes that led to `state` matches.  `complement`
+    /// uses this to stop early.
+    fn will_always_match(&self, _state: &Self::State) -> bool {
+        false
+    }
+
+    /// Match the keys that either automaton matches.
+    fn union<B: Automaton>(self, other: B) -> Union<Self, B>
+    where
+        Self: Sized,
+    {
+        Union(self, other)
+    }
+
+    /// Match the keys that both automata match.
+    fn intersection<B: Automaton>(self, other: B) -> Intersection<Self, B>
+    where
+        Self: Sized,
+    {
+        Intersection(self, other)
+    }
+
+    /// Match the keys that this automaton doesn't.
+    fn complement(self) -> Complement<Self>
+    where
+        Self: Sized,
+    {
+        Complement(self)
+    }
+
+    /// Match the keys that start with a key this automaton matches.
+    fn starts_with(self) -> StartsWith<Self>
+    where
+        Self: Sized,
+    {
+        StartsWith(self)
+    }
+}
+
+impl<M: Automaton> Automaton for &M {
+    type State = M::State;
+
+    fn start(&self) -> M::State {
+        (*self).start()
+    }
+
+    fn accept(&self, state: &M::State, byte: u8) -> M::State {
+        (*self).accept(state, byte)
+    }
+
+    fn is_match(&self, state: &M::State) -> bool {
+        (*self).is_match(state)
+    }
+
+    fn can_match(&self, state: &M::State) -> bool {
+        (*self).can_match(state)
+    }
+
+    fn will_always_match(&self, state: &M::State) -> bool {
+        (*self).will_always_match(state)
+    }
+}
+
+/// Matches the keys that start with some bytes.
+#[derive(Clone, Debug)]
+pub struct Prefix<'a> {
+    prefix: &'a [u8],
+}
+
+impl<'a> Prefix<'a> {
+    pub fn new(prefix: &'a [u8]) -> Self {
+        Self { prefix }
+    }
+}
+
+impl Automaton for Prefix<'_> {
+    // How many bytes of the prefix have been read, or `None` once a byte didn't match.
+    type State = Option<usize>;
+
+    fn start(&self) -> Option<usize> {
+        Some(0)
+    }
+
+    fn accept(&self, &state: &Option<usize>, byte: u8) -> Option<usize> {
+        match state? {
+            i if i == self.prefix.len() => Some(i),
+            i if self.prefix[i] == byte => Some(i + 1),
+            _ => None,
+        }
+    }
+
+    fn is_match(&self, &state: &Option<usize>) -> bool {
+        state == Some(self.prefix.len())
+    }
+
+    fn can_match(&self, state: &Option<usize>) -> bool {
+        state.is_some()
+    }
+
+    fn will_always_match(&self, state: &Option<usize>) -> bool {
+        self.is_match(state)
+    }
+}
+
+/// Matches the keys that contain some bytes in order, though not necessarily next to each
+/// other, like fuzzy file finders do.
+#[derive(Clone, Debug)]
+pub struct Subsequence<'a> {
+    subsequence: &'a [u8],
+}
+
+impl<'a> Subsequence<'a> {
+    pub fn new(subsequence: &'a [u8]) -> Self {
+        Self { subsequence }
+    }
+}
+
+impl Automaton for Subsequence<'_> {
+    // How many bytes of the subsequence have been found so far.
+    type State = usize;
+
+    fn start(&self) -> usize {
+        0
+    }
+
+    fn accept(&self, &state: &usize, byte: u8) -> usize {
+        match self.subsequence.get(state) {
+            Some(&b) if b == byte => state + 1,
+            _ => state,
+        }
+    }
+
+    fn is_match(&self, &state: &usize) -> bool {
+        state == self.subsequence.len()
+    }
+
+    fn will_always_match(&self, state: &usize) -> bool {
+        self.is_match(state)
+    }
+}
+
+/// Matches the keys that either of two automata match.  See `Automaton::union`.
+#[derive(Clone, Debug)]
+pub struct Union<A, B>(A, B);
+
+impl<A: Automaton, B: Automaton> Automaton for Union<A, B> {
+    type State = (A::State, B::State);
+
+    fn start(&self) -> Self::State {
+        (self.0.start(), self.1.start())
+    }
+
+    fn accept(&self, (a, b): &Self::State, byte: u8) -> Self::State {
+        (self.0.accept(a, byte), self.1.accept(b, byte))
+    }
+
+    fn is_match(&self, (a, b): &Self::State) -> bool {
+        self.0.is_match(a) || self.1.is_match(b)
+    }
+
+    fn can_match(&self, (a, b): &Self::State) -> bool {
+        self.0.can_match(a) || self.1.can_match(b)
+    }
+
+    fn will_always_match(&self, (a, b): &Self::State) -> bool {
+        self.0.will_always_match(a) || self.1.will_always_match(b)
+    }
+}
+
+/// Matches the keys that both of two automata match.  See `Automaton::intersection`.
+#[derive(Clone, Debug)]
+pub struct Intersection<A, B>(A, B);
+
+impl<A: Automaton, B: Automaton> Automaton for Intersection<A, B> {
+    type State = (A::State, B::State);
+
+    fn start(&self) -> Self::State {
+        (self.0.start(), self.1.start())
+    }
+
+    fn accept(&self, (a, b): &Self::State, byte: u8) -> Self::State {
+        (self.0.accept(a, byte), self.1.accept(b, byte))
+    }
+
+    fn is_match(&self, (a, b): &Self::State) -> bool {
+        self.0.is_match(a) && self.1.is_match(b)
+    }
+
+    fn can_match(&self, (a, b): &Self::State) -> bool {
+        self.0.can_match(a) && self.1.can_match(b)
+    }
+
+    fn will_always_match(&self, (a, b): &Self::State) -> bool {
+        self.0.will_always_match(a) && self.1.will_always_match(b)
+    }
+}
+
+/// Matches the keys that an automaton doesn't.  See `Automaton::complement`.
+#[derive(Clone, Debug)]
+pub struct Complement<A>(A);
+
+impl<A: Automaton> Automaton for Complement<A> {
+    type State = A::State;
+
+    fn start(&self) -> A::State {
+        self.0.start()
+    }
+
+    fn accept(&self, state: &A::State, byte: u8) -> A::State {
+        self.0.accept(state, byte)
+    }
+
+    fn is_match(&self, state: &A::State) -> bool {
+        !self.0.is_match(state)
+    }
+
+    fn can_match(&self, state: &A::State) -> bool {
+        !self.0.will_always_match(state)
+    }
+
+    fn will_always_match(&self, state: &A::State) -> bool {
+        !self.0.can_match(state)
+    }
+}
+
+/// Matches the keys that start with a key an automaton matches.  See `Automaton::starts_with`.
+#[derive(Clone, Debug)]
+pub struct StartsWith<A>(A);
+
+impl<A: Automaton> StartsWith<A> {
+    // Once some of the bytes read so far matched, we don't need to run the automaton anymore.
+    fn check(&self, state: A::State) -> Option<A::State> {
+        if self.0.is_match(&state) {
+            return None;
+        }
+        Some(state)
+    }
+}
+
+impl<A: Automaton> Automaton for StartsWith<A> {
+    // The wrapped automaton's state, or `None` once it matched.
+    type State = Option<A::State>;
+
+    fn start(&self) -> Self::State {
+        self.check(self.0.start())
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        let state = state.as_ref()?;
+        self.check(self.0.accept(state, byte))
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        state.is_none()
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        state.as_ref().is_none_or(|s| self.0.can_match(s))
+    }
+
+    fn will_always_match(&self, state: &Self::State) -> bool {
+        state.as_ref().is_none_or(|s| self.0.will_always_match(s))
+    }
+}
+
+// Add the entries below `node` whose keys `automaton` matches, where `key` holds the bytes
+// leading up to `node` and `state` is where they left the automaton.
+pub fn search_node<'a, T, A, S: Summary<T>, M: Automaton>(
+    node: &'a PackedNode<T, A, S>,
+    automaton: &M,
+    mut state: M::State,
+    key: &mut Vec<u8>,
+    out: &mut Vec<(Vec<u8>, &'a T)>,
+) {
+    let key_len = key.len();
+    for &byte in node.prefix() {
+        key.push(byte);
+        state = automaton.accept(&state, byte);
+        if !automaton.can_match(&state) {
+            key.truncate(key_len);
+            return;
+        }
+    }
+    if let Some(value) = node.value() {
+        if automaton.is_match(&state) {
+            out.push((key.clone(), value));
+        }
+    }
+    for (byte, child) in node.children() {
+        let child_state = automaton.accept(&state, byte);
+        if automaton.can_match(&child_state) {
+            key.push(byte);
+            search_node(child, automaton, child_state, key, out);
+            key.pop();
+        }
+    }
+    key.truncate(key_len);
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Find the entries whose keys `automaton` matches, in key order.  This only visits the
+    /// nodes along keys that the automaton can still match, so it's cheap to search for keys
+    /// with a given prefix, matching a regular expression, and so on.
+    pub fn search<M: Automaton>(&self, automaton: M) -> Vec<(Vec<u8>, &T)> {
+        let mut out = vec![];
+        let start = automaton.start();
+        if !self.root.is_empty() && automaton.can_match(&start) {
+            search_node(&self.root, &automaton, start, &mut vec![], &mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Automaton, Prefix, Subsequence};
+    use crate::{Regex, Trie};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // Run `automaton` over `key` directly, without any pruning.
+    fn matches(automaton: &impl Automaton, key: &[u8]) -> bool {
+        let state = key.iter().fold(automaton.start(), |s, &b| automaton.accept(&s, b));
+        automaton.is_match(&state)
+    }
+
+    fn check_search(t: &Trie<u32>, automaton: impl Automaton) -> Vec<Vec<u8>> {
+        let found = t.search(&automaton);
+        let expected = t.iter().filter(|(k, _)| matches(&automaton, k)).collect::<Vec<_>>();
+        assert_eq!(found, expected);
+        found.into_iter().map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn test_search() {
+        let mut t = Trie::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        for i in 0..2000 {
+            let len = rng.gen_range(0, 8);
+            let key = (0..len).map(|_| rng.gen_range(b'a', b'e')).collect::<Vec<_>>();
+            t.insert(&key, i);
+        }
+        for i in 0..100 {
+            t.insert(format!("long/{}/{}", "x".repeat(80), i).as_bytes(), i);
+        }
+
+        let found = check_search(&t, Prefix::new(b"abc"));
+        assert!(!found.is_empty() && found.iter().all(|k| k.starts_with(b"abc")));
+        assert_eq!(check_search(&t, Prefix::new(b"long/x")).len(), 100);
+        assert_eq!(check_search(&t, Prefix::new(b"")).len(), t.len());
+        check_search(&t, Subsequence::new(b"ace"));
+        // 9, 19, ..., 89, and 90 through 99.
+        assert_eq!(check_search(&t, Subsequence::new(b"l/9")).len(), 19);
+
+        let a = Prefix::new(b"ab");
+        let b = Subsequence::new(b"dd");
+        check_search(&t, a.clone().union(b.clone()));
+        check_search(&t, a.clone().intersection(b.clone()));
+        let not_a = check_search(&t, a.clone().complement());
+        assert_eq!(not_a.len() + check_search(&t, a.clone()).len(), t.len());
+        check_search(&t, b.clone().complement().intersection(a.clone().complement()));
+        check_search(&t, Regex::new("c[ab]*").unwrap().starts_with());
+        check_search(&t, Regex::new("a|b").unwrap().starts_with().complement());
+        check_search(&t, Regex::new("(ab|cd)+").unwrap().union(Prefix::new(b"long")));
+        assert!(Trie::<u32>::new().search(Prefix::new(b"")).is_empty());
+    }
+}
@@ -1,4 +1,7 @@
+use std::fmt;
+
 /// This specialized bitset stores exactly 256 bits, all defaulting to zero.
+#[derive(Clone, Copy)]
 #[repr(packed)]
 pub struct Bitset {
     bits: [u64; 4],
@@ -15,6 +18,11 @@ impl Bitset {
         self.bits[byte as usize / 64] |= 1 << (byte % 64);
     }
 
+    /// Check whether the `byte`th bit is set.
+    pub fn contains(&self, byte: u8) -> bool {
+        self.bits[byte as usize / 64] & (1 << (byte % 64)) != 0
+    }
+
     /// If the `byte`th bit is set, return the number of bits set to the left of `byte`.
     pub fn query(&self, byte: u8) -> Option<usize> {
         if self.bits[byte as usize / 64] & (1 << (byte % 64)) == 0 {
@@ -38,3 +46,10 @@ impl Bitset {
             .map(|(i, j)| i as u8 * 64 + j as u8)
     }
 }
+
+// Shows the set bits' positions, like a `BTreeSet<u8>` of them would.
+impl fmt::Debug for Bitset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+use crate::allocator::{Allocator, Global};
+use crate::node::{Node, NodeChildren};
+use crate::packed_node::PackedNode;
+use crate::prefix;
+use crate::trie::Trie;
+
+impl<T> Trie<T> {
+    /// Build a trie from entries whose keys are strictly increasing.  See `from_sorted_in`.
+    pub fn from_sorted<K: AsRef<[u8]>>(entries: impl IntoIterator<Item = (K, T)>) -> Self {
+        Self::from_sorted_in(entries, Global)
+    }
+}
+
+impl<T, A: Allocator> Trie<T, A> {
+    /// Build a trie from entries whose keys are strictly increasing.  Since the keys are sorted,
+    /// each node is allocated exactly once with its final children, rather than being rebuilt
+    /// as keys get inserted below it.
+    ///
+    /// # Panics
+    /// Panics if the keys aren't strictly increasing.
+    pub fn from_sorted_in<K: AsRef<[u8]>>(
+        entries: impl IntoIterator<Item = (K, T)>,
+        alloc: A,
+    ) -> Self {
+        let (keys, values): (Vec<K>, Vec<T>) = entries.into_iter().unzip();
+        // Call `as_ref` once up front, so that `build` doesn't run any user code that could panic
+        // with half the trie allocated, or give a different key the second time around.
+        let keys = keys.iter().map(|key| key.as_ref()).collect::<Vec<_>>();
+        assert!(
+            is_strictly_sorted(&keys),
+            "Keys passed to `from_sorted` must be strictly increasing"
+        );
+        let mut trie = Self::new_in(alloc);
+        if !keys.is_empty() {
+            let node = build(&keys, 0, &mut values.into_iter(), &trie.alloc);
+            trie.root = PackedNode::new(node, &trie.alloc);
+        }
+        trie.debug_check_invariants();
+        trie
+    }
+}
+
+fn is_strictly_sorted(keys: &[&[u8]]) -> bool {
+    keys.windows(2).all(|w| w[0] < w[1])
+}
+
+// Build the node for `keys`, which are sorted, nonempty, and all share their first `depth`
+// bytes.  Values are consumed in key order, since a node's own key sorts before all of its
+// children's.
+fn build<T, A: Allocator>(
+    keys: &[&[u8]],
+    depth: usize,
+    values: &mut impl Iterator<Item = T>,
+    alloc: &A,
+) -> Node<T, A> {
+    // The keys are sorted, so the first and last share the longest prefix of any pair.
+    let first = &keys[0][depth..];
+    let last = &keys[keys.len() - 1][depth..];
+    let prefix_len = prefix::mismatch(first, last).unwrap_or(first.len());
+    let node_depth = depth + prefix_len;
+
+    let (value, mut rest) = if first.len() == prefix_len {
+        (values.next(), &keys[1..])
+    } else {
+        (None, keys)
+    };
+    let mut pairs = BTreeMap::new();
+    while let Some(key) = rest.first() {
+        let byte = key[node_depth];
+        let group_len = rest
+            .iter()
+            .position(|k| k[node_depth] != byte)
+            .unwrap_or(rest.len());
+        let child = build(&rest[..group_len], node_depth + 1, values, alloc);
+        pairs.insert(byte, PackedNode::new(child, alloc));
+        rest = &rest[group_len..];
+    }
+    Node::new(first[..prefix_len].to_owned(), NodeChildren::from_pairs(pairs), value, alloc)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Counting, Trie};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_from_sorted() {
+        let mut model = BTreeMap::new();
+        for i in 0..5000u32 {
+            model.insert(format!("{}", i * 37 % 5003).into_bytes(), i);
+        }
+        for i in 0..300u32 {
+            model.insert(vec![0xff, (i % 256) as u8, (i / 256) as u8], i);
+        }
+        model.insert(vec![], 0);
+        model.insert(vec![b'a'; 300], 1);
+        model.insert(vec![b'a'; 301], 2);
+
+        let t = Trie::from_sorted(model.iter().map(|(k, &v)| (k, v)));
+        assert_eq!(t.check_invariants(), Ok(()));
+        assert!(t.iter().map(|(k, &v)| (k, v)).eq(model.clone().into_iter()));
+
+        // Every node is allocated once, unlike when inserting one key at a time.
+        let t = Trie::from_sorted_in(model.clone(), Counting::default());
+        let mut inserted = Trie::new_in(Counting::default());
+        for (k, v) in model {
+            inserted.insert(&k, v);
+        }
+        assert_eq!(t.allocator().live_allocations(), t.allocator().total_allocations());
+        assert!(t.allocator().total_allocations() < inserted.allocator().total_allocations());
+        assert_eq!(t.memory_stats(), inserted.memory_stats());
+
+        assert!(Trie::<u32>::from_sorted(Vec::<(&[u8], u32)>::new()).iter().next().is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_from_sorted_unsorted() {
+        Trie::from_sorted(vec![(&b"b"[..], 1), (&b"a"[..], 2)]);
+    }
+}
@@ -0,0 +1,599 @@
+// `ConcurrentTrie` lets readers run without ever blocking or writing to shared memory.  Writers
+// are serialized by a lock, and they never modify a node that's visible to readers.  Instead, they
+// path copy: every node from the root down to the change is rebuilt into a new buffer, and the
+// new root is published with a single atomic store.  Unchanged subtrees are moved into the new
+// nodes as is, so a reader holding on to an old root still sees a complete, consistent trie.
+//
+// The buffers of the replaced nodes (and any removed or overwritten values) can't be freed until
+// all readers that may have seen them are done.  We defer that with epoch based reclamation: the
+// trie's allocator hands freed buffers to `crossbeam_epoch`, which releases them once every
+// thread that was pinned at the time has unpinned.
+
+use std::alloc::Layout;
+use std::mem::{self, ManuallyDrop};
+use std::ops::{Bound, RangeBounds};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned};
+
+use crate::allocator::{Allocator, Global};
+use crate::invariants::{self, InvariantViolation};
+use crate::node::{Node, NodeChildren};
+use crate::packed_node::PackedNode;
+use crate::prefix;
+
+// Allocates from `Global`, but defers frees until no reader can be looking at the buffer.  Each
+// operation pins the epoch once, and everything it frees goes through that guard.  The buffers
+// themselves outlive the allocator, which is just a handle on the guard.
+struct EpochDeferred {
+    guard: Guard,
+}
+
+impl EpochDeferred {
+    fn pin() -> Self {
+        Self { guard: epoch::pin() }
+    }
+}
+
+unsafe impl Allocator for EpochDeferred {
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        Global.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.guard.defer_unchecked(move || Global.dealloc(ptr, layout));
+    }
+}
+
+// A removed or overwritten value, which readers may still be cloning.  We clone it once more for
+// the caller, and drop it once they're done, even if that clone panics.
+struct DeferredDrop<'g, T>(ManuallyDrop<T>, &'g Guard);
+
+impl<'g, T: Clone> DeferredDrop<'g, T> {
+    fn clone_and_drop(value: T, guard: &'g Guard) -> T {
+        T::clone(&DeferredDrop(ManuallyDrop::new(value), guard).0)
+    }
+}
+
+impl<T> Drop for DeferredDrop<'_, T> {
+    fn drop(&mut self) {
+        let value = unsafe { ManuallyDrop::take(&mut self.0) };
+        unsafe { self.1.defer_unchecked(move || drop(value)) };
+    }
+}
+
+// Tests can park threads at the points where they're most likely to race, to run them in an
+// order of their choosing.
+#[cfg(test)]
+thread_local! {
+    static INTERLEAVE: std::cell::RefCell<Option<Box<dyn Fn()>>> = Default::default();
+}
+
+#[inline]
+fn interleave() {
+    #[cfg(test)]
+    INTERLEAVE.with(|hook| {
+        if let Some(hook) = &*hook.borrow() {
+            hook();
+        }
+    });
+}
+
+/// A trie that can be shared between threads, where `get` and `range` never block.  Writes are
+/// serialized against each other, and copy the path from the root to the modified node.  Values
+/// are cloned out of the trie, since a concurrent `remove` may drop them at any time after.
+pub struct ConcurrentTrie<T> {
+    root: Atomic<PackedNode<T, EpochDeferred>>,
+    writer: Mutex<()>,
+}
+
+impl<T: Clone + Send + Sync + 'static> ConcurrentTrie<T> {
+    pub fn new() -> Self {
+        Self {
+            root: Atomic::new(PackedNode::empty()),
+            writer: Mutex::new(()),
+        }
+    }
+
+    fn root<'g>(&self, guard: &'g Guard) -> &'g PackedNode<T, EpochDeferred> {
+        unsafe { self.root.load(Ordering::Acquire, guard).deref() }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<T> {
+        let guard = epoch::pin();
+        let root = self.root(&guard);
+        interleave();
+        lookup(root, key).cloned()
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        let guard = epoch::pin();
+        let root = self.root(&guard);
+        interleave();
+        lookup(root, key).is_some()
+    }
+
+    pub fn insert(&self, key: &[u8], value: T) -> Option<T> {
+        let alloc = EpochDeferred::pin();
+        let old_value = self.write(&alloc, |root| insert(root, key, value, &alloc))?;
+        interleave();
+        Some(DeferredDrop::clone_and_drop(old_value, &alloc.guard))
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Option<T> {
+        // Don't bother taking the lock if there's nothing to remove.
+        if !self.contains_key(key) {
+            return None;
+        }
+        interleave();
+        // Another writer may have removed the key since, so check again under the lock before
+        // copying the path.
+        let alloc = EpochDeferred::pin();
+        let old_value = self.write(&alloc, |root| {
+            lookup(root, key)?;
+            remove(root, key, &alloc)
+        })?;
+        interleave();
+        Some(DeferredDrop::clone_and_drop(old_value, &alloc.guard))
+    }
+
+    // Run `f` on a private copy of the root and publish the result, freeing the old root through
+    // `alloc`'s guard.  `f` must not modify any node buffers in place, or run user code: the old
+    // nodes it replaces are already queued to be freed, so it mustn't unwind before the new root
+    // is published.
+    fn write<R>(
+        &self,
+        alloc: &EpochDeferred,
+        f: impl FnOnce(&mut PackedNode<T, EpochDeferred>) -> R,
+    ) -> R {
+        let _lock = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let old_root = self.root.load(Ordering::Acquire, &alloc.guard);
+        let mut new_root = unsafe { ptr::read(old_root.deref()) };
+        let result = f(&mut new_root);
+        self.root.store(Owned::new(new_root), Ordering::Release);
+        self.debug_check_invariants();
+
+        // The old root's box still aliases the nodes we just moved into the new root, so free it
+        // without dropping its contents.
+        let old_root = old_root.as_raw() as *mut ManuallyDrop<PackedNode<T, EpochDeferred>>;
+        unsafe { alloc.guard.defer_unchecked(move || drop(Box::from_raw(old_root))) };
+        result
+    }
+
+    /// See `Trie::check_invariants`.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        let guard = epoch::pin();
+        invariants::check_node(self.root(&guard), &mut vec![])
+    }
+
+    #[inline]
+    fn debug_check_invariants(&self) {
+        invariants::debug_check(|| self.check_invariants());
+    }
+
+    /// Clone out all of the entries with keys in `range`, in key order.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Vec<(Vec<u8>, T)> {
+        let bytes_bound = |bound| match bound {
+            Bound::Included(k) => Bound::Included(K::as_ref(k)),
+            Bound::Excluded(k) => Bound::Excluded(K::as_ref(k)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let range = (bytes_bound(range.start_bound()), bytes_bound(range.end_bound()));
+        let guard = epoch::pin();
+        let root = self.root(&guard);
+        interleave();
+        let mut out = vec![];
+        collect_range(root, &mut vec![], range, &mut out);
+        out
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Default for ConcurrentTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for ConcurrentTrie<T> {
+    fn drop(&mut self) {
+        // We have exclusive access, so no readers can be left.
+        unsafe {
+            let guard = epoch::unprotected();
+            let root = self.root.load(Ordering::Relaxed, guard).as_raw();
+            let mut root = Box::from_raw(root as *mut PackedNode<T, EpochDeferred>);
+            root.drop_in(&EpochDeferred::pin());
+        }
+    }
+}
+
+fn lookup<'a, T, A>(node: &'a PackedNode<T, A>, key: &[u8]) -> Option<&'a T> {
+    let mut cur = node;
+    let mut key = key;
+    loop {
+        let node_prefix = cur.prefix();
+        if !prefix::starts_with(key, node_prefix) {
+            return None;
+        }
+        let (&branch_byte, rest) = match key[node_prefix.len()..].split_first() {
+            None => return cur.value(),
+            Some(p) => p,
+        };
+        key = rest;
+        cur = cur.lookup(branch_byte)?;
+    }
+}
+
+// These mirror `PackedNode::insert` and `PackedNode::remove`, but take apart every node they pass
+// through rather than modifying its children in place.  `node` itself is always a private copy,
+// either the new root or a slot in a node we're rebuilding.
+
+fn insert<T, A: Allocator>(
+    node: &mut PackedNode<T, A>,
+    key: &[u8],
+    value: T,
+    alloc: &A,
+) -> Option<T> {
+    if node.is_empty() {
+        let new_node = Node::new(key.to_owned(), NodeChildren::Empty, Some(value), alloc);
+        *node = PackedNode::new(new_node, alloc);
+        return None;
+    }
+    let prefix_len = node.prefix().len();
+    if let Some(i) = prefix::mismatch(node.prefix(), key) {
+        match key.get(i) {
+            Some(&key_byte) => node.branch_prefix(i, key_byte, &key[(i + 1)..], value, alloc),
+            None => node.split_prefix(i, value, alloc),
+        }
+        return None;
+    }
+    let mut copy = node.take(alloc);
+    let old_value = match key.get(prefix_len) {
+        None => mem::replace(&mut copy.value, Some(value)),
+        Some(&branch_byte) => match copy.children.get_mut(branch_byte) {
+            Some(child) => insert(child, &key[(prefix_len + 1)..], value, alloc),
+            None => {
+                let child_key = key[(prefix_len + 1)..].to_owned();
+                let new_child = Node::new(child_key, NodeChildren::Empty, Some(value), alloc);
+                let children = mem::replace(&mut copy.children, NodeChildren::Empty);
+                let mut pairs = children.into_pairs();
+                pairs.insert(branch_byte, PackedNode::new(new_child, alloc));
+                copy.children = NodeChildren::from_pairs(pairs);
+                None
+            }
+        },
+    };
+    *node = PackedNode::new(copy, alloc);
+    old_value
+}
+
+// Callers must check that `key` is present first, while holding the writer lock: once we've
+// taken a node apart, its old buffer is queued to be freed, so we can't back out.
+fn remove<T, A: Allocator>(node: &mut PackedNode<T, A>, key: &[u8], alloc: &A) -> Option<T> {
+    debug_assert!(prefix::starts_with(key, node.prefix()));
+    let prefix_len = node.prefix().len();
+    let mut copy = node.take(alloc);
+    let removed_value = match key.get(prefix_len) {
+        None => copy.value.take()?,
+        Some(&branch_byte) => {
+            let child = copy.children.get_mut(branch_byte)?;
+            let removed_value = remove(child, &key[(prefix_len + 1)..], alloc)?;
+            if !child.is_empty() {
+                *node = PackedNode::new(copy, alloc);
+                return Some(removed_value);
+            }
+            removed_value
+        }
+    };
+
+    // Patch up the invariants described in `remove.rs`.
+    let Node { mut prefix, children, value, .. } = copy;
+    let pairs = children.into_pairs();
+    match (value.is_some(), pairs.len()) {
+        // Leave ourselves as empty to let the parent cleanup.
+        (false, 0) => (),
+        (false, 1) => {
+            let (child_byte, mut packed_child) = pairs.into_iter().next().unwrap();
+            let child = packed_child.take(alloc);
+            prefix.push(child_byte);
+            prefix.extend_from_slice(child.prefix());
+            let new_node = Node::new(prefix, child.children, child.value, alloc);
+            *node = PackedNode::new(new_node, alloc);
+        }
+        _ => {
+            let new_node = Node::new(prefix, NodeChildren::from_pairs(pairs), value, alloc);
+            *node = PackedNode::new(new_node, alloc);
+        }
+    }
+    Some(removed_value)
+}
+
+// Append the entries below `node` that fall in `range`, where `key` holds the bytes leading up to
+// `node`.  Every key below `node` starts with `key` plus its prefix, so we can skip subtrees that
+// are entirely outside of the range.
+fn collect_range<T: Clone, A>(
+    node: &PackedNode<T, A>,
+    key: &mut Vec<u8>,
+    range: (Bound<&[u8]>, Bound<&[u8]>),
+    out: &mut Vec<(Vec<u8>, T)>,
+) {
+    if node.is_empty() {
+        return;
+    }
+    let key_len = key.len();
+    key.extend_from_slice(node.prefix());
+
+    let below_start = match range.0 {
+        Bound::Included(start) | Bound::Excluded(start) => {
+            &key[..] < start && !start.starts_with(key)
+        }
+        Bound::Unbounded => false,
+    };
+    let past_end = match range.1 {
+        Bound::Included(end) => &key[..] > end,
+        Bound::Excluded(end) => &key[..] >= end,
+        Bound::Unbounded => false,
+    };
+    if !below_start && !past_end {
+        if let Some(value) = node.value() {
+            if range.contains(&key[..]) {
+                out.push((key.clone(), value.clone()));
+            }
+        }
+        for (byte, child) in node.children() {
+            key.push(byte);
+            collect_range(child, key, range, out);
+            key.pop();
+        }
+    }
+    key.truncate(key_len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConcurrentTrie, INTERLEAVE};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key/{}/{}", i % 7, i).into_bytes()
+    }
+
+    #[test]
+    fn test_concurrent_basic() {
+        let t = ConcurrentTrie::new();
+        let mut model = BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..5000 {
+            let i = rng.gen_range(0, 1000);
+            if rng.gen_bool(0.3) {
+                assert_eq!(t.remove(&key(i)), model.remove(&key(i)));
+            } else {
+                assert_eq!(t.insert(&key(i), i.to_string()), model.insert(key(i), i.to_string()));
+            }
+        }
+        for i in 0..1000 {
+            assert_eq!(t.get(&key(i)), model.get(&key(i)).cloned());
+        }
+        assert_eq!(t.check_invariants(), Ok(()));
+        let all = t.range::<&[u8], _>(..);
+        assert!(all.iter().cloned().eq(model.clone().into_iter()));
+
+        let (start, end) = (&b"key/3"[..], &b"key/5/"[..]);
+        let expected = model
+            .range(start.to_vec()..end.to_vec())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        assert!(!expected.is_empty());
+        assert_eq!(t.range(start..end), expected);
+        assert_eq!(t.range(start..=start), vec![]);
+    }
+
+    #[test]
+    fn test_concurrent_panicking_clone() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        #[derive(Debug)]
+        struct PanicOnClone(u32);
+        impl Clone for PanicOnClone {
+            fn clone(&self) -> Self {
+                assert_ne!(self.0, 0, "cloning {}", self.0);
+                PanicOnClone(self.0)
+            }
+        }
+
+        let t = ConcurrentTrie::new();
+        for i in 0..100 {
+            t.insert(&key(i), PanicOnClone(i + 1));
+        }
+        t.insert(b"zero", PanicOnClone(0));
+        // Cloning the old value to return it panics, but only once the new one is in place.
+        let insert = AssertUnwindSafe(|| t.insert(b"zero", PanicOnClone(1000)));
+        assert!(panic::catch_unwind(insert).is_err());
+        assert_eq!(t.get(b"zero").map(|v| v.0), Some(1000));
+        t.insert(b"zero", PanicOnClone(0));
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| t.remove(b"zero"))).is_err());
+        assert!(!t.contains_key(b"zero"));
+
+        assert_eq!(t.check_invariants(), Ok(()));
+        for i in 0..100 {
+            assert_eq!(t.remove(&key(i)).map(|v| v.0), Some(i + 1));
+        }
+    }
+
+    // Writers own disjoint sets of keys and keep a model of them, while readers check that every
+    // value they see is one that could have been written for that key.
+    #[test]
+    fn test_concurrent_stress() {
+        const WRITERS: u32 = 3;
+        const READERS: u32 = 2;
+        const KEYS: u32 = 50;
+
+        for seed in 0..20 {
+            let t = Arc::new(ConcurrentTrie::<(u32, Arc<u32>)>::new());
+            let models = Arc::new(Mutex::new(BTreeMap::new()));
+            let mut threads = vec![];
+            for r in 0..READERS {
+                let t = t.clone();
+                threads.push(Box::new(move || {
+                    let mut rng = StdRng::seed_from_u64(seed * 100 + u64::from(r));
+                    for _ in 0..200 {
+                        let i = rng.gen_range(0, KEYS * WRITERS);
+                        if let Some(v) = t.get(&key(i)) {
+                            assert_eq!(v.0, i);
+                        }
+                        let entries = t.range(&key(i)[..]..);
+                        assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+                        for (k, v) in entries.iter().take(10) {
+                            assert_eq!(k, &key(v.0));
+                        }
+                    }
+                }) as Box<dyn FnOnce() + Send>);
+            }
+            for w in 0..WRITERS {
+                let (t, models) = (t.clone(), models.clone());
+                threads.push(Box::new(move || {
+                    let mut rng = StdRng::seed_from_u64(seed * 100 + 50 + u64::from(w));
+                    let mut model = BTreeMap::new();
+                    for n in 0..300u32 {
+                        let i = w + WRITERS * rng.gen_range(0, KEYS);
+                        let value = (i, Arc::new(n));
+                        if rng.gen_bool(0.4) {
+                            assert_eq!(t.remove(&key(i)), model.remove(&key(i)));
+                        } else {
+                            let old_value = model.insert(key(i), value.clone());
+                            assert_eq!(t.insert(&key(i), value), old_value);
+                        }
+                    }
+                    models.lock().unwrap().extend(model);
+                }));
+            }
+            run_scheduled(seed, threads);
+            let model = models.lock().unwrap().clone();
+            assert!(t.range::<&[u8], _>(..).into_iter().eq(model.into_iter()));
+            assert_eq!(t.check_invariants(), Ok(()));
+        }
+    }
+
+    // Writers race to remove the same keys, while a reader keeps looking them up.  Each key must
+    // be removed exactly once, and losing a race must leave the trie alone.
+    #[test]
+    fn test_concurrent_shared_removes() {
+        const WRITERS: u32 = 3;
+        const KEYS: u32 = 30;
+
+        for seed in 0..100 {
+            let t = Arc::new(ConcurrentTrie::<Arc<u32>>::new());
+            for i in 0..KEYS {
+                t.insert(&key(i), Arc::new(i));
+            }
+            let removed = Arc::new(Mutex::new(vec![]));
+            let reader = {
+                let t = t.clone();
+                Box::new(move || {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    for _ in 0..100 {
+                        let i = rng.gen_range(0, KEYS);
+                        if let Some(v) = t.get(&key(i)) {
+                            assert_eq!(*v, i);
+                        }
+                    }
+                }) as Box<dyn FnOnce() + Send>
+            };
+            let mut threads = vec![reader];
+            for w in 0..WRITERS {
+                let (t, removed) = (t.clone(), removed.clone());
+                threads.push(Box::new(move || {
+                    let mut rng = StdRng::seed_from_u64(seed * 100 + u64::from(w));
+                    let mut keys = (0..KEYS).collect::<Vec<_>>();
+                    keys.shuffle(&mut rng);
+                    for i in keys {
+                        if let Some(v) = t.remove(&key(i)) {
+                            assert_eq!(*v, i);
+                            removed.lock().unwrap().push(i);
+                        }
+                    }
+                }));
+            }
+            run_scheduled(seed, threads);
+            let mut removed = removed.lock().unwrap().clone();
+            removed.sort_unstable();
+            assert!(removed.into_iter().eq(0..KEYS), "seed {}", seed);
+            assert_eq!(t.range::<&[u8], _>(..), vec![]);
+            assert_eq!(t.check_invariants(), Ok(()));
+        }
+    }
+
+    // Runs threads one at a time, handing over at `interleave` points to a thread picked by a
+    // seeded RNG.  Each seed always runs the same schedule, so failures can be replayed.
+    struct Schedule {
+        // The thread whose turn it is, the RNG, and which threads have finished.
+        state: Mutex<(usize, StdRng, Vec<bool>)>,
+        turn: Condvar,
+    }
+
+    impl Schedule {
+        fn wait_for_turn(&self, me: usize) {
+            let mut state = self.state.lock().unwrap();
+            while state.0 != me {
+                state = self.turn.wait(state).unwrap();
+            }
+        }
+
+        // Give the turn to a random unfinished thread (possibly us), and wait for it to come
+        // back around unless we're done.
+        fn switch(&self, me: usize, finished: bool) {
+            let mut state = self.state.lock().unwrap();
+            state.2[me] = finished;
+            let live = (0..state.2.len()).filter(|&i| !state.2[i]).collect::<Vec<_>>();
+            if live.is_empty() {
+                return;
+            }
+            state.0 = live[state.1.gen_range(0, live.len())];
+            self.turn.notify_all();
+            drop(state);
+            if !finished {
+                self.wait_for_turn(me);
+            }
+        }
+    }
+
+    fn run_scheduled(seed: u64, threads: Vec<Box<dyn FnOnce() + Send>>) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let first = rng.gen_range(0, threads.len());
+        let schedule = Arc::new(Schedule {
+            state: Mutex::new((first, rng, vec![false; threads.len()])),
+            turn: Condvar::new(),
+        });
+        // Pass the turn on when a thread finishes, even if it panicked.
+        struct Finish(Arc<Schedule>, usize);
+        impl Drop for Finish {
+            fn drop(&mut self) {
+                self.0.switch(self.1, true);
+            }
+        }
+        let handles = threads
+            .into_iter()
+            .enumerate()
+            .map(|(me, body)| {
+                let schedule = schedule.clone();
+                thread::spawn(move || {
+                    let _finish = Finish(schedule.clone(), me);
+                    schedule.wait_for_turn(me);
+                    INTERLEAVE.with(|hook| {
+                        *hook.borrow_mut() = Some(Box::new(move || schedule.switch(me, false)));
+                    });
+                    body();
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::{self, Write};
+
+use crate::allocator::Allocator;
+use crate::header::NodeChildrenType;
+use crate::packable::Header;
+use crate::packed_node::PackedNode;
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+// Prefixes longer than this are cut short in node labels, since a few long URLs would otherwise
+// make every node in the diagram as wide as the page.
+const MAX_LABEL_PREFIX_LEN: usize = 32;
+
+/// Options for `Trie::write_dot`.
+#[derive(Clone, Debug, Default)]
+pub struct DotOptions {
+    /// Don't draw nodes more than this many levels below the first one drawn.  Nodes at the limit
+    /// say how many children they have but don't draw them.
+    pub max_depth: Option<usize>,
+    /// Only draw the subtree holding the keys that start with these bytes.
+    pub key_prefix: Vec<u8>,
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Write the trie's structure as a Graphviz graph, for when `debug`'s output is too large to
+    /// read.  Each node shows its children type and count, prefix, whether it has a value, and
+    /// the size of its allocation, and each edge its branch byte.  Render it with something like
+    /// `dot -Tsvg`.
+    pub fn write_dot(&self, mut out: impl Write, options: &DotOptions) -> io::Result<()> {
+        writeln!(out, "digraph trie {{")?;
+        writeln!(out, "  node [shape=box, fontname=\"monospace\"];")?;
+        writeln!(out, "  edge [fontname=\"monospace\"];")?;
+        if !options.key_prefix.is_empty() {
+            let label = escape(&options.key_prefix, usize::MAX);
+            writeln!(out, "  label=\"keys starting with \\\"{}\\\"\";", label)?;
+        }
+        if let Some((node, _)) = self.root.find_prefix(&options.key_prefix) {
+            let mut writer = DotWriter { out: &mut out, max_depth: options.max_depth, next_id: 0 };
+            writer.write_node(node, 0)?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+struct DotWriter<W> {
+    out: W,
+    max_depth: Option<usize>,
+    next_id: usize,
+}
+
+impl<W: Write> DotWriter<W> {
+    // Write `node` and everything below it, returning its id.
+    fn write_node<T, A, S: Summary<T>>(
+        &mut self,
+        node: &PackedNode<T, A, S>,
+        depth: usize,
+    ) -> io::Result<usize> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut label = match node.header() {
+            Some(header) => {
+                let children_type = header.children_type();
+                let mut label = match children_type {
+                    NodeChildrenType::Empty => "Empty".to_owned(),
+                    _ => format!("{:?} ({})", children_type, header.num_children()),
+                };
+                label += &format!("\\n{} bytes", header.layout().size());
+                label
+            }
+            None => "inline leaf".to_owned(),
+        };
+        let prefix = node.prefix();
+        if !prefix.is_empty() {
+            label += &format!("\\nprefix: \\\"{}\\\"", escape(prefix, MAX_LABEL_PREFIX_LEN));
+            if prefix.len() > MAX_LABEL_PREFIX_LEN {
+                label += &format!(" ({} bytes)", prefix.len());
+            }
+        }
+        if node.has_value() {
+            label += "\\nvalue";
+        }
+
+        let num_children = node.children().count();
+        let expand = self.max_depth.is_none_or(|max_depth| depth < max_depth);
+        if !expand && num_children > 0 {
+            label += &format!("\\n{} children not shown", num_children);
+        }
+        let style = if node.is_inline() { ", style=dashed" } else { "" };
+        writeln!(self.out, "  n{} [label=\"{}\"{}];", id, label, style)?;
+
+        if expand {
+            for (byte, child) in node.children() {
+                let child_id = self.write_node(child, depth + 1)?;
+                let byte = escape(&[byte], 1);
+                writeln!(self.out, "  n{} -> n{} [label=\"{}\"];", id, child_id, byte)?;
+            }
+        }
+        Ok(id)
+    }
+}
+
+// Escape `bytes` for a quoted DOT string, showing printable ASCII as is and everything else as
+// `\xNN`, and cutting it short after `max_len` bytes.
+fn escape(bytes: &[u8], max_len: usize) -> String {
+    let mut escaped = String::new();
+    for &b in bytes.iter().take(max_len) {
+        for c in std::ascii::escape_default(b) {
+            // DOT treats backslashes and quotes specially, including the ones `escape_default`
+            // adds.
+            if c == b'\\' || c == b'"' {
+                escaped.push('\\');
+            }
+            escaped.push(c as char);
+        }
+    }
+    if bytes.len() > max_len {
+        escaped += "...";
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DotOptions;
+    use crate::Trie;
+
+    fn write_dot(t: &Trie<u32>, options: &DotOptions) -> String {
+        let mut out = vec![];
+        t.write_dot(&mut out, options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn count_nodes(dot: &str) -> usize {
+        let is_node = |l: &str| l.starts_with("  n") && l[3..].starts_with(char::is_numeric);
+        dot.lines().filter(|l| is_node(l) && !l.contains("->")).count()
+    }
+
+    #[test]
+    fn test_write_dot() {
+        let mut t = Trie::new();
+        for i in 0..1000u32 {
+            t.insert(format!("user/{}/name", i).as_bytes(), i);
+        }
+        t.insert(b"\"quoted\"\n\\", 0);
+        t.insert(&[b'x'; 100], 0);
+
+        let dot = write_dot(&t, &DotOptions::default());
+        assert!(dot.starts_with("digraph trie {\n"));
+        assert!(dot.ends_with("}\n"));
+        let nodes = count_nodes(&dot);
+        assert_eq!(nodes, t.memory_stats().total_nodes());
+        assert_eq!(dot.matches("->").count(), nodes - 1);
+        assert!(dot.contains(r#"prefix: \"ser/\""#));
+        assert!(dot.contains(r#"[label="\\\""]"#));
+        assert!(dot.contains(r#"prefix: \"quoted\\\"\\n\\\\\""#));
+        assert!(dot.contains("(99 bytes)"));
+        assert!(dot.contains("Pairs (3)"));
+
+        let shallow = write_dot(&t, &DotOptions { max_depth: Some(1), ..Default::default() });
+        assert_eq!(count_nodes(&shallow), 1 + t.root.children().count());
+        assert!(shallow.contains("children not shown"));
+
+        let options = DotOptions { key_prefix: b"user/12".to_vec(), ..Default::default() };
+        let subtree = write_dot(&t, &options);
+        // "user/12/name" and "user/120/name" through "user/129/name".
+        assert!(subtree.contains("keys starting with \\\"user/12\\\""));
+        assert_eq!(subtree.matches("\\nvalue").count(), 11);
+
+        let options = DotOptions { key_prefix: b"nope".to_vec(), ..Default::default() };
+        assert_eq!(count_nodes(&write_dot(&t, &options)), 0);
+        assert_eq!(count_nodes(&write_dot(&Trie::new(), &DotOptions::default())), 0);
+    }
+}
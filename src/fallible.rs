@@ -0,0 +1,627 @@
+// Fallible versions of `insert`, `remove`, and `clone`, for when running out of memory should be
+// an error rather than an abort.
+//
+// Updates rebuild nodes by taking them apart and packing the pieces into new buffers, so an
+// allocation can fail after the old node's already been taken apart.  To roll back, we take nodes
+// with `take_detached`, which keeps their buffers around until the new nodes are all allocated,
+// and put the pieces back into them if not.  Both insertions and removals only ever rebuild the
+// nodes at one level of the trie: removing a leaf rebuilds its parent before unlinking it, so
+// there's nothing to undo below the level that failed.
+
+use std::alloc::handle_alloc_error;
+use std::collections::BTreeMap;
+use std::mem;
+
+use crate::allocator::{AllocError, Allocator};
+use crate::header::MAX_PREFIX_LEN;
+use crate::node::{Node, NodeChildren};
+use crate::packed_node::PackedNode;
+use crate::prefix;
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Like `insert`, but returns an error rather than aborting if a node can't be allocated.  The
+    /// trie is left unchanged in that case, and `value` is dropped.
+    pub fn try_insert(&mut self, key: &[u8], value: T) -> Result<Option<T>, AllocError> {
+        let result = self.root.try_insert(key, value, &self.alloc);
+        self.root.update_summary();
+        self.debug_check_invariants();
+        result
+    }
+
+    /// Like `remove`, but returns an error rather than aborting if a node can't be allocated,
+    /// leaving the trie unchanged.  Removals allocate because the nodes around the removed key
+    /// get rebuilt without it.
+    pub fn try_remove(&mut self, key: &[u8]) -> Result<Option<T>, AllocError> {
+        let result = self.root.try_remove(key, &self.alloc);
+        self.root.update_summary();
+        self.debug_check_invariants();
+        result
+    }
+
+    /// Copy the trie into a clone of its allocator, returning an error rather than aborting if a
+    /// node can't be allocated.
+    pub fn try_clone(&self) -> Result<Self, AllocError>
+    where
+        T: Clone,
+        A: Clone,
+    {
+        let mut trie = Self::with_summary_in(self.alloc.clone());
+        trie.root = self.root.try_clone(&trie.alloc)?;
+        trie.root.update_summary();
+        trie.debug_check_invariants();
+        Ok(trie)
+    }
+}
+
+impl<T: Clone, A: Allocator + Clone, S: Summary<T>> Clone for Trie<T, A, S> {
+    fn clone(&self) -> Self {
+        match self.try_clone() {
+            Ok(trie) => trie,
+            Err(e) => handle_alloc_error(e.layout()),
+        }
+    }
+}
+
+// What's left of a node that `try_pack` couldn't allocate.
+struct Failed<T, A, S: Summary<T>> {
+    error: AllocError,
+    children: NodeChildren<T, A, S>,
+    value: Option<T>,
+}
+
+// Like `PackedNode::new(Node::new(..))`, but hands the children and value back on failure.  Like
+// `Node::new`, this splits prefixes longer than `MAX_PREFIX_LEN` into a chain of nodes.
+#[allow(clippy::result_large_err)]
+fn try_pack<T, A: Allocator, S: Summary<T>>(
+    prefix: &[u8],
+    children: NodeChildren<T, A, S>,
+    value: Option<T>,
+    alloc: &A,
+) -> Result<PackedNode<T, A, S>, Failed<T, A, S>> {
+    if prefix.len() <= MAX_PREFIX_LEN {
+        let node = Node { prefix: prefix.to_owned(), skipped_len: 0, children, value };
+        return PackedNode::try_new(node, alloc).map_err(|(node, error)| Failed {
+            error,
+            children: node.children,
+            value: node.value,
+        });
+    }
+    let (&branch, suffix) = prefix[MAX_PREFIX_LEN..].split_first().unwrap();
+    let child = try_pack(suffix, children, value, alloc)?;
+    let node = Node {
+        prefix: prefix[..MAX_PREFIX_LEN].to_owned(),
+        skipped_len: 0,
+        children: NodeChildren::one(branch, child),
+        value: None,
+    };
+    PackedNode::try_new(node, alloc).map_err(|(node, error)| {
+        let (children, value) = unpack(only_child(node.children), suffix.len(), alloc);
+        Failed { error, children, value }
+    })
+}
+
+// Undo a `try_pack` of a prefix of length `prefix_len`, freeing the nodes it allocated.
+fn unpack<T, A: Allocator, S: Summary<T>>(
+    mut node: PackedNode<T, A, S>,
+    prefix_len: usize,
+    alloc: &A,
+) -> (NodeChildren<T, A, S>, Option<T>) {
+    let Node { children, value, .. } = node.take(alloc);
+    if prefix_len <= MAX_PREFIX_LEN {
+        return (children, value);
+    }
+    unpack(only_child(children), prefix_len - MAX_PREFIX_LEN - 1, alloc)
+}
+
+fn only_child<T, A, S: Summary<T>>(children: NodeChildren<T, A, S>) -> PackedNode<T, A, S> {
+    let mut pairs = children.into_pairs();
+    assert_eq!(pairs.len(), 1);
+    pairs.pop_first().unwrap().1
+}
+
+fn merged_prefix(prefix: &[u8], branch: u8, child_prefix: &[u8]) -> Vec<u8> {
+    let mut merged = Vec::with_capacity(prefix.len() + 1 + child_prefix.len());
+    merged.extend_from_slice(prefix);
+    merged.push(branch);
+    merged.extend_from_slice(child_prefix);
+    merged
+}
+
+impl<T, A: Allocator, S: Summary<T>> PackedNode<T, A, S> {
+    // See `insert`.  On failure, the node is left unchanged and `value` is dropped.
+    pub fn try_insert(&mut self, key: &[u8], value: T, alloc: &A) -> Result<Option<T>, AllocError> {
+        if self.is_empty() {
+            *self = try_pack(key, NodeChildren::Empty, Some(value), alloc).map_err(|f| f.error)?;
+            return Ok(None);
+        }
+        let prefix_len = self.prefix().len();
+        if let Some(i) = prefix::mismatch(self.prefix(), key) {
+            match key.get(i) {
+                Some(&key_byte) => {
+                    self.try_branch_prefix(i, key_byte, &key[(i + 1)..], value, alloc)?
+                }
+                None => self.try_split_prefix(i, value, alloc)?,
+            }
+            return Ok(None);
+        }
+        let mut key_iter = key[prefix_len..].iter();
+        let branch_byte = match key_iter.next() {
+            None => return self.try_set_value(value, alloc),
+            Some(&k) => k,
+        };
+        self.clear_summary();
+        match self.lookup_mut(branch_byte) {
+            None => {
+                self.try_add_child(branch_byte, key_iter.as_slice(), value, alloc)?;
+                Ok(None)
+            }
+            Some(next_node) => {
+                let filled_slot = next_node.is_empty();
+                let old_value = next_node.try_insert(key_iter.as_slice(), value, alloc)?;
+                if filled_slot {
+                    self.increment_dense_children();
+                }
+                if old_value.is_none() {
+                    self.set_len(self.len() + 1);
+                }
+                Ok(old_value)
+            }
+        }
+    }
+
+    // See `split_prefix`.
+    fn try_split_prefix(
+        &mut self,
+        split_at: usize,
+        new_value: T,
+        alloc: &A,
+    ) -> Result<(), AllocError> {
+        let (Node { prefix, skipped_len, children, value }, detached) = self.take_detached(alloc);
+        let branch = prefix[split_at];
+        let child_prefix = &prefix[(split_at + 1)..];
+
+        let child = match try_pack(child_prefix, children, value, alloc) {
+            Ok(child) => child,
+            Err(Failed { error, children, value }) => {
+                *self = detached.restore(Node { prefix, skipped_len, children, value }, alloc);
+                return Err(error);
+            }
+        };
+        let children = NodeChildren::one(branch, child);
+        match try_pack(&prefix[..split_at], children, Some(new_value), alloc) {
+            Ok(parent) => {
+                *self = parent;
+                detached.free(alloc);
+                Ok(())
+            }
+            Err(Failed { error, children, .. }) => {
+                let (children, value) = unpack(only_child(children), child_prefix.len(), alloc);
+                *self = detached.restore(Node { prefix, skipped_len, children, value }, alloc);
+                Err(error)
+            }
+        }
+    }
+
+    // See `branch_prefix`.
+    fn try_branch_prefix(
+        &mut self,
+        split_at: usize,
+        key_branch: u8,
+        key_remainder: &[u8],
+        new_value: T,
+        alloc: &A,
+    ) -> Result<(), AllocError> {
+        let (Node { prefix, skipped_len, children, value }, detached) = self.take_detached(alloc);
+        let first_branch = prefix[split_at];
+        let first_prefix = &prefix[(split_at + 1)..];
+
+        let first_child = match try_pack(first_prefix, children, value, alloc) {
+            Ok(child) => child,
+            Err(Failed { error, children, value }) => {
+                *self = detached.restore(Node { prefix, skipped_len, children, value }, alloc);
+                return Err(error);
+            }
+        };
+        let second_child = try_pack(key_remainder, NodeChildren::Empty, Some(new_value), alloc);
+        let second_child = match second_child {
+            Ok(child) => child,
+            Err(Failed { error, value: new_value, .. }) => {
+                let (children, value) = unpack(first_child, first_prefix.len(), alloc);
+                *self = detached.restore(Node { prefix, skipped_len, children, value }, alloc);
+                // Only drop the value once we're back in one piece, in case its `Drop` panics.
+                drop(new_value);
+                return Err(error);
+            }
+        };
+        let children = NodeChildren::two(first_branch, first_child, key_branch, second_child);
+        match try_pack(&prefix[..split_at], children, None, alloc) {
+            Ok(parent) => {
+                *self = parent;
+                detached.free(alloc);
+                Ok(())
+            }
+            Err(Failed { error, children, .. }) => {
+                let mut pairs = children.into_pairs();
+                let mut second_child = pairs.remove(&key_branch).unwrap();
+                let first_child = pairs.remove(&first_branch).unwrap();
+                let (children, value) = unpack(first_child, first_prefix.len(), alloc);
+                *self = detached.restore(Node { prefix, skipped_len, children, value }, alloc);
+                second_child.drop_in(alloc);
+                Err(error)
+            }
+        }
+    }
+
+    // See `set_value`.
+    fn try_set_value(&mut self, new_value: T, alloc: &A) -> Result<Option<T>, AllocError> {
+        let (mut node, detached) = self.take_detached(alloc);
+        let old_value = node.value.replace(new_value);
+        match PackedNode::try_new(node, alloc) {
+            Ok(packed) => {
+                *self = packed;
+                detached.free(alloc);
+                Ok(old_value)
+            }
+            Err((mut node, error)) => {
+                let new_value = mem::replace(&mut node.value, old_value);
+                *self = detached.restore(node, alloc);
+                drop(new_value);
+                Err(error)
+            }
+        }
+    }
+
+    // See `add_child`.
+    fn try_add_child(
+        &mut self,
+        key: u8,
+        suffix: &[u8],
+        value: T,
+        alloc: &A,
+    ) -> Result<(), AllocError> {
+        let child = try_pack(suffix, NodeChildren::Empty, Some(value), alloc).map_err(|f| f.error)?;
+        let (mut node, detached) = self.take_detached(alloc);
+        let mut pairs = mem::replace(&mut node.children, NodeChildren::Empty).into_pairs();
+        assert!(pairs.insert(key, child).is_none());
+        node.children = NodeChildren::from_pairs(pairs);
+        match PackedNode::try_new(node, alloc) {
+            Ok(packed) => {
+                *self = packed;
+                detached.free(alloc);
+                Ok(())
+            }
+            Err((mut node, error)) => {
+                let mut pairs = mem::replace(&mut node.children, NodeChildren::Empty).into_pairs();
+                let mut child = pairs.remove(&key).unwrap();
+                node.children = NodeChildren::from_pairs(pairs);
+                *self = detached.restore(node, alloc);
+                child.drop_in(alloc);
+                Err(error)
+            }
+        }
+    }
+
+    // See `remove`.  Unlike `remove`, which removes the value and then patches up the nodes
+    // above it, this rebuilds a leaf's parent first, so that a failure doesn't leave anything to
+    // undo in the leaf.
+    pub fn try_remove(&mut self, key: &[u8], alloc: &A) -> Result<Option<T>, AllocError> {
+        if !prefix::starts_with(key, self.prefix()) {
+            return Ok(None);
+        }
+        let (&branch_byte, rest) = match key[self.prefix().len()..].split_first() {
+            None if self.has_value() => return self.try_remove_value(alloc).map(Some),
+            None => return Ok(None),
+            Some(parts) => parts,
+        };
+        self.clear_summary();
+        let child = match self.lookup_mut(branch_byte) {
+            Some(child) => child,
+            None => return Ok(None),
+        };
+        let is_leaf = child.prefix() == rest && child.children().next().is_none();
+        if !is_leaf || !child.has_value() {
+            let removed = child.try_remove(rest, alloc)?;
+            if removed.is_some() {
+                self.set_len(self.len() - 1);
+            }
+            return Ok(removed);
+        }
+        self.try_remove_leaf(branch_byte, alloc).map(Some)
+    }
+
+    // Remove the value from a node that has one.
+    fn try_remove_value(&mut self, alloc: &A) -> Result<T, AllocError> {
+        let num_children = self.children().count();
+        if num_children == 0 {
+            // This only happens at the root, since parents unlink their leaves themselves.
+            return Ok(self.take(alloc).value.unwrap());
+        }
+        let (node, detached) = self.take_detached(alloc);
+        if num_children > 1 {
+            let Node { prefix, skipped_len, children, value } = node;
+            let new_node = Node { prefix, skipped_len, children, value: None };
+            return match PackedNode::try_new(new_node, alloc) {
+                Ok(packed) => {
+                    *self = packed;
+                    detached.free(alloc);
+                    Ok(value.unwrap())
+                }
+                Err((mut node, error)) => {
+                    node.value = value;
+                    *self = detached.restore(node, alloc);
+                    Err(error)
+                }
+            };
+        }
+
+        // Merge into our only child.
+        let Node { prefix, skipped_len, children, value } = node;
+        let (child_byte, mut packed_child) = children.into_pairs().pop_first().unwrap();
+        let (child, child_detached) = packed_child.take_detached(alloc);
+        let merged = merged_prefix(&prefix, child_byte, &child.prefix);
+        match try_pack(&merged, child.children, child.value, alloc) {
+            Ok(packed) => {
+                *self = packed;
+                detached.free(alloc);
+                child_detached.free(alloc);
+                Ok(value.unwrap())
+            }
+            Err(Failed { error, children, value: child_value }) => {
+                let child = Node { children, value: child_value, ..child };
+                let child = child_detached.restore(child, alloc);
+                let children = NodeChildren::one(child_byte, child);
+                *self = detached.restore(Node { prefix, skipped_len, children, value }, alloc);
+                Err(error)
+            }
+        }
+    }
+
+    // Unlink the leaf child at `byte`, rebuilding ourselves without it.
+    fn try_remove_leaf(&mut self, byte: u8, alloc: &A) -> Result<T, AllocError> {
+        let (Node { prefix, skipped_len, children, value }, detached) = self.take_detached(alloc);
+        let mut pairs = children.into_pairs();
+        let mut leaf = pairs.remove(&byte).unwrap();
+
+        if value.is_none() && pairs.len() <= 1 {
+            let (other_byte, mut other) = match pairs.pop_first() {
+                Some(pair) => pair,
+                None => {
+                    // Leave ourselves empty, like `remove` does.
+                    detached.free(alloc);
+                    return Ok(leaf.take(alloc).value.unwrap());
+                }
+            };
+            // Merge into our other child.
+            let (other_node, other_detached) = other.take_detached(alloc);
+            let merged = merged_prefix(&prefix, other_byte, &other_node.prefix);
+            return match try_pack(&merged, other_node.children, other_node.value, alloc) {
+                Ok(packed) => {
+                    *self = packed;
+                    detached.free(alloc);
+                    other_detached.free(alloc);
+                    Ok(leaf.take(alloc).value.unwrap())
+                }
+                Err(Failed { error, children, value: other_value }) => {
+                    let other_node = Node { children, value: other_value, ..other_node };
+                    let mut pairs = BTreeMap::new();
+                    pairs.insert(other_byte, other_detached.restore(other_node, alloc));
+                    pairs.insert(byte, leaf);
+                    let children = NodeChildren::from_pairs(pairs);
+                    *self = detached.restore(Node { prefix, skipped_len, children, value }, alloc);
+                    Err(error)
+                }
+            };
+        }
+
+        let children = NodeChildren::from_pairs(pairs);
+        match PackedNode::try_new(Node { prefix, skipped_len, children, value }, alloc) {
+            Ok(packed) => {
+                *self = packed;
+                detached.free(alloc);
+                Ok(leaf.take(alloc).value.unwrap())
+            }
+            Err((mut node, error)) => {
+                let mut pairs = mem::replace(&mut node.children, NodeChildren::Empty).into_pairs();
+                pairs.insert(byte, leaf);
+                node.children = NodeChildren::from_pairs(pairs);
+                *self = detached.restore(node, alloc);
+                Err(error)
+            }
+        }
+    }
+
+    // Copy this node and everything below it into new buffers from `alloc`.
+    pub fn try_clone(&self, alloc: &A) -> Result<Self, AllocError>
+    where
+        T: Clone,
+    {
+        if self.is_empty() {
+            return Ok(PackedNode::empty());
+        }
+        let value = self.value().cloned();
+        // Frees the children cloned so far if a later one fails, or `T::clone` panics.
+        let mut cloned = ClonedChildren { pairs: BTreeMap::new(), alloc };
+        for (byte, child) in self.children() {
+            cloned.pairs.insert(byte, child.try_clone(alloc)?);
+        }
+        let node = Node {
+            prefix: self.prefix().to_owned(),
+            skipped_len: self.skipped_len(),
+            children: NodeChildren::from_pairs(mem::take(&mut cloned.pairs)),
+            value,
+        };
+        PackedNode::try_new(node, alloc).map_err(|(node, error)| {
+            cloned.pairs = node.children.into_pairs();
+            error
+        })
+    }
+}
+
+struct ClonedChildren<'a, T, A: Allocator, S: Summary<T>> {
+    pairs: BTreeMap<u8, PackedNode<T, A, S>>,
+    alloc: &'a A,
+}
+
+impl<T, A: Allocator, S: Summary<T>> Drop for ClonedChildren<'_, T, A, S> {
+    fn drop(&mut self) {
+        for (_, mut child) in mem::take(&mut self.pairs) {
+            child.drop_in(self.alloc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::summary::Summary;
+    use crate::test_helpers::random_key;
+    use crate::{Counting, Failing, Trie};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_fallible_updates() {
+        let counting = Counting::default();
+        let failing = Failing::new(&counting);
+        let mut t = Trie::new_in(&failing);
+        let mut model = BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for i in 0..2000u32 {
+            let key = random_key(&mut rng);
+            let insert = rng.gen_bool(0.6);
+            let stats = t.memory_stats();
+            let live_allocations = counting.live_allocations();
+
+            // Fail each allocation the update makes in turn, until it makes it through.
+            for n in 0.. {
+                failing.fail_after(n);
+                let result = if insert { t.try_insert(&key, i) } else { t.try_remove(&key) };
+                match result {
+                    Ok(old_value) => {
+                        if insert {
+                            assert_eq!(old_value, model.insert(key, i));
+                        } else {
+                            assert_eq!(old_value, model.remove(&key));
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        assert!(e.layout().size() > 0);
+                        // The trie's exactly like it was before.
+                        assert_eq!(t.check_invariants(), Ok(()));
+                        assert_eq!(t.memory_stats(), stats);
+                        assert!(model.iter().all(|(k, v)| t.get(k) == Some(v)));
+                        assert_eq!(counting.live_allocations(), live_allocations);
+                    }
+                }
+            }
+            failing.never_fail();
+            assert_eq!(t.check_invariants(), Ok(()));
+        }
+        assert!(t.iter().map(|(k, &v)| (k, v)).eq(model.clone()));
+        assert!(failing.failures() > 1000);
+
+        // Every node is cloned, so each allocation in turn fails.
+        let live_allocations = counting.live_allocations();
+        for n in 0..live_allocations {
+            failing.fail_after(n);
+            assert!(t.try_clone().is_err());
+            assert_eq!(counting.live_allocations(), live_allocations);
+        }
+        failing.never_fail();
+        let clone = t.clone();
+        assert_eq!(clone.check_invariants(), Ok(()));
+        assert!(clone.iter().eq(t.iter()));
+        assert_eq!(clone.memory_stats(), t.memory_stats());
+        assert_eq!(counting.live_allocations(), 2 * live_allocations);
+
+        for (k, v) in model {
+            assert_eq!(t.try_remove(&k), Ok(Some(v)));
+        }
+        drop((t, clone));
+        assert_eq!(counting.live_allocations(), 0);
+    }
+
+    #[test]
+    fn test_fallible_summary() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Sum(u64);
+
+        impl Summary<u32> for Sum {
+            fn empty() -> Self {
+                Sum(0)
+            }
+
+            fn of(value: &u32) -> Self {
+                Sum(*value as u64)
+            }
+
+            fn combine(&self, other: &Self) -> Self {
+                Sum(self.0 + other.0)
+            }
+        }
+
+        let failing = Failing::new(Counting::default());
+        let mut t = Trie::<u32, _, Sum>::with_summary_in(&failing);
+        let mut model = BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(1);
+        let sum_prefix = |model: &BTreeMap<Vec<u8>, u32>, prefix: &[u8]| {
+            Sum(model.iter().filter(|(k, _)| k.starts_with(prefix)).map(|(_, &v)| v as u64).sum())
+        };
+
+        for i in 0..500u32 {
+            let key = random_key(&mut rng);
+            let insert = rng.gen_bool(0.6);
+            for n in 0.. {
+                failing.fail_after(n);
+                let result = if insert { t.try_insert(&key, i) } else { t.try_remove(&key) };
+                if result.is_ok() {
+                    break;
+                }
+            }
+            failing.never_fail();
+            if insert {
+                model.insert(key.clone(), i);
+            } else {
+                model.remove(&key);
+            }
+            // Failed updates don't leave stale summaries behind along the key's path.
+            assert_eq!(t.summary(), sum_prefix(&model, b""));
+            for len in 0..key.len().min(3) {
+                assert_eq!(t.summarize_prefix(&key[..len]), sum_prefix(&model, &key[..len]));
+            }
+        }
+        let clone = t.clone();
+        assert_eq!(clone.summary(), t.summary());
+        assert_eq!(clone.summarize_prefix(b"x"), sum_prefix(&model, b"x"));
+    }
+
+    #[test]
+    fn test_panicking_clone() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        #[derive(Debug, PartialEq)]
+        struct PanicOnClone(u32);
+        impl Clone for PanicOnClone {
+            fn clone(&self) -> Self {
+                assert_ne!(self.0, 500, "cloning {}", self.0);
+                PanicOnClone(self.0)
+            }
+        }
+
+        let counting = Counting::default();
+        let mut t = Trie::new_in(&counting);
+        for i in 0..1000 {
+            t.insert(format!("{}", i).as_bytes(), PanicOnClone(i));
+        }
+        let live_allocations = counting.live_allocations();
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| t.clone())).is_err());
+        // The nodes cloned before the panic were freed.
+        assert_eq!(counting.live_allocations(), live_allocations);
+        assert_eq!(t.remove(b"500"), Some(PanicOnClone(500)));
+        assert_eq!(t.clone().iter().count(), 999);
+    }
+}
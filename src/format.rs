@@ -0,0 +1,666 @@
+// `Trie::write_to` and `Trie::read_from` save a trie to a stream and load it back.  The format
+// mirrors the trie's nodes, so loading builds each node once, directly from its encoding.  All
+// integers are little endian, and varints are unsigned LEB128 in their shortest form.
+//
+// ```text
+// file        = magic version num_nodes num_entries node checksum
+// magic       = "BAOBTRIE"
+// version     = u32, currently 1
+// num_nodes   = u64, the number of nodes that follow, including an empty root
+// num_entries = u64, the number of nodes with values
+// node        = flags prefix_len prefix [value_len value] index child*
+// flags       = u8, with bit 0 set if the node has a value and bits 1-2 holding its child layout
+//               (0 = no children, 1 = Pairs, 2 = Sparse, 3 = Dense); the other bits are zero
+// prefix_len  = varint, followed by that many prefix bytes
+// value_len   = varint, followed by that many bytes from the `ValueCodec`
+// index       = nothing for nodes without children; for Pairs, the number of children as a u8
+//               followed by their branch bytes in increasing order; for Sparse and Dense, a 32
+//               byte bitset with bit `b % 8` of byte `b / 8` set for each branch byte `b`
+// child       = node, in increasing order of branch byte
+// checksum    = u32, the CRC-32 of everything before it
+// ```
+//
+// Nodes follow the trie's invariants: the layout matches the number of children, and every node
+// has a value or at least two children, unless merging it into its only child would make a
+// prefix longer than `MAX_PREFIX_LEN`.  The only exception is the root of an empty trie, which is
+// written as an empty node.
+//
+// Reading never trusts lengths from the input up front, and walks the nodes with an explicit
+// stack, so corrupted input gets an error rather than a panic, an abort, or a huge allocation.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+
+use crate::allocator::{Allocator, Global};
+use crate::header::{NodeChildrenType, MAX_PREFIX_LEN};
+use crate::node::{Node, NodeChildren};
+use crate::packed_node::PackedNode;
+use crate::trie::Trie;
+
+const MAGIC: [u8; 8] = *b"BAOBTRIE";
+const VERSION: u32 = 1;
+const BITSET_LEN: usize = 32;
+
+/// Converts values to and from bytes for `Trie::write_to` and `Trie::read_from`.  Each encoded
+/// value is stored with its length, so `decode` always gets exactly the bytes `encode` wrote.
+pub trait ValueCodec<T> {
+    fn encode(&self, value: &T, out: &mut Vec<u8>);
+    fn decode(&self, bytes: &[u8]) -> Result<T, Box<dyn Error + Send + Sync>>;
+}
+
+impl<T, C: ValueCodec<T>> ValueCodec<T> for &C {
+    fn encode(&self, value: &T, out: &mut Vec<u8>) {
+        (**self).encode(value, out)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, Box<dyn Error + Send + Sync>> {
+        (**self).decode(bytes)
+    }
+}
+
+/// Stores `Vec<u8>` values as is.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BytesCodec;
+
+impl ValueCodec<Vec<u8>> for BytesCodec {
+    fn encode(&self, value: &Vec<u8>, out: &mut Vec<u8>) {
+        out.extend_from_slice(value);
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        Ok(bytes.to_owned())
+    }
+}
+
+/// Stores integers in little endian.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeCodec;
+
+macro_rules! le_codec {
+    ($($t:ty),*) => {
+        $(
+            impl ValueCodec<$t> for LeCodec {
+                fn encode(&self, value: &$t, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+
+                fn decode(&self, bytes: &[u8]) -> Result<$t, Box<dyn Error + Send + Sync>> {
+                    let mut buf = [0; mem::size_of::<$t>()];
+                    if bytes.len() != buf.len() {
+                        let msg = format!("expected {} bytes, got {}", buf.len(), bytes.len());
+                        return Err(msg.into());
+                    }
+                    buf.copy_from_slice(bytes);
+                    Ok(<$t>::from_le_bytes(buf))
+                }
+            }
+        )*
+    };
+}
+
+le_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+/// Why `Trie::read_from` couldn't load a trie, or `FrozenTrie::new` couldn't open one.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The underlying reader failed.
+    Io(io::Error),
+    /// The input ended in the middle of the trie.
+    Truncated,
+    /// The input doesn't start with the format's magic bytes.
+    BadMagic,
+    /// The input was written in a format version this build can't read.
+    UnsupportedVersion(u32),
+    /// The checksum didn't match the contents, so the input was corrupted.
+    ChecksumMismatch { stored: u32, computed: u32 },
+    /// The contents don't describe a valid trie.
+    Corrupt(&'static str),
+    /// The `ValueCodec` couldn't decode a value.
+    Value(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "failed to read trie: {}", e),
+            ReadError::Truncated => write!(f, "unexpected end of input"),
+            ReadError::BadMagic => write!(f, "input isn't a saved trie"),
+            ReadError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {} (expected {})", version, VERSION)
+            }
+            ReadError::ChecksumMismatch { stored, computed } => {
+                write!(f, "checksum mismatch: stored {:08x}, computed {:08x}", stored, computed)
+            }
+            ReadError::Corrupt(reason) => write!(f, "corrupt trie: {}", reason),
+            ReadError::Value(e) => write!(f, "failed to decode value: {}", e),
+        }
+    }
+}
+
+impl Error for ReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReadError::Io(e) => Some(e),
+            ReadError::Value(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ReadError::Truncated
+        } else {
+            ReadError::Io(e)
+        }
+    }
+}
+
+fn layout_code(children_type: NodeChildrenType) -> u8 {
+    match children_type {
+        NodeChildrenType::Empty => 0,
+        NodeChildrenType::Pairs => 1,
+        NodeChildrenType::Sparse => 2,
+        NodeChildrenType::Dense => 3,
+    }
+}
+
+// Feeds everything that passes through it into the checksum.
+struct Checksummed<S> {
+    inner: S,
+    hasher: crc32fast::Hasher,
+}
+
+impl<S> Checksummed<S> {
+    fn new(inner: S) -> Self {
+        Self { inner, hasher: crc32fast::Hasher::new() }
+    }
+}
+
+impl<W: Write> Checksummed<W> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.hasher.update(buf);
+        self.inner.write_all(buf)
+    }
+
+    fn write_varint(&mut self, mut n: u64) -> io::Result<()> {
+        let mut buf = [0; 10];
+        let mut len = 0;
+        loop {
+            buf[len] = (n & 0x7f) as u8;
+            n >>= 7;
+            len += 1;
+            if n == 0 {
+                break;
+            }
+            buf[len - 1] |= 0x80;
+        }
+        self.write_all(&buf[..len])
+    }
+}
+
+impl<R: Read> Checksummed<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ReadError> {
+        self.inner.read_exact(buf)?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ReadError> {
+        let mut buf = [0; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ReadError> {
+        let mut buf = [0; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, ReadError> {
+        let mut buf = [0; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_varint(&mut self) -> Result<u64, ReadError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                break;
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                // A trailing zero byte could have been left off, and every trie has exactly one
+                // encoding.
+                if byte == 0 && shift > 0 {
+                    return Err(ReadError::Corrupt("varint isn't in its shortest form"));
+                }
+                return Ok(n);
+            }
+        }
+        Err(ReadError::Corrupt("varint overflows 64 bits"))
+    }
+
+    // Read `len` bytes, only allocating as much as the input actually has.
+    fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>, ReadError> {
+        let mut buf = vec![];
+        (&mut self.inner).take(len).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < len {
+            return Err(ReadError::Truncated);
+        }
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+}
+
+fn count_nodes<T, A>(node: &PackedNode<T, A>, num_nodes: &mut u64, num_entries: &mut u64) {
+    *num_nodes += 1;
+    *num_entries += node.has_value() as u64;
+    for (_, child) in node.children() {
+        count_nodes(child, num_nodes, num_entries);
+    }
+}
+
+fn write_node<T, A, W: Write>(
+    node: &PackedNode<T, A>,
+    out: &mut Checksummed<W>,
+    codec: &impl ValueCodec<T>,
+    value_buf: &mut Vec<u8>,
+) -> io::Result<()> {
+    let children_type = match node.header() {
+        Some(header) => header.children_type(),
+        None => NodeChildrenType::Empty,
+    };
+    let flags = node.has_value() as u8 | layout_code(children_type) << 1;
+    out.write_all(&[flags])?;
+    out.write_varint(node.prefix().len() as u64)?;
+    out.write_all(node.prefix())?;
+    if let Some(value) = node.value() {
+        value_buf.clear();
+        codec.encode(value, value_buf);
+        out.write_varint(value_buf.len() as u64)?;
+        out.write_all(value_buf)?;
+    }
+    match children_type {
+        NodeChildrenType::Empty => (),
+        NodeChildrenType::Pairs => {
+            let keys = node.children().map(|(byte, _)| byte).collect::<Vec<_>>();
+            out.write_all(&[keys.len() as u8])?;
+            out.write_all(&keys)?;
+        }
+        NodeChildrenType::Sparse | NodeChildrenType::Dense => {
+            let mut bitset = [0u8; BITSET_LEN];
+            for (byte, _) in node.children() {
+                bitset[byte as usize / 8] |= 1 << (byte % 8);
+            }
+            out.write_all(&bitset)?;
+        }
+    }
+    for (_, child) in node.children() {
+        write_node(child, out, codec, value_buf)?;
+    }
+    Ok(())
+}
+
+// A node whose children are still being read.
+struct Frame<T, A> {
+    prefix: Vec<u8>,
+    value: Option<T>,
+    branches: Vec<u8>,
+    children: BTreeMap<u8, PackedNode<T, A>>,
+}
+
+impl<T, A: Allocator> Frame<T, A> {
+    fn read<R: Read>(
+        input: &mut Checksummed<R>,
+        codec: &impl ValueCodec<T>,
+        is_root: bool,
+    ) -> Result<Self, ReadError> {
+        let flags = input.read_u8()?;
+        if flags & !0b111 != 0 {
+            return Err(ReadError::Corrupt("unknown node flags"));
+        }
+        let prefix_len = input.read_varint()?;
+        if prefix_len > MAX_PREFIX_LEN as u64 {
+            return Err(ReadError::Corrupt("prefix is too long"));
+        }
+        let prefix = input.read_bytes(prefix_len)?;
+        let value = if flags & 1 != 0 {
+            let value_len = input.read_varint()?;
+            let value_bytes = input.read_bytes(value_len)?;
+            Some(codec.decode(&value_bytes).map_err(ReadError::Value)?)
+        } else {
+            None
+        };
+        let layout = flags >> 1;
+        let branches = match layout {
+            0 => vec![],
+            1 => {
+                let num_children = input.read_u8()?;
+                let mut keys = vec![0; num_children as usize];
+                input.read_exact(&mut keys)?;
+                if keys.windows(2).any(|w| w[0] >= w[1]) {
+                    return Err(ReadError::Corrupt("pairs keys aren't sorted"));
+                }
+                keys
+            }
+            _ => {
+                let mut bitset = [0u8; BITSET_LEN];
+                input.read_exact(&mut bitset)?;
+                (0..=255u8).filter(|&b| bitset[b as usize / 8] & (1 << (b % 8)) != 0).collect()
+            }
+        };
+        if layout_code(NodeChildrenType::from_count(branches.len())) != layout {
+            return Err(ReadError::Corrupt("child layout doesn't match the number of children"));
+        }
+        match (value.is_some(), branches.len()) {
+            (false, 0) if !is_root || !prefix.is_empty() => {
+                return Err(ReadError::Corrupt("node has neither a value nor children"));
+            }
+            _ => (),
+        }
+        Ok(Self { prefix, value, branches, children: BTreeMap::new() })
+    }
+
+    fn finish(self, alloc: &A) -> PackedNode<T, A> {
+        if self.value.is_none() && self.branches.is_empty() {
+            return PackedNode::empty();
+        }
+        let children = NodeChildren::from_pairs(self.children);
+        PackedNode::new(Node::new(self.prefix, children, self.value, alloc), alloc)
+    }
+
+    fn drop_in(self, alloc: &A) {
+        for (_, mut child) in self.children {
+            child.drop_in(alloc);
+        }
+    }
+}
+
+struct FrameStack<'a, T, A: Allocator> {
+    frames: Vec<Frame<T, A>>,
+    alloc: &'a A,
+}
+
+impl<T, A: Allocator> Drop for FrameStack<'_, T, A> {
+    fn drop(&mut self) {
+        for frame in self.frames.drain(..) {
+            frame.drop_in(self.alloc);
+        }
+    }
+}
+
+// Read the root node and everything below it.  On error, the nodes read so far are freed.
+fn read_root<T, A: Allocator, R: Read>(
+    input: &mut Checksummed<R>,
+    codec: &impl ValueCodec<T>,
+    num_nodes: u64,
+    num_entries: u64,
+    alloc: &A,
+) -> Result<PackedNode<T, A>, ReadError> {
+    // Frees the nodes read so far if we return early, or the codec or `input` panics.
+    let mut stack = FrameStack { frames: vec![], alloc };
+    let mut nodes_read = 0;
+    let mut entries_read = 0;
+    let result = loop {
+        let frames = &mut stack.frames;
+        let frame = match frames.last_mut() {
+            Some(frame) if frame.children.len() == frame.branches.len() => frames.pop().unwrap(),
+            _ => {
+                if nodes_read == num_nodes {
+                    break Err(ReadError::Corrupt("more nodes than the header says"));
+                }
+                match Frame::read(input, codec, frames.is_empty()) {
+                    Ok(frame) => {
+                        nodes_read += 1;
+                        entries_read += frame.value.is_some() as u64;
+                        frames.push(frame);
+                    }
+                    Err(e) => break Err(e),
+                }
+                continue;
+            }
+        };
+        let mut node = frame.finish(alloc);
+        match frames.last_mut() {
+            None => break Ok(node),
+            Some(parent) => {
+                // A parent without a value can only have a single child when their prefixes
+                // are too long to merge.
+                let mergeable = parent.prefix.len() + 1 + node.prefix().len() <= MAX_PREFIX_LEN;
+                if parent.value.is_none() && parent.branches.len() == 1 && mergeable {
+                    node.drop_in(alloc);
+                    break Err(ReadError::Corrupt("node without a value has a single child"));
+                }
+                let byte = parent.branches[parent.children.len()];
+                parent.children.insert(byte, node);
+            }
+        }
+    };
+    drop(stack);
+    let mut root = result?;
+    if (nodes_read, entries_read) != (num_nodes, num_entries) {
+        root.drop_in(alloc);
+        return Err(ReadError::Corrupt("node or entry count doesn't match the header"));
+    }
+    Ok(root)
+}
+
+impl<T> Trie<T> {
+    /// See `read_from_in`.
+    pub fn read_from(input: impl Read, codec: impl ValueCodec<T>) -> Result<Self, ReadError> {
+        Self::read_from_in(input, codec, Global)
+    }
+}
+
+impl<T, A: Allocator> Trie<T, A> {
+    /// Save the trie to `out`, using `codec` to encode its values.  The format is documented in
+    /// `format.rs`.  This makes many small writes, so `out` should be buffered.
+    pub fn write_to(&self, out: impl Write, codec: impl ValueCodec<T>) -> io::Result<()> {
+        let (mut num_nodes, mut num_entries) = (0, 0);
+        count_nodes(&self.root, &mut num_nodes, &mut num_entries);
+
+        let mut out = Checksummed::new(out);
+        out.write_all(&MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&num_nodes.to_le_bytes())?;
+        out.write_all(&num_entries.to_le_bytes())?;
+        write_node(&self.root, &mut out, &codec, &mut vec![])?;
+        let checksum = out.hasher.clone().finalize();
+        out.inner.write_all(&checksum.to_le_bytes())?;
+        out.inner.flush()
+    }
+
+    /// Load a trie saved by `write_to`, using `codec` to decode its values.  Corrupted or
+    /// truncated input returns an error.  This makes many small reads, so `input` should be
+    /// buffered.  It stops right after the trie, so more data can follow it in the stream.
+    pub fn read_from_in(
+        input: impl Read,
+        codec: impl ValueCodec<T>,
+        alloc: A,
+    ) -> Result<Self, ReadError> {
+        let mut input = Checksummed::new(input);
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(ReadError::BadMagic);
+        }
+        let version = input.read_u32()?;
+        if version != VERSION {
+            return Err(ReadError::UnsupportedVersion(version));
+        }
+        let num_nodes = input.read_u64()?;
+        let num_entries = input.read_u64()?;
+
+        let mut trie = Self::new_in(alloc);
+        trie.root = read_root(&mut input, &codec, num_nodes, num_entries, &trie.alloc)?;
+        let computed = input.hasher.clone().finalize();
+        let mut stored = [0; 4];
+        input.inner.read_exact(&mut stored)?;
+        let stored = u32::from_le_bytes(stored);
+        if stored != computed {
+            return Err(ReadError::ChecksumMismatch { stored, computed });
+        }
+        trie.debug_check_invariants();
+        Ok(trie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BytesCodec, LeCodec, ReadError};
+    use crate::Trie;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn sample_trie(n: u64) -> Trie<u64> {
+        let mut t = Trie::new();
+        for i in 0..n {
+            t.insert(format!("{}", i * 37 % 2003).as_bytes(), i);
+        }
+        for i in 0..300u64 {
+            t.insert(&[0xff, (i % 256) as u8, (i / 256) as u8], i);
+        }
+        t.insert(&[b'x'; 200], 1);
+        t.insert(&[], 2);
+        t
+    }
+
+    #[test]
+    fn test_format_roundtrip() {
+        let t = sample_trie(2000);
+        let mut buf = vec![];
+        t.write_to(&mut buf, LeCodec).unwrap();
+        let loaded = Trie::<u64>::read_from(&buf[..], LeCodec).unwrap();
+        assert!(t.iter().eq(loaded.iter()));
+        assert_eq!(t.memory_stats(), loaded.memory_stats());
+        assert_eq!(loaded.check_invariants(), Ok(()));
+
+        // Reading stops at the end of the trie.
+        let trie_len = buf.len();
+        buf.extend_from_slice(b"more");
+        let mut input = &buf[..];
+        Trie::<u64>::read_from(&mut input, LeCodec).unwrap();
+        assert_eq!(input, b"more");
+        buf.truncate(trie_len);
+
+        let mut t = Trie::new();
+        let mut buf = vec![];
+        t.write_to(&mut buf, BytesCodec).unwrap();
+        assert!(Trie::read_from(&buf[..], BytesCodec).unwrap().iter().next().is_none());
+        t.insert(b"abc", b"def".to_vec());
+        buf.clear();
+        t.write_to(&mut buf, BytesCodec).unwrap();
+        assert!(t.iter().eq(Trie::read_from(&buf[..], BytesCodec).unwrap().iter()));
+    }
+
+    #[test]
+    fn test_format_corruption() {
+        let t = sample_trie(200);
+        let mut buf = vec![];
+        t.write_to(&mut buf, LeCodec).unwrap();
+
+        for len in (0..buf.len()).step_by(5) {
+            let err = Trie::<u64>::read_from(&buf[..len], LeCodec).err().unwrap();
+            assert!(matches!(err, ReadError::Truncated), "{} at {}", err, len);
+        }
+        let mut bad = buf.clone();
+        bad[0] ^= 1;
+        assert!(matches!(Trie::<u64>::read_from(&bad[..], LeCodec), Err(ReadError::BadMagic)));
+        let mut bad = buf.clone();
+        bad[8] = 2;
+        let err = Trie::<u64>::read_from(&bad[..], LeCodec).err().unwrap();
+        assert!(matches!(err, ReadError::UnsupportedVersion(2)));
+        let err = Trie::<u32>::read_from(&buf[..], LeCodec).err().unwrap();
+        assert!(matches!(err, ReadError::Value(..)));
+
+        // Flipped bits anywhere after the header are caught, usually by the checksum.  If we fix
+        // up the checksum, they either get caught by validation or load a valid trie.
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..500 {
+            let mut bad = buf.clone();
+            let i = rng.gen_range(12, buf.len() - 4);
+            bad[i] ^= 1 << rng.gen_range(0, 8);
+            assert!(Trie::<u64>::read_from(&bad[..], LeCodec).is_err());
+
+            let checksum_start = bad.len() - 4;
+            let checksum = crc32fast::hash(&bad[..checksum_start]);
+            bad[checksum_start..].copy_from_slice(&checksum.to_le_bytes());
+            if let Ok(loaded) = Trie::<u64>::read_from(&bad[..], LeCodec) {
+                assert_eq!(loaded.check_invariants(), Ok(()));
+                let mut again = vec![];
+                loaded.write_to(&mut again, LeCodec).unwrap();
+                assert_eq!(again, bad);
+            }
+        }
+
+        // Overlong varints are rejected, so each trie has a single encoding.
+        let mut t = Trie::new();
+        t.insert(b"abc", b"def".to_vec());
+        let mut buf = vec![];
+        t.write_to(&mut buf, BytesCodec).unwrap();
+        assert_eq!(buf[29..33], [3, b'a', b'b', b'c']);
+        let mut bad = buf[..29].to_vec();
+        bad.extend_from_slice(&[0x83, 0]);
+        bad.extend_from_slice(&buf[30..(buf.len() - 4)]);
+        bad.extend_from_slice(&crc32fast::hash(&bad).to_le_bytes());
+        let err = Trie::<Vec<u8>>::read_from(&bad[..], BytesCodec).err().unwrap();
+        assert!(matches!(err, ReadError::Corrupt(_)), "{}", err);
+
+        let mut t = Trie::new();
+        t.insert(b"abc", b"x".to_vec());
+        t.insert(b"abd", b"y".to_vec());
+        let mut buf = vec![];
+        t.write_to(&mut buf, BytesCodec).unwrap();
+        assert_eq!(buf[28..43], [2, 2, b'a', b'b', 2, b'c', b'd', 1, 0, 1, b'x', 1, 0, 1, b'y']);
+
+        // Dropping one of the children leaves a node that should have been merged into the other.
+        let mut bad = buf[..12].to_vec();
+        bad.extend_from_slice(&2u64.to_le_bytes());
+        bad.extend_from_slice(&1u64.to_le_bytes());
+        bad.extend_from_slice(&[2, 2, b'a', b'b', 1, b'c', 1, 0, 1, b'x']);
+        bad.extend_from_slice(&crc32fast::hash(&bad).to_le_bytes());
+        let err = Trie::<Vec<u8>>::read_from(&bad[..], BytesCodec).err().unwrap();
+        assert!(matches!(err, ReadError::Corrupt("node without a value has a single child")));
+    }
+
+    #[test]
+    fn test_format_panicking_codec() {
+        use super::ValueCodec;
+        use crate::Counting;
+        use std::error::Error;
+        use std::panic::{self, AssertUnwindSafe};
+
+        struct PanicOn(u64);
+        impl ValueCodec<u64> for PanicOn {
+            fn encode(&self, value: &u64, out: &mut Vec<u8>) {
+                LeCodec.encode(value, out)
+            }
+
+            fn decode(&self, bytes: &[u8]) -> Result<u64, Box<dyn Error + Send + Sync>> {
+                let value = LeCodec.decode(bytes)?;
+                assert_ne!(value, self.0, "decoding {}", value);
+                Ok(value)
+            }
+        }
+
+        let t = sample_trie(200);
+        let mut buf = vec![];
+        t.write_to(&mut buf, LeCodec).unwrap();
+        // The nodes read before the codec panics are freed.
+        let counting = Counting::default();
+        let read = AssertUnwindSafe(|| Trie::read_from_in(&buf[..], PanicOn(150), &counting));
+        assert!(panic::catch_unwind(read).is_err());
+        assert_eq!(counting.live_allocations(), 0);
+        let loaded = Trie::read_from_in(&buf[..], PanicOn(u64::MAX), &counting).unwrap();
+        assert!(loaded.iter().eq(t.iter()));
+    }
+}
@@ -0,0 +1,734 @@
+// A `FrozenTrie` is queried in place from a byte buffer, like a memory-mapped file, without
+// loading it into nodes first.  `Trie::write_frozen` writes the buffer.  Each node keeps the
+// layout of a `PackedNode` buffer, except that its child slots hold relative offsets instead of
+// pointers, so the buffer can live at any address.  All integers are little endian, so files can
+// move between machines.
+//
+// ```text
+// file        = magic version node* root num_entries
+// magic       = "BAOBFRZN"
+// version     = u32, currently 1
+// node        = header prefix index slots [value_len value]
+// header      = the node's `NodeHeader`, which can't skip any prefix bytes
+// index       = the branch bytes for Pairs, the bitset for Sparse (with bit `b % 8` of byte
+//               `b / 8` set for each branch byte `b`), and nothing for Dense
+// slots       = u32 for each child slot: how far back the child starts from the start of this
+//               node, or zero for an empty Dense slot
+// value_len   = u32, followed by that many bytes from the `ValueCodec`
+// root        = u64, the root node's offset from the start of the file, or zero if it's empty
+// num_entries = u64, the number of nodes with values
+// ```
+//
+// Nodes are written in post-order, with each node's subtree immediately before it, so a child
+// always comes before its parent.  `FrozenTrie::new` checks that walking the trie from the root
+// visits every node in the buffer exactly once, in order.  After that, lookups can't leave the
+// buffer or loop, no matter what the buffer held.
+
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::ops::{Bound, Range, RangeBounds};
+
+use crate::allocator::Allocator;
+use crate::format::{ReadError, ValueCodec};
+use crate::header::{NodeChildrenType, NodeHeader};
+use crate::packed_node::PackedNode;
+use crate::prefix;
+use crate::trie::Trie;
+
+const MAGIC: [u8; 8] = *b"BAOBFRZN";
+const VERSION: u32 = 1;
+const FILE_HEADER_LEN: usize = 12;
+const TRAILER_LEN: usize = 16;
+const OFFSET_LEN: usize = 4;
+const BITSET_LEN: usize = 32;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn bitset_contains(bitset: &[u8], byte: usize) -> bool {
+    bitset[byte / 8] & (1 << (byte % 8)) != 0
+}
+
+#[derive(Clone, Copy)]
+struct FrozenNode<'a> {
+    start: usize,
+    header: NodeHeader<()>,
+    // The node's bytes, from its header through its value.
+    bytes: &'a [u8],
+}
+
+impl<'a> FrozenNode<'a> {
+    // Parse the node at `start`, or return `None` if it doesn't fit in `buf`.
+    fn parse(buf: &'a [u8], start: usize) -> Option<Self> {
+        let rest = buf.get(start..)?;
+        let header = NodeHeader::from_bytes(rest)?;
+        if header.skipped_len() != 0 {
+            return None;
+        }
+        let slots_end = Self::slots_range(header).end;
+        let end = if header.has_value() {
+            let value_len = read_u32(rest.get(slots_end..(slots_end + OFFSET_LEN))?);
+            slots_end + OFFSET_LEN + value_len as usize
+        } else {
+            slots_end
+        };
+        Some(Self { start, header, bytes: rest.get(..end)? })
+    }
+
+    fn slots_range(header: NodeHeader<()>) -> Range<usize> {
+        let slots_start = header.prefix_range().end + header.index_len();
+        slots_start..(slots_start + OFFSET_LEN * header.num_slots())
+    }
+
+    fn end(&self) -> usize {
+        self.start + self.bytes.len()
+    }
+
+    fn prefix(&self) -> &'a [u8] {
+        &self.bytes[self.header.prefix_range()]
+    }
+
+    fn index(&self) -> &'a [u8] {
+        let index_start = self.header.prefix_range().end;
+        &self.bytes[index_start..(index_start + self.header.index_len())]
+    }
+
+    fn value(&self) -> Option<&'a [u8]> {
+        if !self.header.has_value() {
+            return None;
+        }
+        Some(&self.bytes[(Self::slots_range(self.header).end + OFFSET_LEN)..])
+    }
+
+    // Where the child in slot `i` starts, or `None` for an empty slot or an offset that points
+    // past the start of the buffer.
+    fn child_start(&self, i: usize) -> Option<usize> {
+        let offset_start = Self::slots_range(self.header).start + OFFSET_LEN * i;
+        match read_u32(&self.bytes[offset_start..]) {
+            0 => None,
+            offset => self.start.checked_sub(offset as usize),
+        }
+    }
+
+    // The first child slot with a branch byte of at least `from`, along with its byte.
+    fn child_after(&self, from: usize) -> Option<(u8, usize)> {
+        match self.header.children_type() {
+            NodeChildrenType::Empty => None,
+            NodeChildrenType::Pairs => {
+                let keys = self.index();
+                let i = keys.iter().position(|&k| k as usize >= from)?;
+                Some((keys[i], i))
+            }
+            NodeChildrenType::Sparse => {
+                let bitset = self.index();
+                let byte = (from..256).find(|&b| bitset_contains(bitset, b))?;
+                let full_bytes = bitset[..(byte / 8)].iter().map(|b| b.count_ones() as usize);
+                let partial = (bitset[byte / 8] & ((1 << (byte % 8)) - 1)).count_ones() as usize;
+                Some((byte as u8, full_bytes.sum::<usize>() + partial))
+            }
+            NodeChildrenType::Dense => {
+                let byte = (from..256).find(|&b| self.child_start(b).is_some())?;
+                Some((byte as u8, byte))
+            }
+        }
+    }
+
+    fn child(&self, buf: &'a [u8], byte: u8) -> Option<FrozenNode<'a>> {
+        match self.child_after(byte as usize)? {
+            (b, i) if b == byte => FrozenNode::parse(buf, self.child_start(i)?),
+            _ => None,
+        }
+    }
+
+    // The number of values in this node's subtree.  Frozen nodes don't store counts, so this
+    // visits every node below this one, but it reads their offsets straight out of the child
+    // slots instead of looking up each branch byte.
+    fn count_values(&self, buf: &'a [u8]) -> usize {
+        let mut count = 0;
+        let mut stack = vec![*self];
+        while let Some(node) = stack.pop() {
+            count += node.header.has_value() as usize;
+            for i in 0..node.header.num_slots() {
+                if let Some(child) = node.child_start(i).and_then(|s| FrozenNode::parse(buf, s)) {
+                    stack.push(child);
+                }
+            }
+        }
+        count
+    }
+
+    // The number of values below this node with keys in `range`, where `key` holds the bytes
+    // leading up to the node.  Only the nodes along the paths to the range's two ends get
+    // split up by branch byte: subtrees inside the range are counted whole.
+    fn count_range(
+        &self,
+        buf: &'a [u8],
+        key: &mut Vec<u8>,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> usize {
+        let key_len = key.len();
+        key.extend_from_slice(self.prefix());
+        let count = match prefix::range_contains_prefix(range, key) {
+            Some(true) => self.count_values(buf),
+            Some(false) => 0,
+            None => {
+                let mut count = (self.header.has_value() && range.contains(&key[..])) as usize;
+                let mut next_byte = 0;
+                while let Some((byte, i)) = self.child_after(next_byte) {
+                    next_byte = byte as usize + 1;
+                    if let Some(child) = self.child_start(i).and_then(|s| Self::parse(buf, s)) {
+                        key.push(byte);
+                        count += child.count_range(buf, key, range);
+                        key.pop();
+                    }
+                }
+                count
+            }
+        };
+        key.truncate(key_len);
+        count
+    }
+
+    // Check the parts of the node that `parse` doesn't.
+    fn validate(&self) -> Result<(), ReadError> {
+        let num_children = self.header.num_children();
+        let live_children = match self.header.children_type() {
+            NodeChildrenType::Empty => 0,
+            NodeChildrenType::Pairs => {
+                if self.index().windows(2).any(|w| w[0] >= w[1]) {
+                    return Err(ReadError::Corrupt("pairs keys aren't sorted"));
+                }
+                num_children
+            }
+            NodeChildrenType::Sparse => {
+                self.index().iter().map(|b| b.count_ones() as usize).sum::<usize>()
+            }
+            NodeChildrenType::Dense => (0..256).filter(|&i| self.child_start(i).is_some()).count(),
+        };
+        if live_children != num_children {
+            return Err(ReadError::Corrupt("child count doesn't match the header"));
+        }
+        Ok(())
+    }
+}
+
+/// A read-only trie that's queried in place from a buffer written by `Trie::write_frozen`,
+/// without allocating any nodes.  Values are the bytes written by the trie's `ValueCodec`.  The
+/// buffer can come from anywhere, like a memory-mapped file: it's fully validated when the
+/// `FrozenTrie` is created.
+#[derive(Clone, Copy)]
+pub struct FrozenTrie<'a> {
+    // Just the nodes, without the file header or trailer.
+    buf: &'a [u8],
+    root: Option<usize>,
+    len: usize,
+}
+
+impl<'a> FrozenTrie<'a> {
+    /// Check that `buf` holds a valid frozen trie, and open it.  This visits every node once.
+    pub fn new(buf: &'a [u8]) -> Result<Self, ReadError> {
+        if buf.len() < MAGIC.len() || buf[..MAGIC.len()] != MAGIC {
+            return Err(ReadError::BadMagic);
+        }
+        if buf.len() < FILE_HEADER_LEN + TRAILER_LEN {
+            return Err(ReadError::Truncated);
+        }
+        let version = read_u32(&buf[MAGIC.len()..]);
+        if version != VERSION {
+            return Err(ReadError::UnsupportedVersion(version));
+        }
+        let nodes_end = buf.len() - TRAILER_LEN;
+        let root = read_u64(&buf[nodes_end..]);
+        let num_entries = read_u64(&buf[(nodes_end + 8)..]);
+        let nodes = &buf[..nodes_end];
+        if root == 0 {
+            if nodes_end != FILE_HEADER_LEN || num_entries != 0 {
+                return Err(ReadError::Corrupt("empty trie with nodes or entries"));
+            }
+            return Ok(Self { buf: nodes, root: None, len: 0 });
+        }
+        let root = usize::try_from(root).map_err(|_| ReadError::Corrupt("root out of bounds"))?;
+        let root_node = FrozenNode::parse(nodes, root)
+            .filter(|_| root >= FILE_HEADER_LEN)
+            .ok_or(ReadError::Corrupt("root out of bounds"))?;
+        root_node.validate()?;
+
+        // Walk the trie in post-order, checking that each node ends where the next begins.
+        // Nodes are validated before we look at their children, so their child indexes agree
+        // with their slots.
+        let mut expected_start = FILE_HEADER_LEN;
+        let mut entries = 0;
+        let mut stack = vec![(root_node, 0)];
+        while let Some((node, next_byte)) = stack.last_mut() {
+            let node = *node;
+            match node.child_after(*next_byte) {
+                Some((byte, i)) => {
+                    *next_byte = byte as usize + 1;
+                    let child = node
+                        .child_start(i)
+                        .filter(|&start| start >= FILE_HEADER_LEN)
+                        .and_then(|start| FrozenNode::parse(nodes, start))
+                        .ok_or(ReadError::Corrupt("child out of bounds"))?;
+                    child.validate()?;
+                    stack.push((child, 0));
+                }
+                None => {
+                    stack.pop();
+                    if node.start != expected_start {
+                        return Err(ReadError::Corrupt("nodes aren't in post-order"));
+                    }
+                    expected_start = node.end();
+                    entries += node.header.has_value() as u64;
+                }
+            }
+        }
+        if expected_start != nodes_end {
+            return Err(ReadError::Corrupt("root isn't the last node"));
+        }
+        if entries != num_entries {
+            return Err(ReadError::Corrupt("entry count doesn't match the trailer"));
+        }
+        Ok(Self { buf: nodes, root: Some(root), len: entries as usize })
+    }
+
+    fn root(&self) -> Option<FrozenNode<'a>> {
+        FrozenNode::parse(self.buf, self.root?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        let mut cur = self.root()?;
+        let mut key = key;
+        loop {
+            let node_prefix = cur.prefix();
+            if !prefix::starts_with(key, node_prefix) {
+                return None;
+            }
+            let (&branch_byte, rest) = match key[node_prefix.len()..].split_first() {
+                None => return cur.value(),
+                Some(p) => p,
+            };
+            key = rest;
+            cur = cur.child(self.buf, branch_byte)?;
+        }
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Iterate over all entries in key order.
+    pub fn iter(&self) -> Iter<'a> {
+        self.range::<&[u8], _>(..)
+    }
+
+    /// Iterate over the entries with keys in `range`, in key order.  Subtrees that are entirely
+    /// outside of the range are skipped.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter<'a> {
+        let owned_bound = |bound| match bound {
+            Bound::Included(k) => Bound::Included(K::as_ref(k).to_owned()),
+            Bound::Excluded(k) => Bound::Excluded(K::as_ref(k).to_owned()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Iter {
+            buf: self.buf,
+            key: vec![],
+            stack: self.root().map(|root| Frame::new(root, 0)).into_iter().collect(),
+            start: owned_bound(range.start_bound()),
+            end: owned_bound(range.end_bound()),
+        }
+    }
+
+    /// Iterate over the entries whose keys start with `key_prefix`, in key order.
+    pub fn iter_prefix(&self, key_prefix: &[u8]) -> Iter<'a> {
+        let mut iter = Iter {
+            buf: self.buf,
+            key: vec![],
+            stack: vec![],
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        };
+        let mut cur = match self.root() {
+            Some(root) => root,
+            None => return iter,
+        };
+        let mut rest = key_prefix;
+        loop {
+            let node_prefix = cur.prefix();
+            if rest.len() <= node_prefix.len() {
+                if node_prefix.starts_with(rest) {
+                    iter.stack.push(Frame::new(cur, iter.key.len()));
+                }
+                return iter;
+            }
+            if !rest.starts_with(node_prefix) {
+                return iter;
+            }
+            let branch_byte = rest[node_prefix.len()];
+            cur = match cur.child(self.buf, branch_byte) {
+                Some(child) => child,
+                None => return iter,
+            };
+            iter.key.extend_from_slice(node_prefix);
+            iter.key.push(branch_byte);
+            rest = &rest[(node_prefix.len() + 1)..];
+        }
+    }
+}
+
+impl<'a> FrozenTrie<'a> {
+    /// The number of keys that start with `key_prefix`.  Frozen tries don't store counts, so
+    /// this visits every node holding one of them, though it doesn't build their keys like
+    /// iterating would.
+    pub fn count_prefix(&self, key_prefix: &[u8]) -> usize {
+        match self.iter_prefix(key_prefix).stack.first() {
+            Some(frame) => frame.node.count_values(self.buf),
+            None => 0,
+        }
+    }
+
+    /// The number of keys in `range`.  Subtrees that are entirely inside or outside of the
+    /// range are counted or skipped as a whole, without building their keys.
+    pub fn count_range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> usize {
+        match self.root() {
+            Some(root) => root.count_range(self.buf, &mut vec![], prefix::byte_bounds(&range)),
+            None => 0,
+        }
+    }
+}
+
+struct Frame<'a> {
+    node: FrozenNode<'a>,
+    // The length of the key leading up to the node's prefix.
+    key_len: usize,
+    entered: bool,
+    next_byte: usize,
+}
+
+impl<'a> Frame<'a> {
+    fn new(node: FrozenNode<'a>, key_len: usize) -> Self {
+        Self { node, key_len, entered: false, next_byte: 0 }
+    }
+}
+
+/// An iterator over a `FrozenTrie`'s entries in key order.
+pub struct Iter<'a> {
+    buf: &'a [u8],
+    key: Vec<u8>,
+    stack: Vec<Frame<'a>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Vec<u8>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.stack.last_mut()?;
+            let node = frame.node;
+            if !frame.entered {
+                frame.entered = true;
+                self.key.truncate(frame.key_len);
+                self.key.extend_from_slice(node.prefix());
+
+                // Every key below this node starts with `key`, so if it's past the end, so is
+                // everything after it.
+                let past_end = match &self.end {
+                    Bound::Included(end) => &self.key > end,
+                    Bound::Excluded(end) => &self.key >= end,
+                    Bound::Unbounded => false,
+                };
+                if past_end {
+                    self.stack.clear();
+                    return None;
+                }
+                let below_start = match &self.start {
+                    Bound::Included(start) | Bound::Excluded(start) => {
+                        &self.key < start && !start.starts_with(&self.key)
+                    }
+                    Bound::Unbounded => false,
+                };
+                if below_start {
+                    self.stack.pop();
+                    continue;
+                }
+                if let Some(value) = node.value() {
+                    let after_start = match &self.start {
+                        Bound::Included(start) => &self.key >= start,
+                        Bound::Excluded(start) => &self.key > start,
+                        Bound::Unbounded => true,
+                    };
+                    if after_start {
+                        return Some((self.key.clone(), value));
+                    }
+                }
+                continue;
+            }
+            match node.child_after(frame.next_byte) {
+                Some((byte, i)) => {
+                    frame.next_byte = byte as usize + 1;
+                    self.key.truncate(frame.key_len + node.prefix().len());
+                    self.key.push(byte);
+                    let child = node.child_start(i).and_then(|s| FrozenNode::parse(self.buf, s))?;
+                    self.stack.push(Frame::new(child, self.key.len()));
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+// Tracks how much has been written, so nodes know their own offsets.
+struct Counted<W> {
+    inner: W,
+    pos: u64,
+}
+
+impl<W: Write> Counted<W> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "frozen trie offset doesn't fit in 32 bits")
+}
+
+// Write the subtree below `node` in post-order, returning the offset of `node` itself.
+fn write_node<T, A, W: Write>(
+    node: &PackedNode<T, A>,
+    out: &mut Counted<W>,
+    codec: &impl ValueCodec<T>,
+) -> io::Result<u64> {
+    let mut children = vec![];
+    for (byte, child) in node.children() {
+        children.push((byte, write_node(child, out, codec)?));
+    }
+    let start = out.pos;
+    let header = NodeHeader::<()>::new(node.prefix().len(), children.len(), node.has_value());
+    let mut buf = vec![0; header.header_range().len()];
+    header.write(&mut buf);
+    buf.extend_from_slice(node.prefix());
+    match header.children_type() {
+        NodeChildrenType::Pairs => buf.extend(children.iter().map(|&(byte, _)| byte)),
+        NodeChildrenType::Sparse => {
+            let mut bitset = [0u8; BITSET_LEN];
+            for &(byte, _) in &children {
+                bitset[byte as usize / 8] |= 1 << (byte % 8);
+            }
+            buf.extend_from_slice(&bitset);
+        }
+        NodeChildrenType::Empty | NodeChildrenType::Dense => (),
+    }
+    let mut offsets = vec![0u32; header.num_slots()];
+    for (i, &(byte, child_start)) in children.iter().enumerate() {
+        let slot = match header.children_type() {
+            NodeChildrenType::Dense => byte as usize,
+            _ => i,
+        };
+        offsets[slot] = u32::try_from(start - child_start).map_err(|_| too_large())?;
+    }
+    for offset in offsets {
+        buf.extend_from_slice(&offset.to_le_bytes());
+    }
+    if let Some(value) = node.value() {
+        let mut value_buf = vec![];
+        codec.encode(value, &mut value_buf);
+        let value_len = u32::try_from(value_buf.len()).map_err(|_| too_large())?;
+        buf.extend_from_slice(&value_len.to_le_bytes());
+        buf.extend_from_slice(&value_buf);
+    }
+    out.write_all(&buf)?;
+    Ok(start)
+}
+
+impl<T, A: Allocator> Trie<T, A> {
+    /// Write the trie in the format read by `FrozenTrie`, using `codec` to encode its values.
+    /// The format is documented in `frozen.rs`.  Since child offsets are 32 bits, the nodes
+    /// below any one node can't take up more than 4 GiB.
+    pub fn write_frozen(&self, out: impl Write, codec: impl ValueCodec<T>) -> io::Result<()> {
+        let mut out = Counted { inner: out, pos: 0 };
+        out.write_all(&MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        let (root, num_entries) = if self.root.is_empty() {
+            (0, 0)
+        } else {
+            let root = write_node(&self.root, &mut out, &codec)?;
+            (root, self.len() as u64)
+        };
+        out.write_all(&root.to_le_bytes())?;
+        out.write_all(&num_entries.to_le_bytes())?;
+        out.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrozenTrie;
+    use crate::{BytesCodec, LeCodec, ReadError, Trie};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::fs::{self, File};
+    use std::io::{BufWriter, Write};
+
+    #[test]
+    fn test_frozen() {
+        let mut t = Trie::new();
+        let mut model = BTreeMap::new();
+        for i in 0..3000u64 {
+            let key = format!("{}", i * 37 % 3001).into_bytes();
+            t.insert(&key, i);
+            model.insert(key, i.to_le_bytes().to_vec());
+        }
+        for i in 0..300u64 {
+            let key = vec![0xff, (i % 256) as u8, (i / 256) as u8];
+            t.insert(&key, i);
+            model.insert(key, i.to_le_bytes().to_vec());
+        }
+        t.insert(&[b'x'; 100], 7);
+        model.insert(vec![b'x'; 100], 7u64.to_le_bytes().to_vec());
+
+        let mut buf = vec![];
+        t.write_frozen(&mut buf, LeCodec).unwrap();
+        let f = FrozenTrie::new(&buf).unwrap();
+        assert_eq!(f.len(), model.len());
+        assert!(f.iter().map(|(k, v)| (k, v.to_vec())).eq(model.clone().into_iter()));
+        for i in 0..3100u64 {
+            let key = format!("{}", i);
+            assert_eq!(f.get(key.as_bytes()), model.get(key.as_bytes()).map(|v| &v[..]));
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let mut start = format!("{}", rng.gen_range(0, 3100)).into_bytes();
+            let mut end = format!("{}", rng.gen_range(0, 3100)).into_bytes();
+            if start > end {
+                std::mem::swap(&mut start, &mut end);
+            }
+            let expected = model.range(start.clone()..end.clone()).map(|(k, v)| (k.clone(), v));
+            assert!(f.range(&start[..]..&end[..]).map(|(k, v)| (k, v.to_vec())).eq(
+                expected.map(|(k, v)| (k, v.clone()))
+            ));
+            let expected = model.range(start.clone()..=end.clone()).count();
+            assert_eq!(f.range(&start[..]..=&end[..]).count(), expected);
+            assert_eq!(f.count_range(&start[..]..=&end[..]), expected);
+            let expected = model.range(start.clone()..end.clone()).count();
+            assert_eq!(f.count_range(&start[..]..&end[..]), expected);
+            assert_eq!(f.count_range(..&start[..]), model.range(..start.clone()).count());
+
+            let prefix = &start[..rng.gen_range(0, start.len() + 1)];
+            let expected = model.keys().filter(|k| k.starts_with(prefix)).cloned();
+            assert!(f.iter_prefix(prefix).map(|(k, _)| k).eq(expected.clone()));
+            assert_eq!(f.count_prefix(prefix), expected.count());
+        }
+        assert_eq!(f.count_range::<&[u8], _>(..), model.len());
+        assert_eq!(f.count_prefix(&[0xff, 7]), 2);
+        assert_eq!(f.count_prefix(&[b'x'; 50]), 1);
+        assert_eq!(f.count_prefix(b"nope"), 0);
+        assert_eq!(f.iter_prefix(&[0xff, 7]).count(), 2);
+        assert_eq!(f.iter_prefix(&[b'x'; 50]).count(), 1);
+        assert_eq!(f.iter_prefix(b"nope").count(), 0);
+
+        let empty = Trie::<Vec<u8>>::new();
+        let mut buf = vec![];
+        empty.write_frozen(&mut buf, BytesCodec).unwrap();
+        let f = FrozenTrie::new(&buf).unwrap();
+        assert!(f.is_empty() && f.get(b"").is_none() && f.iter().next().is_none());
+        assert_eq!((f.count_prefix(b""), f.count_range::<&[u8], _>(..)), (0, 0));
+    }
+
+    #[test]
+    fn test_frozen_mmap() {
+        let mut t = Trie::new();
+        for i in 0..1000u32 {
+            t.insert(format!("word{}", i).as_bytes(), format!("{}", i).into_bytes());
+        }
+        let path = std::env::temp_dir().join(format!("baobab-frozen-{}", std::process::id()));
+        let mut out = BufWriter::new(File::create(&path).unwrap());
+        t.write_frozen(&mut out, BytesCodec).unwrap();
+        out.flush().unwrap();
+        drop(out);
+
+        let file = File::open(&path).unwrap();
+        let mmap = unsafe { memmap2::Mmap::map(&file).unwrap() };
+        let f = FrozenTrie::new(&mmap).unwrap();
+        assert_eq!(f.get(b"word123"), Some(&b"123"[..]));
+        assert_eq!(f.iter_prefix(b"word99").count(), 11);
+        drop(mmap);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_frozen_corruption() {
+        let mut t = Trie::new();
+        for i in 0..300u64 {
+            t.insert(format!("{}", i * 7).as_bytes(), i);
+        }
+        for i in 0..200u64 {
+            t.insert(&[0xff, i as u8], i);
+        }
+        // A Sparse node, whose bitset we corrupt directly below.
+        for i in 0..100u64 {
+            t.insert(&[0xfe, i as u8 * 2], i);
+        }
+        let mut buf = vec![];
+        t.write_frozen(&mut buf, LeCodec).unwrap();
+
+        assert!(matches!(FrozenTrie::new(b"BAOBTRIE"), Err(ReadError::BadMagic)));
+        assert!(matches!(FrozenTrie::new(&buf[..20]), Err(ReadError::Truncated)));
+        let mut bad = buf.clone();
+        bad[8] = 2;
+        assert!(matches!(FrozenTrie::new(&bad), Err(ReadError::UnsupportedVersion(2))));
+        for len in 0..buf.len() {
+            assert!(FrozenTrie::new(&buf[..len]).is_err());
+        }
+
+        // Corrupted buffers either fail validation or can be queried without panicking.
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..2000 {
+            let mut bad = buf.clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let i = rng.gen_range(12, buf.len());
+                bad[i] ^= 1 << rng.gen_range(0, 8);
+            }
+            if let Ok(f) = FrozenTrie::new(&bad) {
+                assert_eq!(f.iter().count(), f.len());
+                assert!(f.iter().all(|(k, v)| f.get(&k) == Some(v)));
+                let expected = f.range(&b"1"[..]..&b"5"[..]).count();
+                assert_eq!(f.count_range(&b"1"[..]..&b"5"[..]), expected);
+                assert_eq!(f.count_prefix(b"2"), f.iter_prefix(b"2").count());
+            }
+        }
+
+        // Bitsets with the wrong number of bits set don't match their node's slots.
+        let bitset = (0..32).map(|i| if i < 25 { 0x55 } else { 0 }).collect::<Vec<u8>>();
+        let start = buf.windows(32).position(|w| w == &bitset[..]).unwrap();
+        for bit in 0..256 {
+            let mut bad = buf.clone();
+            bad[start + bit / 8] ^= 1 << (bit % 8);
+            assert!(matches!(FrozenTrie::new(&bad), Err(ReadError::Corrupt(_))), "bit {}", bit);
+        }
+    }
+}
@@ -0,0 +1,341 @@
+// Fuzzy search finds the keys within an edit distance of a query by walking the trie with the
+// rows of the usual dynamic programming table: after reading some of a key's bytes, the row holds
+// the distance from those bytes to each prefix of the query.  Every node shares the rows for the
+// bytes leading up to it, so we compute them once per node rather than once per key, and since a
+// row's minimum never goes down as more bytes are read, we can skip a subtree as soon as it
+// exceeds the maximum distance, even partway through a compressed prefix.
+
+use std::str;
+
+use crate::allocator::Allocator;
+use crate::packed_node::PackedNode;
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+/// How `Trie::search_fuzzy_with` measures the distance between keys.
+#[derive(Clone, Debug, Default)]
+pub struct FuzzyOptions {
+    /// Count swapping two adjacent symbols as a single edit (the "optimal string alignment"
+    /// variant of Damerau-Levenshtein distance) rather than two.
+    pub transpositions: bool,
+    /// Measure distance in UTF-8 code points rather than bytes, so replacing "é" with "e" is one
+    /// edit instead of two.  Bytes that aren't part of a valid UTF-8 sequence are each treated
+    /// as a symbol of their own.
+    pub unicode: bool,
+}
+
+// Symbols are bytes, or code points with the `unicode` option.  Invalid UTF-8 bytes go above
+// `char::MAX`, so they only match themselves.
+type Symbol = u32;
+
+fn invalid_byte(byte: u8) -> Symbol {
+    char::MAX as Symbol + 1 + byte as Symbol
+}
+
+// Turns bytes into code points, holding onto the start of a sequence until it's complete.
+#[derive(Clone, Copy, Default)]
+struct Utf8Decoder {
+    pending: [u8; 4],
+    pending_len: usize,
+}
+
+impl Utf8Decoder {
+    fn sequence_len(byte: u8) -> usize {
+        match byte {
+            0x00..=0x7f => 1,
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8, out: &mut impl FnMut(Symbol)) {
+        let is_continuation = byte & 0xc0 == 0x80;
+        if self.pending_len > 0 && !is_continuation {
+            self.flush(out);
+        }
+        if self.pending_len == 0 && Self::sequence_len(byte) == 0 {
+            return out(invalid_byte(byte));
+        }
+        self.pending[self.pending_len] = byte;
+        self.pending_len += 1;
+        let sequence = &self.pending[..self.pending_len];
+        if sequence.len() < Self::sequence_len(sequence[0]) {
+            return;
+        }
+        match str::from_utf8(sequence) {
+            Ok(s) => out(s.chars().next().unwrap() as Symbol),
+            // Overlong encodings and surrogates only show up once the sequence is complete.
+            Err(_) => sequence.iter().for_each(|&b| out(invalid_byte(b))),
+        }
+        self.pending_len = 0;
+    }
+
+    // Give up on an incomplete sequence, treating its bytes as invalid.
+    fn flush(&mut self, out: &mut impl FnMut(Symbol)) {
+        for &byte in &self.pending[..self.pending_len] {
+            out(invalid_byte(byte));
+        }
+        self.pending_len = 0;
+    }
+}
+
+fn symbols(bytes: &[u8], unicode: bool) -> Vec<Symbol> {
+    if !unicode {
+        return bytes.iter().map(|&b| b as Symbol).collect();
+    }
+    let mut out = vec![];
+    let mut decoder = Utf8Decoder::default();
+    for &byte in bytes {
+        decoder.push(byte, &mut |s| out.push(s));
+    }
+    decoder.flush(&mut |s| out.push(s));
+    out
+}
+
+// The rows of the distance table for the symbols read so far.
+struct Rows {
+    query: Vec<Symbol>,
+    transpositions: bool,
+    // Row `i` is the distance from the first `i` symbols to each prefix of the query, stored
+    // at `table[i * (query.len() + 1)..]`.
+    table: Vec<usize>,
+    symbols: Vec<Symbol>,
+}
+
+impl Rows {
+    fn new(query: Vec<Symbol>, transpositions: bool) -> Self {
+        let table = (0..=query.len()).collect();
+        Self { query, transpositions, table, symbols: vec![] }
+    }
+
+    fn width(&self) -> usize {
+        self.query.len() + 1
+    }
+
+    fn row(&self, i: usize) -> &[usize] {
+        &self.table[(i * self.width())..((i + 1) * self.width())]
+    }
+
+    fn last(&self) -> &[usize] {
+        self.row(self.symbols.len())
+    }
+
+    fn push(&mut self, symbol: Symbol) {
+        let i = self.symbols.len();
+        let width = self.width();
+        self.table.resize((i + 2) * width, 0);
+        let (prev_rows, row) = self.table.split_at_mut((i + 1) * width);
+        let prev = &prev_rows[(i * width)..];
+        row[0] = prev[0] + 1;
+        for j in 1..width {
+            let substitute = prev[j - 1] + (self.query[j - 1] != symbol) as usize;
+            row[j] = substitute.min(prev[j] + 1).min(row[j - 1] + 1);
+            let transposed = self.transpositions
+                && i > 0
+                && j > 1
+                && symbol == self.query[j - 2]
+                && self.symbols[i - 1] == self.query[j - 1];
+            if transposed {
+                let before = &prev_rows[((i - 1) * width)..(i * width)];
+                row[j] = row[j].min(before[j - 2] + 1);
+            }
+        }
+        self.symbols.push(symbol);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.symbols.truncate(len);
+        self.table.truncate((len + 1) * self.width());
+    }
+
+    // The distance from the symbols read so far to the whole query.
+    fn distance(&self) -> usize {
+        self.last()[self.query.len()]
+    }
+
+    // No key starting with the symbols read so far can be closer than this.
+    fn min_distance(&self) -> usize {
+        self.last().iter().cloned().min().unwrap()
+    }
+}
+
+struct FuzzySearch<'a, T> {
+    rows: Rows,
+    max_distance: usize,
+    unicode: bool,
+    out: Vec<(Vec<u8>, &'a T, usize)>,
+}
+
+impl<'a, T> FuzzySearch<'a, T> {
+    fn feed(&mut self, decoder: &mut Utf8Decoder, byte: u8) {
+        if !self.unicode {
+            return self.rows.push(byte as Symbol);
+        }
+        let rows = &mut self.rows;
+        decoder.push(byte, &mut |s| rows.push(s));
+    }
+
+    // Add the matches below `node`, where `key` holds the bytes leading up to it and `decoder`
+    // any of them that don't make up a whole code point yet.
+    fn walk<A, S: Summary<T>>(
+        &mut self,
+        node: &'a PackedNode<T, A, S>,
+        key: &mut Vec<u8>,
+        decoder: Utf8Decoder,
+    ) {
+        let (key_len, rows_len) = (key.len(), self.rows.symbols.len());
+        let mut decoder = decoder;
+        for &byte in node.prefix() {
+            key.push(byte);
+            self.feed(&mut decoder, byte);
+            if self.rows.min_distance() > self.max_distance {
+                break;
+            }
+        }
+        if self.rows.min_distance() <= self.max_distance {
+            if let Some(value) = node.value() {
+                // The key can end partway through a code point.
+                let mut ended = decoder;
+                let before_end = self.rows.symbols.len();
+                let rows = &mut self.rows;
+                ended.flush(&mut |s| rows.push(s));
+                if self.rows.distance() <= self.max_distance {
+                    self.out.push((key.clone(), value, self.rows.distance()));
+                }
+                self.rows.truncate(before_end);
+            }
+            let rows_after_prefix = self.rows.symbols.len();
+            for (byte, child) in node.children() {
+                let mut child_decoder = decoder;
+                key.push(byte);
+                self.feed(&mut child_decoder, byte);
+                if self.rows.min_distance() <= self.max_distance {
+                    self.walk(child, key, child_decoder);
+                }
+                key.pop();
+                self.rows.truncate(rows_after_prefix);
+            }
+        }
+        key.truncate(key_len);
+        self.rows.truncate(rows_len);
+    }
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Find the entries whose keys are within `max_distance` byte insertions, deletions, or
+    /// substitutions of `query`, along with their distances, in key order.
+    pub fn search_fuzzy(&self, query: &[u8], max_distance: usize) -> Vec<(Vec<u8>, &T, usize)> {
+        self.search_fuzzy_with(query, max_distance, &FuzzyOptions::default())
+    }
+
+    /// Like `search_fuzzy`, but measuring distance as `options` says.  This only visits the
+    /// nodes along keys that can still end up within `max_distance` of `query`, so the smaller
+    /// it is, the less of the trie gets walked.
+    pub fn search_fuzzy_with(
+        &self,
+        query: &[u8],
+        max_distance: usize,
+        options: &FuzzyOptions,
+    ) -> Vec<(Vec<u8>, &T, usize)> {
+        let query = symbols(query, options.unicode);
+        let mut search = FuzzySearch {
+            rows: Rows::new(query, options.transpositions),
+            max_distance,
+            unicode: options.unicode,
+            out: vec![],
+        };
+        if !self.root.is_empty() {
+            search.walk(&self.root, &mut vec![], Utf8Decoder::default());
+        }
+        search.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{symbols, FuzzyOptions, Rows};
+    use crate::Trie;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn distance(a: &[u8], b: &[u8], options: &FuzzyOptions) -> usize {
+        let mut rows = Rows::new(symbols(b, options.unicode), options.transpositions);
+        for s in symbols(a, options.unicode) {
+            rows.push(s);
+        }
+        rows.distance()
+    }
+
+    #[test]
+    fn test_distance() {
+        let levenshtein = FuzzyOptions::default();
+        let damerau = FuzzyOptions { transpositions: true, ..Default::default() };
+        let unicode = FuzzyOptions { unicode: true, ..Default::default() };
+        assert_eq!(distance(b"kitten", b"sitting", &levenshtein), 3);
+        assert_eq!(distance(b"", b"abc", &levenshtein), 3);
+        assert_eq!(distance(b"abcd", b"acbd", &levenshtein), 2);
+        assert_eq!(distance(b"abcd", b"acbd", &damerau), 1);
+        // Optimal string alignment doesn't edit a transposed pair again.
+        assert_eq!(distance(b"ca", b"abc", &damerau), 3);
+        assert_eq!(distance("café".as_bytes(), b"cafe", &levenshtein), 2);
+        assert_eq!(distance("café".as_bytes(), b"cafe", &unicode), 1);
+        assert_eq!(distance("日本".as_bytes(), "日木".as_bytes(), &unicode), 1);
+        // A truncated sequence is one invalid byte per byte.
+        assert_eq!(distance(&"é".as_bytes()[..1], b"", &unicode), 1);
+        assert_eq!(distance(&[0xff, b'a'], b"a", &unicode), 1);
+    }
+
+    #[test]
+    fn test_search_fuzzy() {
+        let alphabet = ["a", "b", "c", "é", "日", "\u{ff}"];
+        let mut rng = StdRng::seed_from_u64(0);
+        let random_key = |rng: &mut StdRng| {
+            let mut key = vec![];
+            for _ in 0..rng.gen_range(0, 7) {
+                key.extend_from_slice(alphabet[rng.gen_range(0, alphabet.len())].as_bytes());
+            }
+            // Throw in some invalid and truncated UTF-8.
+            if rng.gen_range(0, 8) == 0 {
+                key.truncate(rng.gen_range(0, key.len() + 1));
+                key.push(0xc3);
+            }
+            key
+        };
+        let mut t = Trie::new();
+        for i in 0..500u32 {
+            t.insert(&random_key(&mut rng), i);
+        }
+        for i in 0..4 {
+            let options = FuzzyOptions { transpositions: i & 1 != 0, unicode: i & 2 != 0 };
+            for _ in 0..25 {
+                let query = random_key(&mut rng);
+                let max_distance = rng.gen_range(0, 4);
+                let expected = t
+                    .iter()
+                    .map(|(k, v)| {
+                        let d = distance(&k, &query, &options);
+                        (k, v, d)
+                    })
+                    .filter(|&(_, _, d)| d <= max_distance)
+                    .collect::<Vec<_>>();
+                let found = t.search_fuzzy_with(&query, max_distance, &options);
+                assert_eq!(found, expected, "{:?} {:?} {}", options, query, max_distance);
+            }
+        }
+
+        let mut t = Trie::new();
+        for word in &["apple", "apply", "ample", "maple", "applesauce"] {
+            t.insert(word.as_bytes(), ());
+        }
+        let found = t.search_fuzzy(b"appel", 2);
+        let keys = found.iter().map(|(k, _, d)| (&k[..], *d)).collect::<Vec<_>>();
+        let expected: &[(&[u8], usize)] = &[(b"apple", 2), (b"apply", 2)];
+        assert_eq!(keys, expected);
+        let damerau = FuzzyOptions { transpositions: true, ..Default::default() };
+        let found = t.search_fuzzy_with(b"appel", 1, &damerau);
+        assert_eq!(found, vec![(b"apple".to_vec(), &(), 1)]);
+        assert!(Trie::<()>::new().search_fuzzy(b"", 3).is_empty());
+    }
+}
@@ -0,0 +1,342 @@
+// Glob patterns run as automata over the trie, like regexes do, but we match them by tracking
+// the set of positions in the pattern we could be at instead of building a DFA.  Globs are
+// mostly literal text with a few wildcards, so these sets stay small, and any pattern is valid.
+// Most globs start with some literal text, so before branching out, `Trie::glob` looks up the
+// node holding the keys that start with it, the same way `iter_prefix` does.
+
+use crate::allocator::Allocator;
+use crate::automaton::{self, Automaton};
+use crate::bitset::Bitset;
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+/// How `Glob` patterns treat path separators.
+#[derive(Clone, Debug, Default)]
+pub struct GlobOptions {
+    /// Treat keys as paths split up by this byte, like `Some(b'/')`.  Then `*`, `?`, and
+    /// negated classes don't match the separator, and a `**` that makes up a whole path segment
+    /// matches any number of segments, so `a/**/b` matches `a/b` and `a/x/y/b`, and `a/**`
+    /// matches everything under `a/`.  Without a separator, `*` and `**` match any bytes.
+    pub separator: Option<u8>,
+}
+
+#[derive(Clone, Debug)]
+enum Token {
+    Byte(u8),
+    Class(Bitset),
+    // A single byte, or any number of them, other than the separator, if there is one.
+    Any(Option<u8>),
+    Star(Option<u8>),
+    // The next `n` tokens are optional.
+    Optional(usize),
+}
+
+/// A glob pattern that matches whole keys.  `?` matches any byte, `*` matches any run of
+/// bytes, and classes like `[a-z]` or `[!0-9]` match one byte from (or not from) a set, where
+/// `^` works for negation too.  A backslash matches the byte after it literally, and a `[`
+/// without a closing `]` is literal.  See `GlobOptions` for treating keys as paths.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    // The position from which the rest of the pattern is all `Star(None)`s, which match
+    // anything at all.
+    matches_any_rest: usize,
+}
+
+// Parse a class starting right after its `[`, returning the set and where it ends.  `None`
+// means it isn't closed.
+fn parse_class(pattern: &[u8], start: usize, separator: Option<u8>) -> Option<(Bitset, usize)> {
+    let mut pos = start;
+    let negated = matches!(pattern.get(pos), Some(b'!') | Some(b'^'));
+    if negated {
+        pos += 1;
+    }
+    let first = pos;
+    let mut set = Bitset::new();
+    loop {
+        let mut low = *pattern.get(pos)?;
+        // A `]` right at the start is part of the class rather than closing it.
+        if low == b']' && pos > first {
+            break;
+        }
+        if low == b'\\' {
+            pos += 1;
+            low = *pattern.get(pos)?;
+        }
+        pos += 1;
+        let mut high = low;
+        if pattern.get(pos) == Some(&b'-') && pattern.get(pos + 1).is_some_and(|&b| b != b']') {
+            pos += 1;
+            if pattern[pos] == b'\\' {
+                pos += 1;
+            }
+            high = *pattern.get(pos)?;
+            pos += 1;
+        }
+        for byte in low..=high {
+            set.set(byte);
+        }
+    }
+    if negated {
+        let mut negation = Bitset::new();
+        for byte in 0..=255 {
+            if !set.contains(byte) && Some(byte) != separator {
+                negation.set(byte);
+            }
+        }
+        set = negation;
+    }
+    Some((set, pos + 1))
+}
+
+impl Glob {
+    /// Compile `pattern` with the default options, where keys aren't treated as paths.
+    pub fn new(pattern: &[u8]) -> Self {
+        Self::with_options(pattern, &GlobOptions::default())
+    }
+
+    /// Compile `pattern`, treating separators as `options` says.
+    pub fn with_options(pattern: &[u8], options: &GlobOptions) -> Self {
+        let separator = options.separator;
+        let mut tokens = vec![];
+        let mut pos = 0;
+        while pos < pattern.len() {
+            match pattern[pos] {
+                b'?' => {
+                    tokens.push(Token::Any(separator));
+                    pos += 1;
+                }
+                b'*' => {
+                    let start = pos;
+                    while pattern.get(pos) == Some(&b'*') {
+                        pos += 1;
+                    }
+                    let segment_start = start == 0 || Some(pattern[start - 1]) == separator;
+                    if pos - start == 1 || separator.is_none() || !segment_start {
+                        tokens.push(Token::Star(separator));
+                    } else if pos == pattern.len() {
+                        tokens.push(Token::Star(None));
+                    } else if Some(pattern[pos]) == separator {
+                        // `**/` matches nothing, or anything that ends in a separator.
+                        tokens.push(Token::Optional(2));
+                        tokens.push(Token::Star(None));
+                        tokens.push(Token::Byte(pattern[pos]));
+                        pos += 1;
+                    } else {
+                        tokens.push(Token::Star(separator));
+                    }
+                }
+                b'[' => match parse_class(pattern, pos + 1, separator) {
+                    Some((set, end)) => {
+                        tokens.push(Token::Class(set));
+                        pos = end;
+                    }
+                    None => {
+                        tokens.push(Token::Byte(b'['));
+                        pos += 1;
+                    }
+                },
+                b'\\' if pos + 1 < pattern.len() => {
+                    tokens.push(Token::Byte(pattern[pos + 1]));
+                    pos += 2;
+                }
+                byte => {
+                    tokens.push(Token::Byte(byte));
+                    pos += 1;
+                }
+            }
+        }
+        let matches_any_rest = tokens.len()
+            - tokens.iter().rev().take_while(|t| matches!(t, Token::Star(None))).count();
+        Self { tokens, matches_any_rest }
+    }
+
+    // The bytes that every matching key starts with.
+    fn literal_prefix(&self) -> Vec<u8> {
+        let literal = self.tokens.iter().map_while(|token| match token {
+            Token::Byte(byte) => Some(*byte),
+            _ => None,
+        });
+        literal.collect()
+    }
+
+    // Add `pos` to `state`, along with the positions we can get to from it without reading
+    // anything.  Only positions that read a byte, or the end of the pattern, go into states.
+    fn add(&self, pos: usize, state: &mut Vec<usize>) {
+        let mut stack = vec![pos];
+        while let Some(pos) = stack.pop() {
+            match self.tokens.get(pos) {
+                Some(Token::Optional(n)) => {
+                    stack.push(pos + 1 + n);
+                    stack.push(pos + 1);
+                }
+                Some(Token::Star(_)) => {
+                    state.push(pos);
+                    stack.push(pos + 1);
+                }
+                _ => state.push(pos),
+            }
+        }
+    }
+}
+
+impl Automaton for Glob {
+    // The positions in the pattern we could be at, in order.
+    type State = Vec<usize>;
+
+    fn start(&self) -> Vec<usize> {
+        let mut state = vec![];
+        self.add(0, &mut state);
+        state.sort_unstable();
+        state.dedup();
+        state
+    }
+
+    fn accept(&self, state: &Vec<usize>, byte: u8) -> Vec<usize> {
+        let mut next = vec![];
+        for &pos in state {
+            match self.tokens.get(pos) {
+                Some(&Token::Byte(b)) if b == byte => self.add(pos + 1, &mut next),
+                Some(Token::Class(set)) if set.contains(byte) => self.add(pos + 1, &mut next),
+                Some(&Token::Any(except)) if except != Some(byte) => self.add(pos + 1, &mut next),
+                Some(&Token::Star(except)) if except != Some(byte) => self.add(pos, &mut next),
+                _ => (),
+            }
+        }
+        next.sort_unstable();
+        next.dedup();
+        next
+    }
+
+    fn is_match(&self, state: &Vec<usize>) -> bool {
+        state.last() == Some(&self.tokens.len())
+    }
+
+    fn can_match(&self, state: &Vec<usize>) -> bool {
+        !state.is_empty()
+    }
+
+    fn will_always_match(&self, state: &Vec<usize>) -> bool {
+        state.iter().any(|&pos| pos >= self.matches_any_rest && pos < self.tokens.len())
+    }
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Find the entries whose keys match the glob `pattern`, in key order.  See `Glob` for the
+    /// syntax.
+    pub fn glob(&self, pattern: &[u8]) -> Vec<(Vec<u8>, &T)> {
+        self.glob_with(pattern, &GlobOptions::default())
+    }
+
+    /// Like `glob`, but matching as `options` says.  This goes straight to the keys starting
+    /// with the pattern's literal prefix, and then only visits the nodes along keys that the
+    /// rest of the pattern can still match.
+    pub fn glob_with(&self, pattern: &[u8], options: &GlobOptions) -> Vec<(Vec<u8>, &T)> {
+        let glob = Glob::with_options(pattern, options);
+        let literal = glob.literal_prefix();
+        let mut out = vec![];
+        if let Some((node, depth)) = self.root.find_prefix(&literal) {
+            let mut key = literal[..depth].to_vec();
+            let state = key.iter().fold(glob.start(), |state, &b| glob.accept(&state, b));
+            automaton::search_node(node, &glob, state, &mut key, &mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Glob, GlobOptions};
+    use crate::automaton::Automaton;
+    use crate::Trie;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn is_match(pattern: &str, options: &GlobOptions, key: &str) -> bool {
+        let glob = Glob::with_options(pattern.as_bytes(), options);
+        let state = key.bytes().fold(glob.start(), |s, b| glob.accept(&s, b));
+        glob.is_match(&state)
+    }
+
+    #[test]
+    fn test_glob_syntax() {
+        let plain = GlobOptions::default();
+        let paths = GlobOptions { separator: Some(b'/') };
+        let cases: &[(&str, &GlobOptions, &[&str], &[&str])] = &[
+            ("", &plain, &[""], &["a"]),
+            ("a?c", &plain, &["abc", "a/c"], &["ac", "abbc"]),
+            ("a?c", &paths, &["abc"], &["a/c"]),
+            ("*.rs", &plain, &[".rs", "lib.rs", "src/lib.rs"], &["lib.rst"]),
+            ("*.rs", &paths, &["lib.rs"], &["src/lib.rs"]),
+            ("a*b*c", &plain, &["abc", "aXbYbc", "abbc"], &["acb"]),
+            ("[a-c]x[!0-9]", &plain, &["bxy", "cx/"], &["dxy", "ax1"]),
+            ("[!0-9]", &paths, &["a"], &["/", "5"]),
+            ("[^a]", &plain, &["b"], &["a"]),
+            ("[]-]", &plain, &["]", "-"], &["a"]),
+            (r"[\]a]\*", &plain, &["]*", "a*"], &["]x"]),
+            ("a[b", &plain, &["a[b"], &["ab"]),
+            ("a**b", &plain, &["ab", "a/x/b"], &["ba"]),
+            ("a**b", &paths, &["axb"], &["a/b"]),
+            ("a/**/b", &paths, &["a/b", "a/x/b", "a/x/y/b"], &["a/xb", "ab", "a/b/c"]),
+            ("**/b", &paths, &["b", "x/b", "x/y/b"], &["xb"]),
+            ("a/**", &paths, &["a/", "a/x", "a/x/y"], &["a", "b/x"]),
+            ("**", &paths, &["", "a", "a/b/c"], &[]),
+            ("x/**y", &paths, &["x/y", "x/aay"], &["x/a/y"]),
+        ];
+        for (pattern, options, matching, other) in cases {
+            for key in matching.iter() {
+                assert!(is_match(pattern, options, key), "{} should match {:?}", pattern, key);
+            }
+            for key in other.iter() {
+                assert!(!is_match(pattern, options, key), "{} matched {:?}", pattern, key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        let mut t = Trie::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        for i in 0..1000 {
+            let day = format!("2024-{:02}-{:02}", rng.gen_range(1, 13), rng.gen_range(1, 29));
+            let name = ["error", "error.1", "access", "debug/error"][rng.gen_range(0, 4)];
+            t.insert(format!("logs/{}/{}", day, name).as_bytes(), i);
+            let len = rng.gen_range(0, 6);
+            let key = (0..len).map(|_| rng.gen_range(b'a', b'e')).collect::<Vec<_>>();
+            t.insert(&key, i);
+        }
+
+        let paths = GlobOptions { separator: Some(b'/') };
+        let patterns: &[&[u8]] = &[
+            b"logs/2024-??-*/error*",
+            b"logs/2024-1?-0[1-5]/*",
+            b"logs/**/error",
+            b"**/debug/*",
+            b"logs/2024-0[!1-8]-**",
+            b"*a*b",
+            b"[ab]?c*",
+            b"",
+            b"logs/",
+            b"*",
+        ];
+        for &pattern in patterns {
+            for options in &[GlobOptions::default(), paths.clone()] {
+                let glob = Glob::with_options(pattern, options);
+                let state = |key: &[u8]| key.iter().fold(glob.start(), |s, &b| glob.accept(&s, b));
+                let expected = t.iter().filter(|(k, _)| glob.is_match(&state(k)));
+                let expected = expected.collect::<Vec<_>>();
+                assert_eq!(t.glob_with(pattern, options), expected, "{:?}", pattern);
+                assert_eq!(t.search(&glob), expected);
+            }
+        }
+
+        let found = t.glob_with(b"logs/2024-??-*/error*", &paths);
+        assert!(!found.is_empty());
+        for (key, _) in found {
+            let key = String::from_utf8(key).unwrap();
+            assert!(key.ends_with("/error") || key.ends_with("/error.1"), "{}", key);
+        }
+        assert_eq!(t.glob(b"*").len(), t.len());
+        assert!(Trie::<u32>::new().glob(b"*").is_empty());
+    }
+}
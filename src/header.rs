@@ -4,13 +4,28 @@ use std::fmt;
 use std::mem;
 use std::marker::PhantomData;
 use std::ops::Range;
+use std::ptr;
 
+use crate::bitset::Bitset;
 use crate::packable::Header;
-use crate::packed_node::PackedNode;
+use crate::packed_node::{SLOT_ALIGN, SLOT_SIZE};
 
-pub const MAX_PREFIX_LEN: usize = 64 - 1;
+// Prefix lengths up to `SHORT_PREFIX_LEN` fit in the low six bits of the header's prefix byte.
+// Longer ones set those bits to `LONG_PREFIX` and store their length in the `EXT_LEN_SIZE` bytes
+// right after the header, so nodes with short prefixes don't pay for them.  Nodes that skip some
+// of their prefix bytes (see `OptimisticTrie`) use `SKIPPED_PREFIX` and store both the stored
+// prefix length and the skipped length there.
+const SHORT_PREFIX_LEN: usize = 61;
+const LONG_PREFIX: u8 = 62;
+const SKIPPED_PREFIX: u8 = 63;
+const PREFIX_LEN_MASK: u8 = (1 << 6) - 1;
+const EXT_LEN_SIZE: usize = mem::size_of::<u32>();
+const HEADER_SIZE: usize = 2;
+pub const COUNT_SIZE: usize = mem::size_of::<usize>();
 
-#[derive(Debug, Eq, PartialEq)]
+pub const MAX_PREFIX_LEN: usize = u32::MAX as usize;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum NodeChildrenType {
     Empty,
     Pairs,
@@ -19,7 +34,7 @@ pub enum NodeChildrenType {
 }
 
 impl NodeChildrenType {
-    fn from_count(n: usize) -> Self {
+    pub fn from_count(n: usize) -> Self {
         use NodeChildrenType::*;
         match n {
             0 => Empty,
@@ -31,37 +46,48 @@ impl NodeChildrenType {
     }
 }
 
-pub struct NodeHeader<T> {
+pub struct NodeHeader<T, S = ()> {
     prefix_byte: u8,
     children_byte: u8,
-    marker: PhantomData<T>,
+    // Only stored in the node when `prefix_byte` has the `LONG_PREFIX` or `SKIPPED_PREFIX` escape.
+    long_prefix_len: u32,
+    skipped_len: u32,
+    marker: PhantomData<(T, S)>,
 }
 
-impl<T> Clone for NodeHeader<T> {
+impl<T, S> Clone for NodeHeader<T, S> {
     fn clone(&self) -> Self {
         Self {
             prefix_byte: self.prefix_byte,
             children_byte: self.children_byte,
+            long_prefix_len: self.long_prefix_len,
+            skipped_len: self.skipped_len,
             marker: PhantomData,
         }
     }
 }
 
-impl<T> Copy for NodeHeader<T> {}
+impl<T, S> Copy for NodeHeader<T, S> {}
 
-impl<T> fmt::Debug for NodeHeader<T> {
+impl<T, S> fmt::Debug for NodeHeader<T, S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("NodeHeader")
             .field("prefix_byte", &self.prefix_byte)
             .field("children_byte", &self.children_byte)
+            .field("long_prefix_len", &self.long_prefix_len)
+            .field("skipped_len", &self.skipped_len)
             .finish()
     }
 }
 
-impl<T> NodeHeader<T> {
+impl<T, S> NodeHeader<T, S> {
     pub fn new(prefix_len: usize, num_children: usize, has_value: bool) -> Self {
-        assert!(prefix_len < MAX_PREFIX_LEN);
-        let mut prefix_byte = prefix_len as u8;
+        assert!(prefix_len <= MAX_PREFIX_LEN);
+        let (mut prefix_byte, long_prefix_len) = if prefix_len <= SHORT_PREFIX_LEN {
+            (prefix_len as u8, 0)
+        } else {
+            (LONG_PREFIX, prefix_len as u32)
+        };
         let children_byte;
         if num_children == 256 {
             prefix_byte |= 1 << 6;
@@ -72,12 +98,64 @@ impl<T> NodeHeader<T> {
         if has_value {
             prefix_byte |= 1 << 7;
         }
-        Self { prefix_byte, children_byte, marker: PhantomData }
+        Self { prefix_byte, children_byte, long_prefix_len, skipped_len: 0, marker: PhantomData }
+    }
+
+    // Mark the node as skipping `skipped_len` key bytes after its stored prefix.
+    pub fn with_skipped_len(mut self, skipped_len: usize) -> Self {
+        if skipped_len == 0 {
+            return self;
+        }
+        assert!(skipped_len <= MAX_PREFIX_LEN);
+        self.long_prefix_len = self.prefix_len() as u32;
+        self.skipped_len = skipped_len as u32;
+        self.prefix_byte = (self.prefix_byte & !PREFIX_LEN_MASK) | SKIPPED_PREFIX;
+        self
+    }
+
+    // Read a header from the start of `buf`, or return `None` if `buf` is too short to hold it.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+        let header = Self::new(0, 0, false);
+        let short = Self { prefix_byte: buf[0], children_byte: buf[1], ..header };
+        if buf.len() < short.header_range().len() {
+            return None;
+        }
+        Some(unsafe { <Self as Header>::read(buf.as_ptr()) })
+    }
+
+    fn prefix_tag(self) -> u8 {
+        self.prefix_byte & PREFIX_LEN_MASK
+    }
+
+    // Write the header to the start of a node's buffer, which must be at least
+    // `header_range().len()` long.
+    pub fn write(self, buf: &mut [u8]) {
+        buf[0] = self.prefix_byte;
+        buf[1] = self.children_byte;
+        let (ext_lens, num_ext_lens) = match self.prefix_tag() {
+            LONG_PREFIX => ([self.long_prefix_len, 0], 1),
+            SKIPPED_PREFIX => ([self.long_prefix_len, self.skipped_len], 2),
+            _ => ([0, 0], 0),
+        };
+        for (i, len) in ext_lens[..num_ext_lens].iter().enumerate() {
+            let start = HEADER_SIZE + i * EXT_LEN_SIZE;
+            buf[start..(start + EXT_LEN_SIZE)].copy_from_slice(&len.to_le_bytes());
+        }
     }
 
     pub fn prefix_len(self) -> usize {
-        let mask = (1 << 6) - 1;
-        (self.prefix_byte & mask) as usize
+        match self.prefix_tag() {
+            LONG_PREFIX | SKIPPED_PREFIX => self.long_prefix_len as usize,
+            len => len as usize,
+        }
+    }
+
+    // Number of key bytes following the stored prefix that the node doesn't store.
+    pub fn skipped_len(self) -> usize {
+        self.skipped_len as usize
     }
 
     pub fn num_children(self) -> usize {
@@ -88,12 +166,16 @@ impl<T> NodeHeader<T> {
         }
     }
 
-    fn has_value(self) -> bool {
+    pub fn has_value(self) -> bool {
         self.prefix_byte & (1 << 7) != 0
     }
 
     pub fn header_range(self) -> Range<usize> {
-        0..mem::size_of::<Self>()
+        match self.prefix_tag() {
+            LONG_PREFIX => 0..(HEADER_SIZE + EXT_LEN_SIZE),
+            SKIPPED_PREFIX => 0..(HEADER_SIZE + 2 * EXT_LEN_SIZE),
+            _ => 0..HEADER_SIZE,
+        }
     }
 
     pub fn prefix_range(self) -> Range<usize> {
@@ -107,32 +189,75 @@ impl<T> NodeHeader<T> {
         NodeChildrenType::from_count(self.num_children())
     }
 
-    fn children_len(self) -> usize {
-        let (overhead, pointers) = match self.children_type() {
-            NodeChildrenType::Empty => (0, 0),
-            NodeChildrenType::Pairs => (self.num_children(), self.num_children()),
-            NodeChildrenType::Sparse => (32, self.num_children()),
-            NodeChildrenType::Dense => (0, 256),
-        };
-        overhead + mem::size_of::<PackedNode<T>>() * pointers
+    // Pairs nodes store their keys and Sparse nodes their bitset ahead of the child slots.
+    pub fn index_len(self) -> usize {
+        match self.children_type() {
+            NodeChildrenType::Empty | NodeChildrenType::Dense => 0,
+            NodeChildrenType::Pairs => self.num_children(),
+            NodeChildrenType::Sparse => mem::size_of::<Bitset>(),
+        }
+    }
+
+    pub fn num_slots(self) -> usize {
+        match self.children_type() {
+            NodeChildrenType::Empty => 0,
+            NodeChildrenType::Pairs | NodeChildrenType::Sparse => self.num_children(),
+            NodeChildrenType::Dense => 256,
+        }
     }
 
     pub fn children_range(self) -> Range<usize> {
         let Range {
             end: prefix_end, ..
         } = self.prefix_range();
-        prefix_end..(prefix_end + self.children_len())
+        prefix_end..self.slots_range().end
     }
 
-    pub fn value_range(self) -> Option<Range<usize>> {
-        if !self.has_value() {
-            return None;
+    // Child slots are aligned so that inline leaves can hand out references to their values.
+    pub fn slots_range(self) -> Range<usize> {
+        let Range {
+            end: prefix_end, ..
+        } = self.prefix_range();
+        let index_end = prefix_end + self.index_len();
+        if self.num_slots() == 0 {
+            return index_end..index_end;
         }
+        let slots_start = (index_end + SLOT_ALIGN - 1) / SLOT_ALIGN * SLOT_ALIGN;
+        slots_start..(slots_start + SLOT_SIZE * self.num_slots())
+    }
+
+    // Nodes with children store the number of values in their subtree right after the child
+    // slots, which keeps it aligned.  Leaves don't need to: they hold exactly their own value.
+    pub fn count_range(self) -> Range<usize> {
         let Range {
             end: children_end, ..
         } = self.children_range();
+        if self.num_children() == 0 {
+            return children_end..children_end;
+        }
+        children_end..(children_end + COUNT_SIZE)
+    }
+
+    // Tries with a `Summary` also store their subtree's summary in nodes with children, after
+    // the count, as an `Option<S>` that's `None` until it's been computed.  It takes no space
+    // for the default `()` summary.
+    pub fn summary_range(self) -> Range<usize> {
+        let Range { end: count_end, .. } = self.count_range();
+        if self.num_children() == 0 || mem::size_of::<S>() == 0 {
+            return count_end..count_end;
+        }
+        let align = mem::align_of::<Option<S>>();
+        let summary_start = (count_end + align - 1) / align * align;
+        summary_start..(summary_start + mem::size_of::<Option<S>>())
+    }
+
+    pub fn value_range(self) -> Option<Range<usize>> {
+        if !self.has_value() {
+            return None;
+        }
+        let Range { end: summary_end, .. } = self.summary_range();
         let align = mem::align_of::<T>();
-        let value_start = (children_end + align - 1) / align * align;
+        let value_start = (summary_end + align - 1) / align * align;
         Some(value_start..(value_start + mem::size_of::<T>()))
     }
 
@@ -140,14 +265,41 @@ impl<T> NodeHeader<T> {
         if let Some(value_range) = self.value_range() {
             value_range.end
         } else {
-            self.children_range().end
+            self.summary_range().end
         }
     }
 }
 
-impl<T> Header for NodeHeader<T> {
+impl<T, S> Header for NodeHeader<T, S> {
+    unsafe fn read(ptr: *const u8) -> Self {
+        let mut header = Self {
+            prefix_byte: *ptr,
+            children_byte: *ptr.add(1),
+            long_prefix_len: 0,
+            skipped_len: 0,
+            marker: PhantomData,
+        };
+        let read_len = |i| {
+            let mut len_bytes = [0; EXT_LEN_SIZE];
+            let len_ptr = ptr.add(HEADER_SIZE + i * EXT_LEN_SIZE);
+            ptr::copy_nonoverlapping(len_ptr, len_bytes.as_mut_ptr(), EXT_LEN_SIZE);
+            u32::from_le_bytes(len_bytes)
+        };
+        match header.prefix_tag() {
+            LONG_PREFIX => header.long_prefix_len = read_len(0),
+            SKIPPED_PREFIX => {
+                header.long_prefix_len = read_len(0);
+                header.skipped_len = read_len(1);
+            }
+            _ => (),
+        }
+        header
+    }
+
     fn layout(&self) -> Layout {
-        let align = cmp::max(mem::align_of::<Self>(), mem::align_of::<T>());
+        // Aligning for child slots also keeps the low bit of node pointers free for tagging.
+        let value_align = cmp::max(mem::align_of::<T>(), mem::align_of::<Option<S>>());
+        let align = cmp::max(SLOT_ALIGN, value_align);
         Layout::from_size_align(self.alloc_size(), align)
             .unwrap_or_else(|_| panic!("Invalid layout for {:?}", self))
     }
@@ -155,6 +307,14 @@ impl<T> Header for NodeHeader<T> {
 
 #[test]
 fn test_sizes() {
-    assert_eq!(mem::size_of::<NodeHeader<()>>(), 2);
-    assert_eq!(mem::align_of::<NodeHeader<()>>(), 1);
+    use crate::allocator::Global;
+    use crate::packed_node::PackedNode;
+
+    assert_eq!(NodeHeader::<()>::new(SHORT_PREFIX_LEN, 0, false).header_range().len(), 2);
+    assert_eq!(NodeHeader::<()>::new(SHORT_PREFIX_LEN + 1, 0, false).header_range().len(), 6);
+    let skipped = NodeHeader::<()>::new(3, 0, true).with_skipped_len(100);
+    assert_eq!(skipped.header_range().len(), 10);
+    assert_eq!((skipped.prefix_len(), skipped.skipped_len()), (3, 100));
+    assert_eq!(mem::size_of::<PackedNode<(), Global>>(), SLOT_SIZE);
+    assert_eq!(mem::align_of::<PackedNode<(), Global>>(), SLOT_ALIGN);
 }
@@ -1,7 +1,10 @@
+use crate::allocator::Allocator;
 use crate::node::{Node, NodeChildren};
 use crate::packed_node::PackedNode;
+use crate::prefix;
+use crate::summary::Summary;
 
-impl<T> PackedNode<T> {
+impl<T, A: Allocator, S: Summary<T>> PackedNode<T, A, S> {
     // The original tree...
     // ```
     //         o      prefix: abc
@@ -22,23 +25,25 @@ impl<T> PackedNode<T> {
     //         *      value: old_value
     //       / | \    children: old_children
     // ```
-    fn split_prefix(&mut self, split_at: usize, new_value: T) {
+    pub fn split_prefix(&mut self, split_at: usize, new_value: T, alloc: &A) {
         let Node {
             prefix,
             children: old_children,
             value: old_value,
-        } = self.take();
+            ..
+        } = self.take(alloc);
 
         let (parent_prefix, suffix) = prefix.split_at(split_at);
         let (&branch, child_prefix) = suffix.split_first().unwrap();
 
-        let new_child = Node::new(child_prefix.to_owned(), old_children, old_value);
+        let new_child = Node::new(child_prefix.to_owned(), old_children, old_value, alloc);
         let new_parent = Node::new(
             parent_prefix.to_owned(),
-            NodeChildren::one(branch, PackedNode::new(new_child)),
+            NodeChildren::one(branch, PackedNode::new(new_child, alloc)),
             Some(new_value),
+            alloc,
         );
-        *self = PackedNode::new(new_parent);
+        *self = PackedNode::new(new_parent, alloc);
     }
 
     // The original tree...
@@ -62,18 +67,20 @@ impl<T> PackedNode<T> {
     //      / | \   | g
     //              *
     // ```
-    fn branch_prefix(
+    pub fn branch_prefix(
         &mut self,
         split_at: usize,
         key_branch: u8,
         key_remainder: &[u8],
         new_value: T,
+        alloc: &A,
     ) {
         let Node {
             prefix,
             children: old_children,
             value: old_value,
-        } = self.take();
+            ..
+        } = self.take(alloc);
 
         let (parent_prefix, suffix) = prefix.split_at(split_at);
 
@@ -85,62 +92,79 @@ impl<T> PackedNode<T> {
             first_prefix.to_owned(),
             old_children,
             old_value,
+            alloc,
         );
         let second_child = Node::new(
             second_prefix.to_owned(),
             NodeChildren::Empty,
             Some(new_value),
+            alloc,
         );
         let new_parent = Node::new(
             parent_prefix.to_owned(),
             NodeChildren::two(
                 first_branch,
-                PackedNode::new(first_child),
+                PackedNode::new(first_child, alloc),
                 second_branch,
-                PackedNode::new(second_child),
+                PackedNode::new(second_child, alloc),
             ),
             None,
+            alloc,
         );
-        *self = PackedNode::new(new_parent);
+        *self = PackedNode::new(new_parent, alloc);
     }
 
-    pub fn insert(&mut self, key: &[u8], value: T) -> Option<T> {
+    pub fn insert(&mut self, key: &[u8], value: T, alloc: &A) -> Option<T> {
         // TODO: Why is it easy to write this recursively but hard to get the
         // borrow checker to accept the iterative loop version?
         // See https://users.rust-lang.org/t/how-do-you-remove-the-last-node-from-a-singly-linked-list/31805
         // The straightforward switch to a loop works with `-Z polonius`.
-        let mut key_iter = key.iter();
-        for (i, &byte) in self.prefix().iter().enumerate() {
-            match key_iter.next() {
+        // Empty nodes (the root of an empty trie, or a free slot in a Dense table) take the whole
+        // key as their prefix rather than branching on its first byte.
+        if self.is_empty() {
+            let new_node = Node::new(key.to_owned(), NodeChildren::Empty, Some(value), alloc);
+            *self = PackedNode::new(new_node, alloc);
+            return None;
+        }
+        let prefix_len = self.prefix().len();
+        if let Some(i) = prefix::mismatch(self.prefix(), key) {
+            match key.get(i) {
                 // Split current node into a branching node with two children.
-                Some(&key_byte) if key_byte != byte => {
-                    self.branch_prefix(i, key_byte, key_iter.as_slice(), value);
-                    return None;
-                }
+                Some(&key_byte) => self.branch_prefix(i, key_byte, &key[(i + 1)..], value, alloc),
                 // Split current node into a branching node with one child.
-                None => {
-                    self.split_prefix(i, value);
-                    return None;
-                }
-                Some(..) => continue,
+                None => self.split_prefix(i, value, alloc),
             }
+            return None;
         }
+        let mut key_iter = key[prefix_len..].iter();
         let branch_byte = match key_iter.next() {
             // Set value on current node.
-            None => return self.set_value(Some(value)),
+            None => return self.set_value(Some(value), alloc),
             Some(&k) => k,
         };
+        self.clear_summary();
         match self.lookup_mut(branch_byte) {
             None => {
                 let new_child = Node::new(
                     key_iter.as_slice().to_owned(),
                     NodeChildren::Empty,
                     Some(value),
+                    alloc,
                 );
-                self.add_child(branch_byte, new_child);
+                self.add_child(branch_byte, new_child, alloc);
                 None
             }
-            Some(next_node) => next_node.insert(key_iter.as_slice(), value),
+            Some(next_node) => {
+                let filled_slot = next_node.is_empty();
+                let old_value = next_node.insert(key_iter.as_slice(), value, alloc);
+                if filled_slot {
+                    self.increment_dense_children();
+                }
+                if old_value.is_none() {
+                    self.set_len(self.len() + 1);
+                }
+                old_value
+            }
         }
     }
 }
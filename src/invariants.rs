@@ -0,0 +1,240 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::allocator::Allocator;
+use crate::header::{NodeChildrenType, MAX_PREFIX_LEN};
+use crate::packed_node::PackedNode;
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+/// Which structural invariant a node broke.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ViolationKind {
+    /// An allocated node has neither children nor a value.
+    EmptyNode,
+    /// A node without a value has a single child, so it should have been merged into it.  Nodes
+    /// whose merged prefix would be longer than `MAX_PREFIX_LEN` are exempt, since that's how
+    /// long prefixes get split up.
+    UnmergedNode,
+    /// A node's compressed prefix is longer than `MAX_PREFIX_LEN`.
+    PrefixTooLong { len: usize },
+    /// The header's child count doesn't match the number of children in the node's layout.
+    ChildCountMismatch { header: usize, actual: usize },
+    /// A Pairs node's keys aren't strictly increasing.
+    UnsortedPairs { keys: Vec<u8> },
+    /// A Dense node has fewer live slots than the smallest Dense layout.
+    UnderfullDense { live: usize },
+    /// A Pairs or Sparse node has an empty slot for one of its branch bytes.
+    EmptyChild { byte: u8 },
+    /// The number of values a node stores for its subtree doesn't match its value and its
+    /// children's counts.
+    CountMismatch { stored: usize, actual: usize },
+}
+
+/// A broken invariant found by `Trie::check_invariants`, along with the key bytes leading up to
+/// the offending node (not including its own prefix).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvariantViolation {
+    pub path: Vec<u8>,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "node at {:?}: ", self.path)?;
+        match &self.kind {
+            ViolationKind::EmptyNode => write!(f, "allocated node without children or a value"),
+            ViolationKind::UnmergedNode => write!(f, "valueless node with a single child"),
+            ViolationKind::PrefixTooLong { len } => {
+                write!(f, "prefix of length {} exceeds {}", len, MAX_PREFIX_LEN)
+            }
+            ViolationKind::ChildCountMismatch { header, actual } => {
+                write!(f, "header has {} children, but layout has {}", header, actual)
+            }
+            ViolationKind::UnsortedPairs { keys } => write!(f, "unsorted pairs keys {:?}", keys),
+            ViolationKind::UnderfullDense { live } => {
+                write!(f, "dense node with only {} live slots", live)
+            }
+            ViolationKind::EmptyChild { byte } => write!(f, "empty child for byte {}", byte),
+            ViolationKind::CountMismatch { stored, actual } => {
+                write!(f, "node counts {} values, but its subtree has {}", stored, actual)
+            }
+        }
+    }
+}
+
+impl Error for InvariantViolation {}
+
+pub(crate) fn check_node<T, A, S: Summary<T>>(
+    node: &PackedNode<T, A, S>,
+    path: &mut Vec<u8>,
+) -> Result<(), InvariantViolation> {
+    let violation = |kind| Err(InvariantViolation { path: path.clone(), kind });
+    if node.is_empty() {
+        return Ok(());
+    }
+    let prefix = node.prefix();
+    if prefix.len() > MAX_PREFIX_LEN {
+        return violation(ViolationKind::PrefixTooLong { len: prefix.len() });
+    }
+    // Inline leaves always hold a value and never have children.
+    let header = match node.header() {
+        Some(header) => header,
+        None => return Ok(()),
+    };
+
+    // Walking the children of a node whose index disagrees with its header would read past
+    // its slots, so check that first.
+    let actual = node.indexed_children();
+    if actual != header.num_children() {
+        return violation(ViolationKind::ChildCountMismatch {
+            header: header.num_children(),
+            actual,
+        });
+    }
+    let mut keys = vec![];
+    for (byte, child) in node.children() {
+        if child.is_empty() {
+            return violation(ViolationKind::EmptyChild { byte });
+        }
+        keys.push(byte);
+    }
+    match header.children_type() {
+        NodeChildrenType::Pairs if keys.windows(2).any(|w| w[0] >= w[1]) => {
+            return violation(ViolationKind::UnsortedPairs { keys });
+        }
+        NodeChildrenType::Dense if actual <= 192 => {
+            return violation(ViolationKind::UnderfullDense { live: actual });
+        }
+        _ => (),
+    }
+    match (header.has_value(), actual) {
+        (false, 0) => return violation(ViolationKind::EmptyNode),
+        (false, 1) => {
+            let (_, child) = node.children().next().unwrap();
+            if prefix.len() + 1 + child.prefix().len() <= MAX_PREFIX_LEN {
+                return violation(ViolationKind::UnmergedNode);
+            }
+        }
+        _ => (),
+    }
+
+    let path_len = path.len();
+    for (byte, child) in node.children() {
+        path.extend_from_slice(prefix);
+        path.push(byte);
+        check_node(child, path)?;
+        path.truncate(path_len);
+    }
+    // Check our count once we know our children's are right.
+    let count = header.has_value() as usize + node.children().map(|(_, c)| c.len()).sum::<usize>();
+    if node.len() != count {
+        let kind = ViolationKind::CountMismatch { stored: node.len(), actual: count };
+        return Err(InvariantViolation { path: path.clone(), kind });
+    }
+    Ok(())
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Walk the whole trie and verify its structural invariants, returning the first violation
+    /// found.  This is meant for tests and debugging: it visits every node.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        check_node(&self.root, &mut vec![])
+    }
+
+    #[inline]
+    pub(crate) fn debug_check_invariants(&self) {
+        debug_check(|| self.check_invariants());
+    }
+}
+
+// With the `check-invariants` feature, debug builds check the whole trie after every mutation,
+// by calling `check` and panicking on any violation.  Without it, `check` is never called.
+#[inline]
+pub(crate) fn debug_check(check: impl FnOnce() -> Result<(), InvariantViolation>) {
+    #[cfg(all(feature = "check-invariants", debug_assertions))]
+    {
+        if let Err(e) = check() {
+            panic!("Broken invariant after mutation: {}", e);
+        }
+    }
+    #[cfg(not(all(feature = "check-invariants", debug_assertions)))]
+    drop(check);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InvariantViolation, ViolationKind};
+    use crate::node::{Node, NodeChildren};
+    use crate::packed_node::PackedNode;
+    use crate::{Global, Trie};
+
+    #[test]
+    fn test_check_invariants() {
+        let mut t = Trie::new();
+        assert_eq!(t.check_invariants(), Ok(()));
+        for i in 0..5000u32 {
+            t.insert(format!("{}", i * 37 % 5003).as_bytes(), i);
+            if i % 500 == 0 {
+                assert_eq!(t.check_invariants(), Ok(()));
+            }
+        }
+        for i in 0..300u32 {
+            t.insert(&[0xff, (i % 256) as u8, (i / 256) as u8], i);
+        }
+        assert_eq!(t.check_invariants(), Ok(()));
+        for i in (0..5000u32).step_by(3) {
+            t.remove(format!("{}", i * 37 % 5003).as_bytes());
+        }
+        assert_eq!(t.check_invariants(), Ok(()));
+
+        // Build a valueless node with a single child by hand.
+        let mut t = Trie::<u64>::new();
+        let leaf = Node::new(b"def".to_vec(), NodeChildren::Empty, Some(1), &Global);
+        let children = NodeChildren::one(b'x', PackedNode::new(leaf, &Global));
+        let parent = Node::new(b"abc".to_vec(), children, None, &Global);
+        t.root = PackedNode::new(parent, &Global);
+        assert_eq!(
+            t.check_invariants(),
+            Err(InvariantViolation { path: vec![], kind: ViolationKind::UnmergedNode }),
+        );
+
+        // And an allocated leaf without a value below a valid node.
+        let mut t = Trie::<u64>::new();
+        t.insert(b"ab", 1);
+        t.insert(b"abcd", 2);
+        let child = t.root.lookup_mut(b'c').unwrap();
+        child.drop_in(&Global);
+        *child = PackedNode::new(Node::new(vec![], NodeChildren::Empty, None, &Global), &Global);
+        let err = t.check_invariants().unwrap_err();
+        assert_eq!(err.path, b"abc");
+        assert_eq!(err.kind, ViolationKind::EmptyNode);
+
+        // And a node that miscounts its values.
+        let mut t = Trie::<u64>::new();
+        t.insert(b"ab", 1);
+        t.insert(b"abcd", 2);
+        t.root.set_len(3);
+        let err = t.check_invariants().unwrap_err();
+        assert_eq!(err.kind, ViolationKind::CountMismatch { stored: 3, actual: 2 });
+
+        // And Sparse nodes whose bitsets disagree with their headers.
+        for bit in [0, 1, 40, 255] {
+            let mut t = Trie::<u64>::new();
+            for i in 0..40u8 {
+                t.insert(&[b'a', i * 2], i as u64);
+            }
+            t.root.index_bytes_mut()[bit / 8] ^= 1 << (bit % 8);
+            let actual = if bit % 2 == 0 { 39 } else { 41 };
+            assert_eq!(
+                t.check_invariants(),
+                Err(InvariantViolation {
+                    path: vec![],
+                    kind: ViolationKind::ChildCountMismatch { header: 40, actual },
+                }),
+            );
+            // Put it back so the trie can be dropped.
+            t.root.index_bytes_mut()[bit / 8] ^= 1 << (bit % 8);
+        }
+    }
+}
@@ -1,4 +1,6 @@
+use crate::allocator::Allocator;
 use crate::packed_node::PackedNode;
+use crate::summary::Summary;
 use crate::trie::Trie;
 
 #[derive(Clone, Copy)]
@@ -8,12 +10,21 @@ enum State {
     PopByte(Option<u8>),
 }
 
-struct TreeIterator<'a, T> {
+pub(crate) struct TreeIterator<'a, T, A, S: Summary<T> = ()> {
     key: Vec<u8>,
-    stack: Vec<(&'a PackedNode<T>, State)>,
+    stack: Vec<(&'a PackedNode<T, A, S>, State)>,
 }
 
-impl<'a, T> Iterator for TreeIterator<'a, T> {
+impl<'a, T, A, S: Summary<T>> TreeIterator<'a, T, A, S> {
+    pub(crate) fn new(root: &'a PackedNode<T, A, S>) -> Self {
+        TreeIterator {
+            key: vec![],
+            stack: vec![(root, State::Start)],
+        }
+    }
+}
+
+impl<'a, T, A, S: Summary<T>> Iterator for TreeIterator<'a, T, A, S> {
     type Item = (Vec<u8>, &'a T);
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -52,11 +63,8 @@ impl<'a, T> Iterator for TreeIterator<'a, T> {
     }
 }
 
-impl<T> Trie<T> {
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
     pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, &T)> {
-        TreeIterator {
-            key: vec![],
-            stack: vec![(&self.root, State::Start)],
-        }
+        TreeIterator::new(&self.root)
     }
 }
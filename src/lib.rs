@@ -1,6 +1,6 @@
 // TODO:
 // # Algorithm
-// [ ] Add values optimization
+// [X] Add values optimization
 // [ ] Make removals patch up the tree if needed.
 // [ ] Add SIMD support
 // [ ] Add in place mutations
@@ -17,27 +17,27 @@
 // # Performance
 // [X] Pack header tighter
 // [ ] Can we avoid cloning the key in the iterator?
-// [ ] Add SIMD prefix comparison + length short circuit
+// [X] Add SIMD prefix comparison + length short circuit
 //
 // # API
 // [ ] Add iter_mut
 // [ ] Add range iteration
 // [ ] Add into_iter
 // [ ] Add .keys() and .values()
-// [ ] Add random sampling
+// [X] Add random sampling
 // [ ] Min/max APIs
 // [ ] Entry API
 // [ ] Clear API
 // [ ] Merge two tries?
 // [ ] Split a trie?
-// [ ] Node annotation?
-// [ ] Implement clone
+// [X] Node annotation?
+// [X] Implement clone
 //
 // # Testing
-// [ ] Add memory report (w/external fragmentation?)
+// [X] Add memory report (w/external fragmentation?)
 // [ ] Add benchmarks with representative data, compare to other structures
-// [ ] Fuzz testing
-// [ ] Add invariant checks (re: prefix optimization, child lengths, value optimization...)
+// [X] Fuzz testing
+// [X] Add invariant checks (re: prefix optimization, child lengths, value optimization...)
 // [ ] Better unit tests lol
 // [ ] Add microbenchmarking suite
 // [ ] Seems like we're probably memory bound for latency anyways :(
@@ -48,7 +48,7 @@
 // [ ] Naming: baobab?
 //
 // # Packable
-// [ ] Better handle panics within user code
+// [X] Better handle panics within user code
 // [ ] Add dealloc in place perhaps?
 // [ ] DSL for specifying packed structures?  See packed2.rs
 //
@@ -60,17 +60,129 @@
 #[macro_use(quickcheck)]
 extern crate quickcheck_macros;
 
+mod allocator;
+mod automaton;
 mod bitset;
+mod bulk;
+mod concurrent;
+mod dot;
+mod fallible;
+mod format;
+mod frozen;
+mod fuzzy;
+mod glob;
 mod header;
 mod iter;
 mod insert;
+mod invariants;
 mod node;
+mod optimistic;
 mod packable;
 mod packed_node;
+mod persistent;
+mod prefix;
+mod rank;
+mod regex;
 mod remove;
+#[cfg(feature = "rand")]
+mod sample;
+#[cfg(feature = "serde")]
+mod serde_impl;
+mod slab;
+mod stats;
+mod summary;
 mod trie;
 
 #[cfg(test)]
 mod qc_tests;
+#[cfg(test)]
+mod test_helpers;
 
+pub use allocator::{AllocError, Allocator, Bump, Counting, Failing, Global};
+pub use automaton::{Automaton, Complement, Intersection, Prefix, StartsWith, Subsequence, Union};
+pub use format::{BytesCodec, LeCodec, ReadError, ValueCodec};
+pub use frozen::FrozenTrie;
+pub use fuzzy::FuzzyOptions;
+pub use glob::{Glob, GlobOptions};
+pub use concurrent::ConcurrentTrie;
+pub use dot::DotOptions;
+pub use invariants::{InvariantViolation, ViolationKind};
+pub use optimistic::OptimisticTrie;
+pub use persistent::PersistentTrie;
+pub use regex::{Regex, RegexError};
+pub use slab::Slab;
+pub use stats::{MemoryStats, NodeTypeStats};
+pub use summary::Summary;
 pub use trie::Trie;
+
+// Fail to compile if `$ty` implements `$trait`: the call to `some_item` is only ambiguous when
+// the second impl applies too.
+macro_rules! assert_not_impl {
+    ($ty:ty: $trait:path) => {
+        const _: fn() = || {
+            trait AmbiguousIfImpl<A> {
+                fn some_item() {}
+            }
+            impl<T: ?Sized> AmbiguousIfImpl<()> for T {}
+            struct Invalid;
+            impl<T: ?Sized + $trait> AmbiguousIfImpl<Invalid> for T {}
+            let _ = <$ty as AmbiguousIfImpl<_>>::some_item;
+        };
+    };
+}
+
+// Compile-time checks that the tries are `Send` and `Sync` exactly when they should be.
+#[allow(dead_code)]
+fn assert_send_sync() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn send<T: Send>() {}
+    fn sync<T: Sync>() {}
+
+    #[derive(Clone)]
+    struct NotSend(Rc<()>);
+    impl Summary<u64> for NotSend {
+        fn empty() -> Self {
+            NotSend(Rc::new(()))
+        }
+
+        fn of(_: &u64) -> Self {
+            Self::empty()
+        }
+
+        fn combine(&self, _: &Self) -> Self {
+            Self::empty()
+        }
+    }
+
+    send::<Trie<u64>>();
+    sync::<Trie<u64>>();
+    send::<Trie<String, Slab>>();
+    send::<Trie<u64, Bump>>();
+    send::<Trie<u64, Counting>>();
+    send::<OptimisticTrie<Vec<u8>>>();
+    sync::<OptimisticTrie<Vec<u8>>>();
+    send::<FrozenTrie<'static>>();
+    sync::<FrozenTrie<'static>>();
+    send::<PersistentTrie<u64>>();
+    sync::<PersistentTrie<u64>>();
+    send::<ConcurrentTrie<std::sync::Arc<u64>>>();
+    sync::<ConcurrentTrie<std::sync::Arc<u64>>>();
+
+    // Values, allocators, and summaries that can't be shared or sent make the trie the same.
+    assert_not_impl!(Trie<Rc<u64>>: Send);
+    assert_not_impl!(Trie<Rc<u64>>: Sync);
+    assert_not_impl!(Trie<Cell<u64>>: Sync);
+    assert_not_impl!(Trie<u64, Slab>: Sync);
+    assert_not_impl!(Trie<u64, Bump>: Sync);
+    assert_not_impl!(Trie<u64, Global, NotSend>: Send);
+    assert_not_impl!(Trie<u64, Global, NotSend>: Sync);
+    assert_not_impl!(OptimisticTrie<Rc<u64>>: Send);
+    assert_not_impl!(OptimisticTrie<Cell<u64>>: Sync);
+    assert_not_impl!(PersistentTrie<Rc<u64>>: Send);
+    assert_not_impl!(PersistentTrie<Cell<u64>>: Sync);
+    assert_not_impl!(ConcurrentTrie<Rc<u64>>: Send);
+    assert_not_impl!(ConcurrentTrie<Rc<u64>>: Sync);
+    assert_not_impl!(ConcurrentTrie<Cell<u64>>: Sync);
+}
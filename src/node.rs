@@ -1,103 +1,105 @@
 use std::collections::BTreeMap;
 use std::mem;
 
+use crate::allocator::Allocator;
 use crate::bitset::Bitset;
 use crate::header::{MAX_PREFIX_LEN, NodeHeader, NodeChildrenType};
 use crate::packable::PackableStruct;
 use crate::packed_node::PackedNode;
+use crate::summary::Summary;
 
-pub struct Node<T> {
+pub struct Node<T, A, S: Summary<T> = ()> {
     pub prefix: Vec<u8>,
-    pub children: NodeChildren<T>,
+    // Key bytes after `prefix` that the node doesn't store.  This is always zero outside of
+    // `OptimisticTrie`.
+    pub skipped_len: usize,
+    pub children: NodeChildren<T, A, S>,
     pub value: Option<T>,
 }
 
-impl<T> Node<T> {
-    pub fn new(prefix: Vec<u8>, children: NodeChildren<T>, value: Option<T>) -> Self {
+impl<T, A: Allocator, S: Summary<T>> Node<T, A, S> {
+    pub fn new(
+        prefix: Vec<u8>,
+        children: NodeChildren<T, A, S>,
+        value: Option<T>,
+        alloc: &A,
+    ) -> Self {
         if prefix.len() > MAX_PREFIX_LEN {
             let (&branch, suffix) = prefix[MAX_PREFIX_LEN..].split_first().unwrap();
-            let child = Node::new(suffix.to_owned(), children, value);
+            let child = Node::new(suffix.to_owned(), children, value, alloc);
             return Node {
                 prefix: prefix[..MAX_PREFIX_LEN].to_owned(),
-                children: NodeChildren::one(branch, PackedNode::new(child)),
+                skipped_len: 0,
+                children: NodeChildren::one(branch, PackedNode::new(child, alloc)),
                 value: None,
             };
         }
-        Node { prefix, children, value }
+        Node { prefix, skipped_len: 0, children, value }
     }
+}
 
+impl<T, A, S: Summary<T>> Node<T, A, S> {
     pub fn prefix(&self) -> &[u8] {
         &self.prefix[..]
     }
 }
 
-impl<T> PackableStruct for Node<T> {
-    type Header = NodeHeader<T>;
+impl<T, A, S: Summary<T>> PackableStruct for Node<T, A, S> {
+    type Header = NodeHeader<T, S>;
 
-    fn header(&self) -> NodeHeader<T> {
+    fn header(&self) -> NodeHeader<T, S> {
         NodeHeader::new(self.prefix.len(), self.children.len(), self.value.is_some())
+            .with_skipped_len(self.skipped_len)
     }
 
-    fn pack(self, header: NodeHeader<T>, buf: &mut [u8]) {
+    fn pack(self, header: NodeHeader<T, S>, buf: &mut [u8]) {
         let Self {
             prefix,
             children,
             value,
+            ..
         } = self;
 
-        unsafe {
-            buf[header.header_range()]
-                .as_mut_ptr()
-                .cast::<NodeHeader<T>>()
-                .write(header);
-        }
+        header.write(&mut buf[header.header_range()]);
 
         buf[header.prefix_range()].copy_from_slice(&prefix[..]);
 
-        #[allow(unused_assignments)]
-        {
-            let mut children_buf = &mut buf[header.children_range()];
-            assert_eq!(children.structure_type(), header.children_type());
-            match children {
-                NodeChildren::Empty => (),
-                NodeChildren::Pairs { keys, values } => {
-                    assert_eq!(keys.len(), values.len());
-                    for k in keys {
-                        children_buf[0] = k;
-                        children_buf = &mut children_buf[1..];
-                    }
-                    for v in values {
-                        unsafe {
-                            children_buf.as_mut_ptr().cast::<PackedNode<T>>().write(v);
-                        }
-                        children_buf = &mut children_buf[mem::size_of::<PackedNode<T>>()..];
-                    }
-                }
-                NodeChildren::Sparse { bitset, values } => {
-                    let bitset_len = mem::size_of::<Bitset>();
-                    unsafe {
-                        children_buf[..bitset_len]
-                            .as_mut_ptr()
-                            .cast::<Bitset>()
-                            .write(bitset);
-                    };
-                    children_buf = &mut children_buf[bitset_len..];
-                    for v in values {
-                        unsafe {
-                            children_buf.as_mut_ptr().cast::<PackedNode<T>>().write(v);
-                        }
-                        children_buf = &mut children_buf[mem::size_of::<PackedNode<T>>()..];
-                    }
+        assert_eq!(children.structure_type(), header.children_type());
+        let count_range = header.count_range();
+        if !count_range.is_empty() {
+            let count = children.num_values() + value.is_some() as usize;
+            buf[count_range].copy_from_slice(&count.to_ne_bytes());
+        }
+        // Packing mustn't run user code, since callers have usually just taken apart the nodes
+        // this one is built from, so the summary gets filled in by `update_summary` afterwards.
+        let summary_range = header.summary_range();
+        if !summary_range.is_empty() {
+            unsafe { buf[summary_range].as_mut_ptr().cast::<Option<S>>().write(None) };
+        }
+        let index_start = header.children_range().start;
+        match children {
+            NodeChildren::Empty => (),
+            NodeChildren::Pairs { keys, values } => {
+                assert_eq!(keys.len(), values.len());
+                buf[index_start..(index_start + keys.len())].copy_from_slice(&keys[..]);
+                write_slots(&mut buf[header.slots_range()], values);
+            }
+            NodeChildren::Sparse { bitset, values } => {
+                let bitset_len = mem::size_of::<Bitset>();
+                unsafe {
+                    buf[index_start..(index_start + bitset_len)]
+                        .as_mut_ptr()
+                        .cast::<Bitset>()
+                        .write(bitset);
                 }
-                NodeChildren::Dense { table } => {
-                    let table_len = mem::size_of::<[PackedNode<T>; 256]>();
-                    unsafe {
-                        children_buf[..table_len]
-                            .as_mut_ptr()
-                            .cast::<[PackedNode<T>; 256]>()
-                            .write(table);
-                    }
-                    children_buf = &mut children_buf[table_len..];
+                write_slots(&mut buf[header.slots_range()], values);
+            }
+            NodeChildren::Dense { table } => {
+                unsafe {
+                    buf[header.slots_range()]
+                        .as_mut_ptr()
+                        .cast::<[PackedNode<T, A, S>; 256]>()
+                        .write(table);
                 }
             }
         }
@@ -109,47 +111,43 @@ impl<T> PackableStruct for Node<T> {
         }
     }
 
-    fn unpack(header: NodeHeader<T>, buf: &[u8]) -> Self {
+    fn unpack(header: NodeHeader<T, S>, buf: &[u8]) -> Self {
         let prefix = buf[header.prefix_range()].to_owned();
 
-        let children_buf = &buf[header.children_range()];
+        let index_start = header.children_range().start;
+        let slots_buf = &buf[header.slots_range()];
         let children = match header.children_type() {
             NodeChildrenType::Empty => NodeChildren::Empty,
             NodeChildrenType::Pairs => {
-                let keys = children_buf[0..header.num_children()].to_owned();
-                let mut values = Vec::with_capacity(keys.len());
-                let ptr_size = mem::size_of::<PackedNode<T>>();
-                for vs in children_buf[header.num_children()..].chunks(ptr_size) {
-                    let p = unsafe { vs.as_ptr().cast::<PackedNode<T>>().read() };
-                    values.push(p);
-                }
+                let keys = buf[index_start..(index_start + header.num_children())].to_owned();
+                let values = read_slots(slots_buf);
                 assert_eq!(keys.len(), values.len());
                 NodeChildren::Pairs { keys, values }
             }
             NodeChildrenType::Sparse => {
                 let bitset_len = mem::size_of::<Bitset>();
-                let bitset = unsafe { children_buf[..bitset_len].as_ptr().cast::<Bitset>().read() };
-                let mut values = Vec::with_capacity(header.num_children());
-                let ptr_size = mem::size_of::<PackedNode<T>>();
-                for vs in children_buf[bitset_len..].chunks(ptr_size) {
-                    let p = unsafe { vs.as_ptr().cast::<PackedNode<T>>().read() };
-                    values.push(p);
-                }
+                let bitset_buf = &buf[index_start..(index_start + bitset_len)];
+                let bitset = unsafe { bitset_buf.as_ptr().cast::<Bitset>().read() };
+                let values = read_slots(slots_buf);
                 assert_eq!(values.len(), header.num_children());
                 NodeChildren::Sparse { bitset, values }
             }
             NodeChildrenType::Dense => {
-                let table_len = mem::size_of::<[PackedNode<T>; 256]>();
                 let table = unsafe {
-                    children_buf[..table_len]
+                    slots_buf
                         .as_ptr()
-                        .cast::<[PackedNode<T>; 256]>()
+                        .cast::<[PackedNode<T, A, S>; 256]>()
                         .read()
                 };
                 NodeChildren::Dense { table }
             }
         };
 
+        let summary_range = header.summary_range();
+        if !summary_range.is_empty() {
+            drop(unsafe { buf[summary_range].as_ptr().cast::<Option<S>>().read() });
+        }
+
         let value = header.value_range().map(|range| {
             let value_buf = &buf[range];
             unsafe { value_buf.as_ptr().cast::<T>().read() }
@@ -157,42 +155,73 @@ impl<T> PackableStruct for Node<T> {
 
         Self {
             prefix,
+            skipped_len: header.skipped_len(),
             children,
             value,
         }
     }
 }
 
-pub enum NodeChildren<T> {
+// `buf` must be aligned for `PackedNode<T, A, S>`, which `NodeHeader::slots_range` guarantees.
+fn write_slots<T, A, S: Summary<T>>(buf: &mut [u8], values: Vec<PackedNode<T, A, S>>) {
+    assert_eq!(buf.len(), values.len() * mem::size_of::<PackedNode<T, A, S>>());
+    for (slot, v) in buf.chunks_mut(mem::size_of::<PackedNode<T, A, S>>()).zip(values) {
+        unsafe { slot.as_mut_ptr().cast::<PackedNode<T, A, S>>().write(v) };
+    }
+}
+
+fn read_slots<T, A, S: Summary<T>>(buf: &[u8]) -> Vec<PackedNode<T, A, S>> {
+    buf.chunks(mem::size_of::<PackedNode<T, A, S>>())
+        .map(|slot| unsafe { slot.as_ptr().cast::<PackedNode<T, A, S>>().read() })
+        .collect()
+}
+
+pub enum NodeChildren<T, A, S: Summary<T> = ()> {
     Empty,
     Pairs {
         keys: Vec<u8>,
-        values: Vec<PackedNode<T>>,
+        values: Vec<PackedNode<T, A, S>>,
     },
     Sparse {
         bitset: Bitset,
-        values: Vec<PackedNode<T>>,
+        values: Vec<PackedNode<T, A, S>>,
     },
     Dense {
-        table: [PackedNode<T>; 256],
+        table: [PackedNode<T, A, S>; 256],
     },
 }
 
-impl<T> NodeChildren<T> {
-    pub fn one(k: u8, ptr: PackedNode<T>) -> Self {
+impl<T, A, S: Summary<T>> NodeChildren<T, A, S> {
+    pub fn one(k: u8, ptr: PackedNode<T, A, S>) -> Self {
         NodeChildren::Pairs {
             keys: vec![k],
             values: vec![ptr],
         }
     }
 
-    pub fn two(k1: u8, ptr1: PackedNode<T>, k2: u8, ptr2: PackedNode<T>) -> Self {
+    pub fn two(k1: u8, ptr1: PackedNode<T, A, S>, k2: u8, ptr2: PackedNode<T, A, S>) -> Self {
+        // Keep Pairs keys sorted so children are always visited in byte order.
+        if k2 < k1 {
+            return Self::two(k2, ptr2, k1, ptr1);
+        }
         NodeChildren::Pairs {
             keys: vec![k1, k2],
             values: vec![ptr1, ptr2],
         }
     }
 
+    pub fn get_mut(&mut self, byte: u8) -> Option<&mut PackedNode<T, A, S>> {
+        match self {
+            NodeChildren::Empty => None,
+            NodeChildren::Pairs { keys, values } => {
+                let i = keys.iter().position(|&k| k == byte)?;
+                Some(&mut values[i])
+            }
+            NodeChildren::Sparse { bitset, values } => Some(&mut values[bitset.query(byte)?]),
+            NodeChildren::Dense { table } => Some(&mut table[byte as usize]),
+        }
+    }
+
     fn structure_type(&self) -> NodeChildrenType {
         match self {
             NodeChildren::Empty => NodeChildrenType::Empty,
@@ -211,7 +240,18 @@ impl<T> NodeChildren<T> {
         }
     }
 
-    pub fn into_pairs(self) -> BTreeMap<u8, PackedNode<T>> {
+    // Number of values below these children.
+    pub fn num_values(&self) -> usize {
+        match self {
+            NodeChildren::Empty => 0,
+            NodeChildren::Pairs { values, .. } | NodeChildren::Sparse { values, .. } => {
+                values.iter().map(PackedNode::len).sum()
+            }
+            NodeChildren::Dense { table } => table.iter().map(PackedNode::len).sum(),
+        }
+    }
+
+    pub fn into_pairs(self) -> BTreeMap<u8, PackedNode<T, A, S>> {
         let mut out = BTreeMap::new();
         match self {
             NodeChildren::Empty => (),
@@ -241,7 +281,7 @@ impl<T> NodeChildren<T> {
         out
     }
 
-    pub fn from_pairs(pairs: BTreeMap<u8, PackedNode<T>>) -> Self {
+    pub fn from_pairs(pairs: BTreeMap<u8, PackedNode<T, A, S>>) -> Self {
         match pairs.len() {
             0 => NodeChildren::Empty,
             1..=32 => {
@@ -263,7 +303,7 @@ impl<T> NodeChildren<T> {
                 NodeChildren::Sparse { bitset, values }
             }
             192..=256 => {
-                let mut table: [PackedNode<T>; 256] = unsafe { mem::zeroed() };
+                let mut table: [PackedNode<T, A, S>; 256] = unsafe { mem::zeroed() };
                 for i in 0..256 {
                     table[i] = PackedNode::empty();
                 }
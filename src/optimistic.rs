@@ -0,0 +1,466 @@
+// An `OptimisticTrie` uses the hybrid path compression scheme from the ART paper.  Short
+// prefixes are stored in their nodes as usual, but long ones only keep their first few bytes and
+// record how many bytes they skip in the node header.  Lookups jump over the skipped bytes
+// without comparing them, so every value is stored alongside its full key, and we verify the key
+// once we've found a candidate.
+//
+// Whenever we need a node's skipped bytes (to split its prefix on insert, or to merge it into its
+// parent on remove), we read them out of the key of any value below it: all keys in a subtree
+// share its prefixes.
+
+use std::alloc::{handle_alloc_error, Layout};
+use std::collections::BTreeMap;
+use std::io;
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use crate::allocator::{Allocator, Global};
+use crate::invariants::{self, InvariantViolation};
+use crate::node::{Node, NodeChildren};
+use crate::packed_node::{Children, PackedNode};
+use crate::prefix;
+use crate::stats::MemoryStats;
+
+// Prefixes up to `MAX_STORED_PREFIX_LEN` bytes are stored in full.  Longer ones store their
+// first `STORED_PREFIX_LEN` bytes and skip the rest.
+const MAX_STORED_PREFIX_LEN: usize = 16;
+const STORED_PREFIX_LEN: usize = 8;
+
+// A value and its full key, which is kept in a buffer from the trie's allocator.  Leaves can't
+// free that buffer on their own, so it's freed by `into_value` when a leaf leaves the trie, or by
+// `free_keys` when the whole trie is dropped.
+pub struct Leaf<T> {
+    key: NonNull<u8>,
+    key_len: usize,
+    value: T,
+}
+
+// Leaves own their keys outright, like a `Box<[u8]>` would.
+unsafe impl<T: Send> Send for Leaf<T> {}
+unsafe impl<T: Sync> Sync for Leaf<T> {}
+
+impl<T> Leaf<T> {
+    fn new<A: Allocator>(key: &[u8], value: T, alloc: &A) -> Self {
+        let mut ptr = NonNull::dangling();
+        if !key.is_empty() {
+            let layout = Layout::for_value(key);
+            ptr = alloc.alloc_zeroed(layout).unwrap_or_else(|| handle_alloc_error(layout));
+            unsafe { ptr::copy_nonoverlapping(key.as_ptr(), ptr.as_ptr(), key.len()) };
+        }
+        Self { key: ptr, key_len: key.len(), value }
+    }
+
+    fn key(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.key.as_ptr(), self.key_len) }
+    }
+
+    fn into_value<A: Allocator>(self, alloc: &A) -> T {
+        unsafe { self.free_key(alloc) };
+        self.value
+    }
+
+    // The key can't be read again afterwards.
+    unsafe fn free_key<A: Allocator>(&self, alloc: &A) {
+        if self.key_len != 0 {
+            alloc.dealloc(self.key, Layout::array::<u8>(self.key_len).unwrap());
+        }
+    }
+}
+
+/// A trie with optimistic path compression.  Long compressed prefixes cost a fixed amount of
+/// space in their nodes regardless of their length, and lookups don't compare them byte by byte.
+/// In exchange, every value also stores a copy of its full key.
+pub struct OptimisticTrie<T, A: Allocator = Global> {
+    root: PackedNode<Leaf<T>, A>,
+    alloc: A,
+}
+
+impl<T> OptimisticTrie<T> {
+    pub fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<T, A: Allocator> OptimisticTrie<T, A> {
+    pub fn new_in(alloc: A) -> Self {
+        Self {
+            root: PackedNode::empty(),
+            alloc,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&T> {
+        let mut cur = &self.root;
+        let mut depth = 0;
+        loop {
+            let node_prefix = cur.prefix();
+            if !prefix::starts_with(&key[depth..], node_prefix) {
+                return None;
+            }
+            depth += node_prefix.len() + cur.skipped_len();
+            let branch_byte = match key.get(depth) {
+                None if depth == key.len() => {
+                    let leaf = cur.value()?;
+                    return if leaf.key() == key { Some(&leaf.value) } else { None };
+                }
+                None => return None,
+                Some(&b) => b,
+            };
+            depth += 1;
+            cur = cur.lookup(branch_byte)?;
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: T) -> Option<T> {
+        let old_value = insert(&mut self.root, key, 0, value, &self.alloc);
+        self.debug_check_invariants();
+        old_value
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<T> {
+        let value = remove(&mut self.root, key, 0, &self.alloc);
+        self.debug_check_invariants();
+        value
+    }
+
+    pub fn iter(&self) -> Iter<'_, T, A> {
+        Iter {
+            pending: Some(&self.root),
+            stack: vec![],
+        }
+    }
+
+    /// See `Trie::memory_stats`.  Note that the keys stored next to each value aren't included,
+    /// even though they come from the trie's allocator too.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        stats.record(&self.root, 0);
+        stats
+    }
+
+    /// See `Trie::check_invariants`.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        invariants::check_node(&self.root, &mut vec![])
+    }
+
+    #[inline]
+    fn debug_check_invariants(&self) {
+        invariants::debug_check(|| self.check_invariants());
+    }
+
+    pub fn debug(&self, out: &mut impl io::Write) -> io::Result<()> {
+        self.root.debug("", out)
+    }
+}
+
+impl<T> Default for OptimisticTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, A: Allocator> Drop for OptimisticTrie<T, A> {
+    fn drop(&mut self) {
+        if !A::NOOP_DEALLOC {
+            free_keys(&self.root, &self.alloc);
+        }
+        self.root.drop_in(&self.alloc);
+    }
+}
+
+// Free the keys of all of the leaves below `node`, which is about to be dropped.
+fn free_keys<T, A: Allocator>(node: &PackedNode<Leaf<T>, A>, alloc: &A) {
+    if let Some(leaf) = node.value() {
+        unsafe { leaf.free_key(alloc) };
+    }
+    for (_, child) in node.children() {
+        free_keys(child, alloc);
+    }
+}
+
+// Find the key of any value below `node`.
+fn any_key<T, A>(node: &PackedNode<Leaf<T>, A>) -> &[u8] {
+    let mut cur = node;
+    loop {
+        if let Some(leaf) = cur.value() {
+            return leaf.key();
+        }
+        cur = cur
+            .children()
+            .map(|(_, child)| child)
+            .find(|child| !child.is_empty())
+            .expect("Node without a value or children");
+    }
+}
+
+// The node's stored prefix followed by its skipped bytes, where `depth` is the length of the key
+// leading up to the node.
+fn full_prefix<T, A>(node: &PackedNode<Leaf<T>, A>, depth: usize) -> &[u8] {
+    let skipped_len = node.skipped_len();
+    if skipped_len == 0 {
+        return node.prefix();
+    }
+    &any_key(node)[depth..(depth + node.prefix().len() + skipped_len)]
+}
+
+fn new_node<T, A>(
+    full_prefix: &[u8],
+    children: NodeChildren<Leaf<T>, A>,
+    value: Option<Leaf<T>>,
+) -> Node<Leaf<T>, A> {
+    let stored_len = if full_prefix.len() <= MAX_STORED_PREFIX_LEN {
+        full_prefix.len()
+    } else {
+        STORED_PREFIX_LEN
+    };
+    Node {
+        prefix: full_prefix[..stored_len].to_owned(),
+        skipped_len: full_prefix.len() - stored_len,
+        children,
+        value,
+    }
+}
+
+fn insert<T, A: Allocator>(
+    node: &mut PackedNode<Leaf<T>, A>,
+    key: &[u8],
+    depth: usize,
+    value: T,
+    alloc: &A,
+) -> Option<T> {
+    if node.is_empty() {
+        let new_leaf = Leaf::new(key, value, alloc);
+        let new_leaf = new_node(&key[depth..], NodeChildren::Empty, Some(new_leaf));
+        *node = PackedNode::new(new_leaf, alloc);
+        return None;
+    }
+    let node_prefix = full_prefix(node, depth);
+    let prefix_len = node_prefix.len();
+    if let Some(i) = prefix::mismatch(node_prefix, &key[depth..]) {
+        // See `PackedNode::split_prefix` and `PackedNode::branch_prefix`.
+        let node_prefix = node_prefix.to_owned();
+        let Node { children, value: old_value, .. } = node.take(alloc);
+        let old_child = new_node(&node_prefix[(i + 1)..], children, old_value);
+        let old_child = PackedNode::new(old_child, alloc);
+        let leaf = Leaf::new(key, value, alloc);
+        let new_parent = match key.get(depth + i) {
+            Some(&key_byte) => {
+                let new_child = new_node(&key[(depth + i + 1)..], NodeChildren::Empty, Some(leaf));
+                let new_child = PackedNode::new(new_child, alloc);
+                let children = NodeChildren::two(node_prefix[i], old_child, key_byte, new_child);
+                new_node(&node_prefix[..i], children, None)
+            }
+            None => {
+                let children = NodeChildren::one(node_prefix[i], old_child);
+                new_node(&node_prefix[..i], children, Some(leaf))
+            }
+        };
+        *node = PackedNode::new(new_parent, alloc);
+        return None;
+    }
+    let depth = depth + prefix_len;
+    let branch_byte = match key.get(depth) {
+        None => {
+            let old_leaf = node.set_value(Some(Leaf::new(key, value, alloc)), alloc);
+            return old_leaf.map(|leaf| leaf.into_value(alloc));
+        }
+        Some(&b) => b,
+    };
+    match node.lookup_mut(branch_byte) {
+        None => {
+            let leaf = Leaf::new(key, value, alloc);
+            let new_child = new_node(&key[(depth + 1)..], NodeChildren::Empty, Some(leaf));
+            node.add_child(branch_byte, new_child, alloc);
+            None
+        }
+        Some(next_node) => {
+            let filled_slot = next_node.is_empty();
+            let old_value = insert(next_node, key, depth + 1, value, alloc);
+            if filled_slot {
+                node.increment_dense_children();
+            }
+            if old_value.is_none() {
+                node.set_len(node.len() + 1);
+            }
+            old_value
+        }
+    }
+}
+
+fn remove<T, A: Allocator>(
+    node: &mut PackedNode<Leaf<T>, A>,
+    key: &[u8],
+    depth: usize,
+    alloc: &A,
+) -> Option<T> {
+    if node.is_empty() || !prefix::starts_with(&key[depth..], node.prefix()) {
+        return None;
+    }
+    let prefix_len = node.prefix().len() + node.skipped_len();
+    let child_depth = depth + prefix_len + 1;
+    let removed = match key.get(depth + prefix_len) {
+        None if depth + prefix_len == key.len() => {
+            if node.value().map(|leaf| leaf.key() != key).unwrap_or(true) {
+                return None;
+            }
+            // Grab our prefix while we still have a value to read it from.
+            let node_prefix = full_prefix(node, depth).to_owned();
+            let Node { children, value, .. } = node.take(alloc);
+            repack(node, &node_prefix, depth, children.into_pairs(), None, alloc);
+            return value.map(|leaf| leaf.into_value(alloc));
+        }
+        None => return None,
+        Some(&branch_byte) => {
+            let next_node = node.lookup_mut(branch_byte)?;
+            let removed = remove(next_node, key, child_depth, alloc)?;
+            if !next_node.is_empty() {
+                node.set_len(node.len() - 1);
+                return Some(removed);
+            }
+            removed
+        }
+    };
+    // If we have neither a value nor any other children, there's nothing to read our prefix
+    // from, but we're about to become empty anyways.
+    let node_prefix = if node.has_value() || node.children().any(|(_, c)| !c.is_empty()) {
+        full_prefix(node, depth).to_owned()
+    } else {
+        vec![]
+    };
+    let Node { children, value, .. } = node.take(alloc);
+    repack(node, &node_prefix, depth, children.into_pairs(), value, alloc);
+    Some(removed)
+}
+
+// Rebuild a node after removing a value below it, patching up the invariants described in
+// `remove.rs`.
+fn repack<T, A: Allocator>(
+    node: &mut PackedNode<Leaf<T>, A>,
+    node_prefix: &[u8],
+    depth: usize,
+    pairs: BTreeMap<u8, PackedNode<Leaf<T>, A>>,
+    value: Option<Leaf<T>>,
+    alloc: &A,
+) {
+    match (value.is_some(), pairs.len()) {
+        // Leave ourselves as empty to let the parent cleanup.
+        (false, 0) => (),
+        (false, 1) => {
+            let (child_byte, mut child) = pairs.into_iter().next().unwrap();
+            let child_depth = depth + node_prefix.len() + 1;
+            let mut merged_prefix = node_prefix.to_owned();
+            merged_prefix.push(child_byte);
+            merged_prefix.extend_from_slice(full_prefix(&child, child_depth));
+            let Node { children, value, .. } = child.take(alloc);
+            *node = PackedNode::new(new_node(&merged_prefix, children, value), alloc);
+        }
+        _ => {
+            let children = NodeChildren::from_pairs(pairs);
+            *node = PackedNode::new(new_node(node_prefix, children, value), alloc);
+        }
+    }
+}
+
+pub struct Iter<'a, T, A> {
+    pending: Option<&'a PackedNode<Leaf<T>, A>>,
+    stack: Vec<Children<'a, Leaf<T>, A>>,
+}
+
+impl<'a, T, A> Iterator for Iter<'a, T, A> {
+    type Item = (&'a [u8], &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = match self.pending.take() {
+                Some(node) => node,
+                None => match self.stack.last_mut()?.next() {
+                    Some((_, child)) => child,
+                    None => {
+                        self.stack.pop();
+                        continue;
+                    }
+                },
+            };
+            if node.is_empty() {
+                continue;
+            }
+            self.stack.push(node.children());
+            if let Some(leaf) = node.value() {
+                return Some((leaf.key(), &leaf.value));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OptimisticTrie;
+    use crate::{Counting, Trie};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_optimistic() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let base = "https://example.com/some/very/long/shared/path/";
+        let mut model = BTreeMap::new();
+        let counting = Counting::default();
+        let mut t = OptimisticTrie::new_in(&counting);
+        for i in 0..3000u32 {
+            let dir = "abcdefgh".repeat(rng.gen_range(0, 5));
+            let key = format!("{}{}/{}", base, dir, rng.gen_range(0, 500)).into_bytes();
+            if rng.gen_bool(0.3) {
+                assert_eq!(t.remove(&key), model.remove(&key));
+            } else {
+                assert_eq!(t.insert(&key, i), model.insert(key, i));
+            }
+        }
+        assert_eq!(t.check_invariants(), Ok(()));
+        for (k, v) in &model {
+            assert_eq!(t.get(k), Some(v));
+        }
+        assert!(t.iter().map(|(k, &v)| (k.to_vec(), v)).eq(model.clone().into_iter()));
+
+        // Keys that agree with the stored bytes but not the skipped ones are caught at the leaf.
+        let (k, _) = model.iter().next().unwrap();
+        let mut wrong = k.clone();
+        wrong[20] ^= 1;
+        assert_eq!(t.get(&wrong), None);
+        assert_eq!(t.remove(&wrong), None);
+        assert_eq!(t.get(&k[..(k.len() - 1)]), None);
+
+        for (k, v) in &model {
+            assert_eq!(t.remove(k), Some(*v));
+        }
+        assert!(t.iter().next().is_none());
+        assert_eq!(t.memory_stats().total_nodes(), 0);
+        // Removed and replaced values' keys were freed along with them.
+        assert_eq!(counting.live_allocations(), 0);
+    }
+
+    #[test]
+    fn test_optimistic_prefix_bytes() {
+        // The prefix stored in optimistic nodes doesn't grow with the keys.
+        let keys = (0..100)
+            .map(|i| format!("{}{}", "x".repeat(300), i).into_bytes())
+            .collect::<Vec<_>>();
+        let (pessimistic_alloc, optimistic_alloc) = (Counting::default(), Counting::default());
+        let mut pessimistic = Trie::new_in(&pessimistic_alloc);
+        let mut optimistic = OptimisticTrie::new_in(&optimistic_alloc);
+        for k in &keys {
+            pessimistic.insert(k, ());
+            optimistic.insert(k, ());
+        }
+        assert!(pessimistic.memory_stats().prefix_bytes >= 300);
+        assert!(optimistic.memory_stats().prefix_bytes < 300);
+        assert!(optimistic.iter().map(|(k, _)| k.to_vec()).eq(pessimistic.iter().map(|(k, _)| k)));
+
+        // Each leaf's key is allocated next to the nodes, and freed with them.
+        let key_bytes = keys.iter().map(|k| k.len()).sum::<usize>();
+        let node_bytes = optimistic.memory_stats().total_bytes();
+        assert_eq!(optimistic_alloc.live_bytes(), node_bytes + key_bytes);
+        drop(optimistic);
+        assert_eq!(optimistic_alloc.live_allocations(), 0);
+    }
+}
@@ -1,9 +1,20 @@
-use std::alloc::{self, Layout};
-use std::mem;
+use std::alloc::{handle_alloc_error, Layout};
+use std::marker::PhantomData;
 use std::ptr::NonNull;
 use std::slice;
 
+use crate::allocator::{AllocError, Allocator};
+
 pub trait Header: Copy + Sized {
+    // Read the header back from the start of a packed buffer.  Headers that don't store
+    // themselves verbatim (e.g. ones with variable length encodings) can override this.
+    //
+    // # Safety
+    // `ptr` must point to the start of a buffer that `PackableStruct::pack` wrote this header to.
+    unsafe fn read(ptr: *const u8) -> Self {
+        ptr.cast::<Self>().read()
+    }
+
     // Alignment must exceed Self::alignment, size includes header
     fn layout(&self) -> Layout;
 }
@@ -21,32 +32,22 @@ pub trait PackableStruct {
     fn unpack(header: Self::Header, buf: &[u8]) -> Self;
 }
 
+// `PackedBox` doesn't hold on to its allocator, so it can't free itself on drop: owners must
+// give it back to `unpack`, or they'll leak the allocation.
 #[repr(packed)]
-pub struct PackedBox<T: PackableStruct> {
+pub struct PackedBox<T: PackableStruct, A> {
     ptr: NonNull<T::Header>,
+    marker: PhantomData<A>,
 }
 
-impl<T: PackableStruct> PackedBox<T> {
-    pub fn new(value: T) -> Self {
-        let header = value.header();
-        let layout = header.layout();
-        let size = layout.size();
-        let header_size = mem::size_of::<T::Header>();
-        assert!(size >= header_size);
-
-        let p = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
-            Some(p) => p,
-            None => alloc::handle_alloc_error(layout),
-        };
-        unsafe {
-            let slice = slice::from_raw_parts_mut(p.as_ptr(), size);
-            value.pack(header, slice);
-        }
-        Self { ptr: p.cast() }
-    }
+// A `PackedBox` owns its buffer just like a `Box<T>`, and only hands out shared references from
+// `&self`.  `A` is only a marker: the allocator itself is never reached through the box.
+unsafe impl<T: PackableStruct + Send, A> Send for PackedBox<T, A> {}
+unsafe impl<T: PackableStruct + Sync, A> Sync for PackedBox<T, A> {}
 
+impl<T: PackableStruct, A> PackedBox<T, A> {
     pub fn header(&self) -> T::Header {
-        unsafe { *self.ptr.as_ptr() }
+        unsafe { T::Header::read(self.ptr.as_ptr().cast()) }
     }
 
     pub fn slice(&self) -> &[u8] {
@@ -57,7 +58,6 @@ impl<T: PackableStruct> PackedBox<T> {
         }
     }
 
-    #[allow(unused)]
     pub fn slice_mut(&mut self) -> &mut [u8] {
         let layout = self.header().layout();
         unsafe {
@@ -65,27 +65,69 @@ impl<T: PackableStruct> PackedBox<T> {
             slice::from_raw_parts_mut(slice_ptr.as_ptr(), layout.size())
         }
     }
+}
 
-    pub fn unpack(self) -> T {
-        let header = self.header();
+impl<T: PackableStruct, A: Allocator> PackedBox<T, A> {
+    pub fn new(value: T, alloc: &A) -> Self {
+        match Self::try_new(value, alloc) {
+            Ok(p) => p,
+            Err((_, e)) => handle_alloc_error(e.layout()),
+        }
+    }
+
+    // Like `new`, but hands `value` back if the allocation fails.
+    pub fn try_new(value: T, alloc: &A) -> Result<Self, (T, AllocError)> {
+        let header = value.header();
         let layout = header.layout();
-        let value = T::unpack(header, self.slice());
+        let size = layout.size();
+
+        let p = match alloc.alloc_zeroed(layout) {
+            Some(p) => p,
+            None => return Err((value, AllocError::new(layout))),
+        };
+        unsafe {
+            let slice = slice::from_raw_parts_mut(p.as_ptr(), size);
+            value.pack(header, slice);
+        }
+        Ok(Self { ptr: p.cast(), marker: PhantomData })
+    }
 
-        unsafe { alloc::dealloc(self.ptr.as_ptr().cast(), layout) };
-        mem::forget(self);
+    pub fn unpack(self, alloc: &A) -> T {
+        let (value, buf) = self.unpack_detached();
+        buf.free(alloc);
         value
     }
-}
 
-impl<T: PackableStruct> Drop for PackedBox<T> {
-    fn drop(&mut self) {
+    // Like `unpack`, but leave the buffer allocated so that `repack` can put a value with the
+    // same layout back in it without allocating.
+    pub fn unpack_detached(self) -> (T, Detached<A>) {
         let header = self.header();
-        let layout = header.layout();
         let value = T::unpack(header, self.slice());
-        drop(value);
+        (value, Detached { ptr: self.ptr.cast(), layout: header.layout(), marker: PhantomData })
+    }
+
+    pub fn repack(value: T, buf: Detached<A>) -> Self {
+        let header = value.header();
+        assert_eq!(header.layout(), buf.layout, "Repacked value must have the same layout");
         unsafe {
-            alloc::dealloc(self.ptr.as_ptr().cast(), layout);
+            let slice = slice::from_raw_parts_mut(buf.ptr.as_ptr(), buf.layout.size());
+            value.pack(header, slice);
         }
+        Self { ptr: buf.ptr.cast(), marker: PhantomData }
+    }
+}
+
+// A buffer whose value `PackedBox::unpack_detached` has moved out.  It must either be `free`d or
+// given back to `PackedBox::repack`.
+pub struct Detached<A> {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    marker: PhantomData<A>,
+}
+
+impl<A: Allocator> Detached<A> {
+    pub fn free(self, alloc: &A) {
+        unsafe { alloc.dealloc(self.ptr, self.layout) };
     }
 }
 
@@ -96,6 +138,7 @@ impl<T: PackableStruct> Drop for PackedBox<T> {
 #[cfg(test)]
 mod tests {
     use super::{Header, PackableStruct, PackedBox};
+    use crate::allocator::Global;
 
     use std::alloc::Layout;
     use std::mem;
@@ -136,13 +179,13 @@ mod tests {
         let s = "hello there";
 
         let fat_string = String::from(s);
-        let mut thin_string = PackedBox::new(fat_string);
+        let mut thin_string = PackedBox::new(fat_string, &Global);
 
         let hdr_len = mem::size_of::<usize>();
         assert_eq!(&thin_string.slice()[hdr_len..], s.as_bytes());
         assert_eq!(&thin_string.slice_mut()[hdr_len..], s.as_bytes());
-        assert_eq!(&thin_string.unpack()[..], s);
+        assert_eq!(&thin_string.unpack(&Global)[..], s);
 
-        assert_eq!(mem::size_of::<PackedBox<String>>(), mem::size_of::<usize>());
+        assert_eq!(mem::size_of::<PackedBox<String, Global>>(), mem::size_of::<usize>());
     }
 }
@@ -1,82 +1,206 @@
-use std::mem;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::ops::Range;
+use std::ptr::{self, NonNull};
 use std::slice;
+use std::thread;
 
+use crate::allocator::{AllocError, Allocator};
 use crate::bitset::Bitset;
-use crate::packable::{PackedBox, Header};
-use crate::header::NodeChildrenType;
+use crate::packable::{Detached, PackedBox, Header};
+use crate::header::{NodeChildrenType, NodeHeader, COUNT_SIZE};
 use crate::node::{Node, NodeChildren};
+use crate::summary::Summary;
 
-pub struct PackedNode<T> {
-    pub(crate) ptr: Option<PackedBox<Node<T>>>,
+pub const SLOT_SIZE: usize = mem::size_of::<usize>();
+pub const SLOT_ALIGN: usize = mem::align_of::<usize>();
+
+// The byte of a slot holding its pointer's least significant bit.  Node allocations are aligned
+// to at least `PackedNode`, so this bit is always clear for boxed nodes, and we set it to tag
+// inline leaves.  The rest of the tag byte holds the inline leaf's suffix length.
+#[cfg(target_endian = "little")]
+const TAG_BYTE: usize = 0;
+#[cfg(target_endian = "big")]
+const TAG_BYTE: usize = SLOT_SIZE - 1;
+
+type NodeBox<T, A, S> = PackedBox<Node<T, A, S>, A>;
+
+#[repr(C)]
+union Slot<T, A, S: Summary<T>> {
+    boxed: ManuallyDrop<Option<NodeBox<T, A, S>>>,
+    inline: [MaybeUninit<u8>; SLOT_SIZE],
+    _align: [usize; 0],
 }
 
-impl<T> PackedNode<T> {
+// A child pointer that's either empty, points to a heap allocated node, or directly holds a leaf
+// whose value and key suffix are small enough to fit next to the tag byte.
+pub struct PackedNode<T, A, S: Summary<T> = ()> {
+    slot: Slot<T, A, S>,
+    // Summaries live in the node buffers, so this makes the node `Send` and `Sync` only if
+    // they are.
+    marker: PhantomData<S>,
+}
+
+impl<T, A, S: Summary<T>> PackedNode<T, A, S> {
     pub fn empty() -> Self {
-        Self { ptr: None }
+        Self {
+            slot: Slot {
+                boxed: ManuallyDrop::new(None),
+            },
+            marker: PhantomData,
+        }
     }
 
-    pub fn new(node: Node<T>) -> Self {
-        Self {
-            ptr: Some(PackedBox::new(node)),
+    // Where an inline leaf's key suffix and value live within the slot, or `None` if `T` is too
+    // large to be stored inline.  The value goes at the aligned end of the slot furthest from the
+    // tag byte, and the suffix fills the gap.
+    fn inline_layout() -> Option<(Range<usize>, usize)> {
+        let (size, align) = (mem::size_of::<T>(), mem::align_of::<T>());
+        if size >= SLOT_SIZE || align > SLOT_ALIGN {
+            return None;
+        }
+        if cfg!(target_endian = "little") {
+            let value_start = (SLOT_SIZE - size) / align * align;
+            Some((1..value_start, value_start))
+        } else {
+            Some((size..TAG_BYTE, 0))
         }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.ptr.is_none()
+    fn inline_parts(&self) -> Option<(Range<usize>, usize)> {
+        let layout = Self::inline_layout()?;
+        // NB: The tag byte is initialized for every variant: it's part of the pointer for boxed
+        // nodes, and we explicitly write it for inline ones.
+        let tag = unsafe { self.slot.inline[TAG_BYTE].assume_init() };
+        if tag & 1 == 0 {
+            return None;
+        }
+        let (suffix_range, value_start) = layout;
+        let suffix_len = (tag >> 1) as usize;
+        Some((suffix_range.start..(suffix_range.start + suffix_len), value_start))
     }
 
-    pub fn take(&mut self) -> Node<T> {
-        match self.ptr.take() {
-            None => Node {
-                prefix: vec![],
-                children: NodeChildren::Empty,
-                value: None,
-            },
-            Some(p) => p.unpack(),
+    fn inline_value_ptr(&self, value_start: usize) -> *const T {
+        unsafe { self.slot.inline.as_ptr().add(value_start).cast() }
+    }
+
+    fn boxed(&self) -> Option<&NodeBox<T, A, S>> {
+        if self.inline_parts().is_some() {
+            return None;
         }
+        unsafe { self.slot.boxed.as_ref() }
     }
 
-    pub fn set_value(&mut self, new_value: Option<T>) -> Option<T> {
-        let Node {
-            prefix,
-            children,
-            value: old_value,
-        } = self.take();
-        let new_node = Node::new(prefix, children, new_value);
-        *self = PackedNode::new(new_node);
-        old_value
+    fn boxed_mut(&mut self) -> Option<&mut NodeBox<T, A, S>> {
+        if self.inline_parts().is_some() {
+            return None;
+        }
+        let boxed: &mut Option<NodeBox<T, A, S>> = unsafe { &mut self.slot.boxed };
+        boxed.as_mut()
+    }
+
+    // The start of the node's buffer, or `None` for empty nodes and inline leaves.
+    pub fn buffer_ptr(&self) -> Option<NonNull<u8>> {
+        self.boxed().map(|p| NonNull::from(p.slice()).cast())
     }
 
-    pub fn add_child(&mut self, key: u8, child: Node<T>) {
-        let Node {
-            prefix,
-            children,
-            value,
-        } = self.take();
-        let mut pairs = children.into_pairs();
-        assert!(pairs.insert(key, PackedNode::new(child)).is_none());
-        let new_node = Node::new(prefix, NodeChildren::from_pairs(pairs), value);
-        *self = PackedNode::new(new_node);
+    // Make a second `PackedNode` that points at the same buffer.  This is only sound if the
+    // caller keeps track of how many owners the buffer has and frees it exactly once, like
+    // `PersistentTrie` does.
+    pub unsafe fn alias(&self) -> Self {
+        assert!(!self.is_inline(), "Inline leaves can't be aliased");
+        Self { slot: ptr::read(&self.slot), marker: PhantomData }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.inline_parts().is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.is_inline() && self.boxed().is_none()
     }
 
     pub fn prefix(&self) -> &[u8] {
-        match self.ptr {
+        if let Some((suffix_range, _)) = self.inline_parts() {
+            let suffix_ptr = unsafe { self.slot.inline.as_ptr().add(suffix_range.start).cast() };
+            return unsafe { slice::from_raw_parts(suffix_ptr, suffix_range.len()) };
+        }
+        match self.boxed() {
             None => &[],
-            Some(ref p) => &p.slice()[p.header().prefix_range()],
+            Some(p) => &p.slice()[p.header().prefix_range()],
         }
     }
 
+    // Key bytes after `prefix()` that this node skips without storing them.
+    pub fn skipped_len(&self) -> usize {
+        self.header().map(|h| h.skipped_len()).unwrap_or(0)
+    }
+
     pub fn has_value(&self) -> bool {
-        match self.ptr {
+        if self.is_inline() {
+            return true;
+        }
+        match self.boxed() {
             None => false,
-            Some(ref p) => p.header().value_range().is_some(),
+            Some(p) => p.header().value_range().is_some(),
+        }
+    }
+
+    // Number of values in this node's subtree, including its own.
+    pub fn len(&self) -> usize {
+        if self.is_inline() {
+            return 1;
+        }
+        let p = match self.boxed() {
+            None => return 0,
+            Some(p) => p,
+        };
+        let header = p.header();
+        let count_range = header.count_range();
+        if count_range.is_empty() {
+            return header.has_value() as usize;
+        }
+        let mut count_bytes = [0; COUNT_SIZE];
+        count_bytes.copy_from_slice(&p.slice()[count_range]);
+        usize::from_ne_bytes(count_bytes)
+    }
+
+    // Nodes store their subtree's count, so when a value gets added or removed below a node
+    // through `lookup_mut` rather than by rebuilding it, the node's count needs to be updated.
+    pub fn set_len(&mut self, len: usize) {
+        let p = self.boxed_mut().expect("Only nodes with children store a count");
+        let count_range = p.header().count_range();
+        assert_eq!(count_range.len(), COUNT_SIZE, "Only nodes with children store a count");
+        p.slice_mut()[count_range].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    // The summary slot of nodes with children, or `None` for leaves and empty nodes, and for
+    // every node when `S` takes no space.  The slot is empty until `update_summary` fills it in.
+    pub fn stored_summary(&self) -> Option<&Option<S>> {
+        let p = self.boxed()?;
+        let header = p.header();
+        if header.summary_range().is_empty() {
+            return None;
+        }
+        Some(unsafe { &*p.slice()[header.summary_range()].as_ptr().cast() })
+    }
+
+    pub fn stored_summary_mut(&mut self) -> Option<&mut Option<S>> {
+        let p = self.boxed_mut()?;
+        let header = p.header();
+        if header.summary_range().is_empty() {
+            return None;
         }
+        Some(unsafe { &mut *p.slice_mut()[header.summary_range()].as_mut_ptr().cast() })
     }
 
     pub fn value(&self) -> Option<&T> {
-        match self.ptr {
+        if let Some((_, value_start)) = self.inline_parts() {
+            return Some(unsafe { &*self.inline_value_ptr(value_start) });
+        }
+        match self.boxed() {
             None => None,
-            Some(ref p) => {
+            Some(p) => {
                 let header = p.header();
                 let value_buf = &p.slice()[header.value_range()?];
                 Some(unsafe { &*value_buf.as_ptr().cast() })
@@ -84,69 +208,128 @@ impl<T> PackedNode<T> {
         }
     }
 
-    pub fn lookup_mut(&mut self, byte: u8) -> Option<&mut PackedNode<T>> {
-        self.lookup(byte)
-            .map(|r| unsafe { &mut *(r as *const _ as *mut _) })
+    pub fn value_mut(&mut self) -> Option<&mut T> {
+        if let Some((_, value_start)) = self.inline_parts() {
+            let value_ptr = unsafe { self.slot.inline.as_mut_ptr().add(value_start).cast::<T>() };
+            return Some(unsafe { &mut *value_ptr });
+        }
+        let p = self.boxed_mut()?;
+        let value_range = p.header().value_range()?;
+        let value_buf = &mut p.slice_mut()[value_range];
+        Some(unsafe { &mut *value_buf.as_mut_ptr().cast() })
     }
 
-    pub fn lookup(&self, byte: u8) -> Option<&PackedNode<T>> {
-        let ptr = match self.ptr {
-            None => return None,
-            Some(ref p) => p,
-        };
-        let header = ptr.header();
-        let children_buf = &ptr.slice()[header.children_range()];
-        match header.children_type() {
-            NodeChildrenType::Empty => None,
-            NodeChildrenType::Pairs => {
-                let n = header.num_children();
-                let values_len = n * mem::size_of::<PackedNode<T>>();
-                let values_slice = &children_buf[n..][..values_len];
-                let values: &[PackedNode<T>] = unsafe {
-                    slice::from_raw_parts(values_slice.as_ptr().cast(), n)
-                };
-                for (i, &k) in children_buf[..n].iter().enumerate() {
-                    if k == byte {
-                        return Some(&values[i]);
-                    }
-                }
-                None
+    // The topmost node at or below this one whose subtree holds every key starting with
+    // `key_prefix`, along with how many bytes of `key_prefix` lead up to that node's own prefix.
+    pub fn find_prefix(&self, key_prefix: &[u8]) -> Option<(&PackedNode<T, A, S>, usize)> {
+        let mut node = self;
+        let mut depth = 0;
+        loop {
+            if node.is_empty() {
+                return None;
             }
-            NodeChildrenType::Sparse => {
-                let bitset_len = mem::size_of::<Bitset>();
-                let bitset: &Bitset = unsafe { &*children_buf[..bitset_len].as_ptr().cast() };
-                let values_len = header.num_children() * mem::size_of::<PackedNode<T>>();
-                let values_slice = &children_buf[bitset_len..][..values_len];
-                let values: &[PackedNode<T>] = unsafe {
-                    slice::from_raw_parts(values_slice.as_ptr().cast(), header.num_children())
-                };
-                let rank = bitset.query(byte)?;
-                Some(&values[rank])
+            let rest = &key_prefix[depth..];
+            let prefix = node.prefix();
+            if prefix.starts_with(rest) {
+                return Some((node, depth));
             }
-            NodeChildrenType::Dense => {
-                let table_len = mem::size_of::<[PackedNode<T>; 256]>();
-                let table: &[PackedNode<T>; 256] =
-                    unsafe { &*children_buf[..table_len].as_ptr().cast() };
-                Some(&table[byte as usize])
+            if !rest.starts_with(prefix) {
+                return None;
             }
+            depth += prefix.len();
+            node = node.lookup(key_prefix[depth])?;
+            depth += 1;
         }
     }
 
+    // Every child slot, including the empty ones of Dense nodes.
+    pub fn slots_mut(&mut self) -> &mut [PackedNode<T, A, S>] {
+        match self.child_index_mut() {
+            Some((_, slots)) => slots,
+            None => &mut [],
+        }
+    }
+
+    pub fn lookup_mut(&mut self, byte: u8) -> Option<&mut PackedNode<T, A, S>> {
+        let (index, slots) = self.child_index_mut()?;
+        Some(&mut slots[index.slot(byte)?])
+    }
+
+    pub fn lookup(&self, byte: u8) -> Option<&PackedNode<T, A, S>> {
+        let (index, slots) = self.child_index()?;
+        Some(&slots[index.slot(byte)?])
+    }
+
+    // Iterate over the nonempty children in order of their branch bytes.
+    pub fn children(&self) -> Children<'_, T, A, S> {
+        let (index, slots) = match self.child_index() {
+            Some(parts) => parts,
+            None => (ChildIndex::Pairs(&[]), &[][..]),
+        };
+        Children { index, slots, byte: 0, slot: 0 }
+    }
+
+    pub fn header(&self) -> Option<NodeHeader<T, S>> {
+        self.boxed().map(|p| p.header())
+    }
+
+    // The number of children the node's index lists, which is the header's child count unless
+    // the node is corrupt.  Unlike `children`, this doesn't trust the index to fit the slots.
+    pub fn indexed_children(&self) -> usize {
+        match self.child_index() {
+            None => 0,
+            Some((ChildIndex::Pairs(keys), _)) => keys.len(),
+            Some((ChildIndex::Sparse(bitset), _)) => bitset.iter().count(),
+            Some((ChildIndex::Dense, slots)) => slots.iter().filter(|c| !c.is_empty()).count(),
+        }
+    }
+
+    // The raw bytes of the node's child index, for tests that corrupt it.
+    #[cfg(test)]
+    pub fn index_bytes_mut(&mut self) -> &mut [u8] {
+        let p = self.boxed_mut().expect("Only nodes with children have an index");
+        let header = p.header();
+        let index_start = header.children_range().start;
+        &mut p.slice_mut()[index_start..(index_start + header.index_len())]
+    }
+
+    fn child_index(&self) -> Option<(ChildIndex<'_>, &[PackedNode<T, A, S>])> {
+        let ptr = self.boxed()?;
+        let header = ptr.header();
+        let buf = ptr.slice();
+        let index = ChildIndex::new(header, buf)?;
+        let slots_buf = &buf[header.slots_range()];
+        let slots: &[PackedNode<T, A, S>] = unsafe {
+            slice::from_raw_parts(slots_buf.as_ptr().cast(), slots_buf.len() / SLOT_SIZE)
+        };
+        Some((index, slots))
+    }
+
+    fn child_index_mut(&mut self) -> Option<(ChildIndex<'_>, &mut [PackedNode<T, A, S>])> {
+        let ptr = self.boxed_mut()?;
+        let header = ptr.header();
+        let slots_range = header.slots_range();
+        // The index comes before the slots, so we can borrow them separately.
+        let (index_buf, slots_buf) = ptr.slice_mut().split_at_mut(slots_range.start);
+        let index = ChildIndex::new(header, index_buf)?;
+        let slots: &mut [PackedNode<T, A, S>] = unsafe {
+            slice::from_raw_parts_mut(slots_buf.as_mut_ptr().cast(), slots_range.len() / SLOT_SIZE)
+        };
+        Some((index, slots))
+    }
+
     pub fn debug(&self, indent: &str, out: &mut impl std::io::Write) -> Result<(), std::io::Error> {
         let num_children = self
-            .ptr
-            .as_ref()
+            .boxed()
             .map(|p| p.header().num_children())
             .unwrap_or(0);
         let children_type = self
-            .ptr
-            .as_ref()
+            .boxed()
             .map(|p| p.header().children_type())
-            .unwrap_or(NodeChildrenType::Pairs);
+            .unwrap_or(NodeChildrenType::Empty);
 
         let heap_usage = self
-            .ptr
-            .as_ref()
+            .boxed()
             .map(|p| p.header().layout().size())
             .unwrap_or(0);
         write!(
@@ -179,3 +362,291 @@ impl<T> PackedNode<T> {
         Ok(())
     }
 }
+
+// How a node maps branch bytes to its child slots.
+enum ChildIndex<'a> {
+    Pairs(&'a [u8]),
+    Sparse(&'a Bitset),
+    Dense,
+}
+
+impl<'a> ChildIndex<'a> {
+    // Read the index out of a node's buffer, which only needs to extend up to its child slots.
+    fn new<T, S>(header: NodeHeader<T, S>, buf: &'a [u8]) -> Option<Self> {
+        let index_start = header.children_range().start;
+        let index = match header.children_type() {
+            NodeChildrenType::Empty => return None,
+            NodeChildrenType::Pairs => {
+                ChildIndex::Pairs(&buf[index_start..(index_start + header.num_children())])
+            }
+            NodeChildrenType::Sparse => {
+                let bitset_len = mem::size_of::<Bitset>();
+                let bitset_buf = &buf[index_start..(index_start + bitset_len)];
+                ChildIndex::Sparse(unsafe { &*bitset_buf.as_ptr().cast() })
+            }
+            NodeChildrenType::Dense => ChildIndex::Dense,
+        };
+        Some(index)
+    }
+
+    fn slot(&self, byte: u8) -> Option<usize> {
+        match self {
+            ChildIndex::Pairs(keys) => keys.iter().position(|&k| k == byte),
+            ChildIndex::Sparse(bitset) => bitset.query(byte),
+            ChildIndex::Dense => Some(byte as usize),
+        }
+    }
+}
+
+pub struct Children<'a, T, A, S: Summary<T> = ()> {
+    index: ChildIndex<'a>,
+    slots: &'a [PackedNode<T, A, S>],
+    byte: usize,
+    slot: usize,
+}
+
+impl<'a, T, A, S: Summary<T>> Iterator for Children<'a, T, A, S> {
+    type Item = (u8, &'a PackedNode<T, A, S>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.index {
+            ChildIndex::Pairs(keys) => {
+                let i = self.slot;
+                self.slot += 1;
+                Some((*keys.get(i)?, &self.slots[i]))
+            }
+            ChildIndex::Sparse(bitset) => {
+                while self.byte < 256 {
+                    let byte = self.byte as u8;
+                    self.byte += 1;
+                    if bitset.contains(byte) {
+                        self.slot += 1;
+                        return Some((byte, &self.slots[self.slot - 1]));
+                    }
+                }
+                None
+            }
+            ChildIndex::Dense => {
+                while self.byte < 256 {
+                    let child = &self.slots[self.byte];
+                    self.byte += 1;
+                    if !child.is_empty() {
+                        return Some(((self.byte - 1) as u8, child));
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<T, A: Allocator, S: Summary<T>> PackedNode<T, A, S> {
+    pub fn new(node: Node<T, A, S>, alloc: &A) -> Self {
+        if Self::fits_inline(&node) {
+            return Self::new_inline(node);
+        }
+        Self {
+            slot: Slot {
+                boxed: ManuallyDrop::new(Some(PackedBox::new(node, alloc))),
+            },
+            marker: PhantomData,
+        }
+    }
+
+    // Like `new`, but hands `node` back if its buffer can't be allocated.  Nodes with a Dense
+    // table make for a large error, but they're moved around by value everywhere else too.
+    #[allow(clippy::result_large_err)]
+    pub fn try_new(node: Node<T, A, S>, alloc: &A) -> Result<Self, (Node<T, A, S>, AllocError)> {
+        if Self::fits_inline(&node) {
+            return Ok(Self::new_inline(node));
+        }
+        let boxed = PackedBox::try_new(node, alloc)?;
+        let slot = Slot { boxed: ManuallyDrop::new(Some(boxed)) };
+        Ok(Self { slot, marker: PhantomData })
+    }
+
+    fn fits_inline(node: &Node<T, A, S>) -> bool {
+        match (Self::inline_layout(), &node.children, &node.value) {
+            (Some((suffix_range, _)), NodeChildren::Empty, Some(..)) => {
+                node.prefix.len() <= suffix_range.len() && node.skipped_len == 0
+            }
+            _ => false,
+        }
+    }
+
+    fn new_inline(node: Node<T, A, S>) -> Self {
+        let (suffix_range, value_start) = Self::inline_layout().unwrap();
+        let Node { prefix, value, .. } = node;
+        let mut inline = [MaybeUninit::uninit(); SLOT_SIZE];
+        inline[TAG_BYTE] = MaybeUninit::new(1 | (prefix.len() as u8) << 1);
+        for (dst, &byte) in inline[suffix_range].iter_mut().zip(prefix.iter()) {
+            *dst = MaybeUninit::new(byte);
+        }
+        let mut slot = Slot { inline };
+        unsafe {
+            let value_ptr = slot.inline.as_mut_ptr().add(value_start).cast::<T>();
+            value_ptr.write(value.unwrap());
+        }
+        Self { slot, marker: PhantomData }
+    }
+
+    pub fn take(&mut self, alloc: &A) -> Node<T, A, S> {
+        // Drop the summary while the node is still in one piece, in case its `Drop` panics.
+        self.clear_summary();
+        let mut taken = ManuallyDrop::new(mem::replace(self, PackedNode::empty()));
+        if let Some((_, value_start)) = taken.inline_parts() {
+            let prefix = taken.prefix().to_owned();
+            let value = unsafe { ptr::read(taken.inline_value_ptr(value_start)) };
+            return Node {
+                prefix,
+                skipped_len: 0,
+                children: NodeChildren::Empty,
+                value: Some(value),
+            };
+        }
+        match unsafe { ManuallyDrop::take(&mut taken.slot.boxed) } {
+            None => Node {
+                prefix: vec![],
+                skipped_len: 0,
+                children: NodeChildren::Empty,
+                value: None,
+            },
+            Some(p) => p.unpack(alloc),
+        }
+    }
+
+    // Like `take`, but keep the node's buffer allocated until the returned `DetachedNode` is
+    // freed.  If rebuilding the node fails, `DetachedNode::restore` can then put it back the way
+    // it was without allocating.
+    pub fn take_detached(&mut self, alloc: &A) -> (Node<T, A, S>, DetachedNode<T, A, S>) {
+        self.clear_summary();
+        if self.boxed().is_none() {
+            // Inline leaves and empty nodes don't have a buffer to keep.
+            return (self.take(alloc), DetachedNode { buf: None, marker: PhantomData });
+        }
+        let mut taken = ManuallyDrop::new(mem::replace(self, PackedNode::empty()));
+        let boxed = unsafe { ManuallyDrop::take(&mut taken.slot.boxed) }.unwrap();
+        let (node, buf) = boxed.unpack_detached();
+        (node, DetachedNode { buf: Some(buf), marker: PhantomData })
+    }
+
+    pub fn set_value(&mut self, new_value: Option<T>, alloc: &A) -> Option<T> {
+        let mut node = self.take(alloc);
+        let old_value = mem::replace(&mut node.value, new_value);
+        *self = PackedNode::new(node, alloc);
+        old_value
+    }
+
+    pub fn add_child(&mut self, key: u8, child: Node<T, A, S>, alloc: &A) {
+        let mut node = self.take(alloc);
+        let mut pairs = mem::replace(&mut node.children, NodeChildren::Empty).into_pairs();
+        assert!(pairs.insert(key, PackedNode::new(child, alloc)).is_none());
+        node.children = NodeChildren::from_pairs(pairs);
+        *self = PackedNode::new(node, alloc);
+    }
+
+    // Dense nodes' children are filled in place through `lookup_mut`, so the header's count needs
+    // to be bumped afterwards.
+    pub fn increment_dense_children(&mut self) {
+        let p = match self.boxed_mut() {
+            Some(p) => p,
+            None => return,
+        };
+        let header = p.header();
+        assert_eq!(header.children_type(), NodeChildrenType::Dense);
+        let new_header = NodeHeader::<T, S>::new(
+            header.prefix_len(),
+            header.num_children() + 1,
+            header.has_value(),
+        )
+        .with_skipped_len(header.skipped_len());
+        new_header.write(&mut p.slice_mut()[header.header_range()]);
+    }
+
+    // Free this node and everything below it.  Nodes don't know their allocator, so they must
+    // be released through here rather than dropped.
+    pub fn drop_in(&mut self, alloc: &A) {
+        if self.is_empty() {
+            return;
+        }
+        let Node { children, value, .. } = self.take(alloc);
+        match children {
+            NodeChildren::Empty => (),
+            NodeChildren::Pairs { mut values, .. } | NodeChildren::Sparse { mut values, .. } => {
+                free_each(&mut values, |child| child.drop_in(alloc));
+            }
+            NodeChildren::Dense { mut table } => {
+                free_each(&mut table, |child| child.drop_in(alloc));
+            }
+        }
+        drop(value);
+    }
+}
+
+// Free each of `nodes` with `free`.  If that panics, because a value's `Drop` did, keep freeing
+// the rest of them while unwinding rather than leaking them, like dropping a `Vec` does.
+pub fn free_each<T, A, S: Summary<T>>(
+    nodes: &mut [PackedNode<T, A, S>],
+    free: impl FnMut(&mut PackedNode<T, A, S>),
+) {
+    struct FreeEach<'a, T, A, S: Summary<T>, F: FnMut(&mut PackedNode<T, A, S>)> {
+        nodes: &'a mut [PackedNode<T, A, S>],
+        free: F,
+    }
+
+    impl<T, A, S: Summary<T>, F: FnMut(&mut PackedNode<T, A, S>)> FreeEach<'_, T, A, S, F> {
+        fn run(&mut self) {
+            while let Some((node, rest)) = mem::take(&mut self.nodes).split_first_mut() {
+                self.nodes = rest;
+                (self.free)(node);
+            }
+        }
+    }
+
+    impl<T, A, S: Summary<T>, F: FnMut(&mut PackedNode<T, A, S>)> Drop
+        for FreeEach<'_, T, A, S, F>
+    {
+        fn drop(&mut self) {
+            self.run();
+        }
+    }
+
+    FreeEach { nodes, free }.run();
+}
+
+// The buffer of a node taken with `take_detached`.
+pub struct DetachedNode<T, A, S = ()> {
+    buf: Option<Detached<A>>,
+    marker: PhantomData<(T, S)>,
+}
+
+impl<T, A: Allocator, S: Summary<T>> DetachedNode<T, A, S> {
+    pub fn free(self, alloc: &A) {
+        if let Some(buf) = self.buf {
+            buf.free(alloc);
+        }
+    }
+
+    // Put `node`, which must be the one `take_detached` returned, back into its buffer.
+    pub fn restore(self, node: Node<T, A, S>, alloc: &A) -> PackedNode<T, A, S> {
+        match self.buf {
+            Some(buf) => {
+                let boxed = PackedBox::repack(node, buf);
+                let slot = Slot { boxed: ManuallyDrop::new(Some(boxed)) };
+                PackedNode { slot, marker: PhantomData }
+            }
+            None => {
+                // The node was an inline leaf, so this doesn't allocate.
+                let restored = PackedNode::new(node, alloc);
+                debug_assert!(restored.is_inline());
+                restored
+            }
+        }
+    }
+}
+
+impl<T, A, S: Summary<T>> Drop for PackedNode<T, A, S> {
+    fn drop(&mut self) {
+        assert!(self.is_empty() || thread::panicking(), "Leaked a node without `drop_in`");
+    }
+}
@@ -0,0 +1,493 @@
+// `PersistentTrie` shares unchanged subtrees between versions of a trie, so taking a snapshot
+// only costs a reference count increment on the root.
+//
+// Every node buffer carries a reference count, which the `Refcounted` allocator keeps just in
+// front of the buffer.  A count above one means another version can reach the node, so writers
+// treat it as frozen: like `ConcurrentTrie`, they copy the path from the root down to the change,
+// share the copied node's children with the original, and drop their own reference to it.  Nodes
+// that only this version can reach are taken apart as usual, so a trie without any live snapshots
+// doesn't copy anything that `Trie` wouldn't.  Inline leaves don't have a buffer to count, so
+// they're cloned instead.
+
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr::NonNull;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::allocator::Allocator;
+use crate::invariants::{self, InvariantViolation};
+use crate::iter::TreeIterator;
+use crate::node::{Node, NodeChildren};
+use crate::packed_node::{free_each, PackedNode};
+use crate::prefix;
+
+const COUNT_SIZE: usize = mem::size_of::<AtomicUsize>();
+
+// Allocates from the system allocator with room for a reference count before each buffer.
+// Counts start at one.
+struct Refcounted;
+
+impl Refcounted {
+    // The layout of the whole allocation, and the buffer's offset into it.  The offset is a
+    // multiple of the buffer's alignment, so the buffer stays aligned.
+    fn extended(layout: Layout) -> (Layout, usize) {
+        let offset = cmp::max(layout.align(), COUNT_SIZE);
+        let align = cmp::max(layout.align(), mem::align_of::<AtomicUsize>());
+        let extended = Layout::from_size_align(offset + layout.size(), align)
+            .unwrap_or_else(|_| panic!("Invalid layout for {:?}", layout));
+        (extended, offset)
+    }
+}
+
+unsafe impl Allocator for Refcounted {
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let (extended, offset) = Self::extended(layout);
+        unsafe {
+            let base = NonNull::new(alloc::alloc_zeroed(extended))?;
+            let ptr = base.as_ptr().add(offset);
+            ptr.sub(COUNT_SIZE).cast::<AtomicUsize>().write(AtomicUsize::new(1));
+            Some(NonNull::new_unchecked(ptr))
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        let (extended, offset) = Self::extended(layout);
+        alloc::dealloc(ptr.as_ptr().sub(offset), extended)
+    }
+}
+
+fn refcount<T>(node: &PackedNode<T, Refcounted>) -> Option<&AtomicUsize> {
+    let ptr = node.buffer_ptr()?;
+    Some(unsafe { &*ptr.as_ptr().sub(COUNT_SIZE).cast() })
+}
+
+fn is_shared<T>(node: &PackedNode<T, Refcounted>) -> bool {
+    refcount(node).is_some_and(|count| count.load(Ordering::Acquire) != 1)
+}
+
+// Add an owner to `node`.
+fn share<T: Clone>(node: &PackedNode<T, Refcounted>) -> PackedNode<T, Refcounted> {
+    if let Some(count) = refcount(node) {
+        count.fetch_add(1, Ordering::Relaxed);
+        return unsafe { node.alias() };
+    }
+    if node.is_empty() {
+        return PackedNode::empty();
+    }
+    let prefix = node.prefix().to_owned();
+    let leaf = Node::new(prefix, NodeChildren::Empty, node.value().cloned(), &Refcounted);
+    PackedNode::new(leaf, &Refcounted)
+}
+
+// Drop this owner's reference to `node`, and free it if that was the last one.  This leaves
+// `node` empty either way.
+fn release<T>(node: &mut PackedNode<T, Refcounted>) {
+    if let Some(count) = refcount(node) {
+        if count.fetch_sub(1, Ordering::Release) != 1 {
+            mem::forget(mem::replace(node, PackedNode::empty()));
+            return;
+        }
+        // Make sure other owners are done with the node before we free it.
+        atomic::fence(Ordering::Acquire);
+    }
+    match node.take(&Refcounted).children {
+        NodeChildren::Empty => (),
+        NodeChildren::Pairs { mut values, .. } | NodeChildren::Sparse { mut values, .. } => {
+            free_each(&mut values, release);
+        }
+        NodeChildren::Dense { mut table } => free_each(&mut table, release),
+    }
+}
+
+// References taken with `share` that get released again if we unwind before using them.
+struct Shared<T>(BTreeMap<u8, PackedNode<T, Refcounted>>);
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        for (_, mut node) in mem::take(&mut self.0) {
+            release(&mut node);
+        }
+    }
+}
+
+// Take apart `node` so it can be rebuilt, copying it instead if other versions can reach it.
+fn unshare<T: Clone>(node: &mut PackedNode<T, Refcounted>) -> Node<T, Refcounted> {
+    if !is_shared(node) {
+        return node.take(&Refcounted);
+    }
+    let mut children = Shared(BTreeMap::new());
+    for (byte, child) in node.children() {
+        children.0.insert(byte, share(child));
+    }
+    let value = node.value().cloned();
+    let children = NodeChildren::from_pairs(mem::take(&mut children.0));
+    let copy = Node::new(node.prefix().to_owned(), children, value, &Refcounted);
+    release(node);
+    copy
+}
+
+fn make_unique<T: Clone>(node: &mut PackedNode<T, Refcounted>) {
+    if is_shared(node) {
+        let copy = unshare(node);
+        *node = PackedNode::new(copy, &Refcounted);
+    }
+}
+
+// Copy the shared nodes that inserting or removing `key` will take apart, from the root down.
+// Copying clones values, which can panic, so writes do this before taking anything apart.  Each
+// copy replaces its original as a whole, so the trie is still intact if we unwind partway.
+fn make_path_unique<T: Clone>(
+    mut node: &mut PackedNode<T, Refcounted>,
+    mut key: &[u8],
+    removing: bool,
+) {
+    loop {
+        make_unique(node);
+        if !prefix::starts_with(key, node.prefix()) {
+            return;
+        }
+        let (&branch_byte, rest) = match key[node.prefix().len()..].split_first() {
+            Some(parts) => parts,
+            None => {
+                // Removing our value merges us with an only child.
+                if removing && node.children().count() == 1 {
+                    let (byte, _) = node.children().next().unwrap();
+                    make_unique(node.lookup_mut(byte).unwrap());
+                }
+                return;
+            }
+        };
+        let is_leaf = |child: &PackedNode<T, Refcounted>| {
+            child.prefix() == rest && child.children().next().is_none()
+        };
+        let merges = !node.has_value() && node.children().count() == 2;
+        if removing && merges && node.lookup(branch_byte).is_some_and(is_leaf) {
+            // Unlinking the leaf merges us with our other child.
+            let (byte, _) = node.children().find(|&(byte, _)| byte != branch_byte).unwrap();
+            make_unique(node.lookup_mut(byte).unwrap());
+        }
+        node = match node.lookup_mut(branch_byte) {
+            Some(child) => child,
+            None => return,
+        };
+        key = rest;
+    }
+}
+
+/// A trie with cheap snapshots, for readers that want a consistent view of the trie while it
+/// keeps changing.  `snapshot` is O(1), and a snapshot and the trie it was taken from share every
+/// node that neither has modified since.  Values are cloned when a write needs its own copy of a
+/// shared node.
+pub struct PersistentTrie<T> {
+    root: PackedNode<T, Refcounted>,
+    // Versions share their values, so sending one to another thread needs the same bounds as
+    // sending an `Arc`.
+    marker: PhantomData<Arc<T>>,
+}
+
+impl<T: Clone> PersistentTrie<T> {
+    pub fn new() -> Self {
+        Self {
+            root: PackedNode::empty(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Take an immutable view of the trie as it is now.  The snapshot is itself a
+    /// `PersistentTrie`, so it can be modified independently too.
+    pub fn snapshot(&self) -> Self {
+        Self {
+            root: share(&self.root),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&T> {
+        let mut cur = &self.root;
+        let mut key = key;
+        loop {
+            let node_prefix = cur.prefix();
+            if !prefix::starts_with(key, node_prefix) {
+                return None;
+            }
+            let (&branch_byte, rest) = match key[node_prefix.len()..].split_first() {
+                None => return cur.value(),
+                Some(p) => p,
+            };
+            key = rest;
+            cur = cur.lookup(branch_byte)?;
+        }
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: T) -> Option<T> {
+        make_path_unique(&mut self.root, key, false);
+        let old_value = insert(&mut self.root, key, value);
+        self.debug_check_invariants();
+        old_value
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<T> {
+        // Don't copy any shared nodes if there's nothing to remove.
+        if !self.contains_key(key) {
+            return None;
+        }
+        make_path_unique(&mut self.root, key, true);
+        let value = remove(&mut self.root, key);
+        self.debug_check_invariants();
+        value
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, &T)> {
+        TreeIterator::new(&self.root)
+    }
+
+    /// See `Trie::check_invariants`.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        invariants::check_node(&self.root, &mut vec![])
+    }
+
+    #[inline]
+    fn debug_check_invariants(&self) {
+        invariants::debug_check(|| self.check_invariants());
+    }
+
+    pub fn debug(&self, out: &mut impl io::Write) -> io::Result<()> {
+        self.root.debug("", out)
+    }
+}
+
+impl<T: Clone> Clone for PersistentTrie<T> {
+    fn clone(&self) -> Self {
+        self.snapshot()
+    }
+}
+
+impl<T: Clone> Default for PersistentTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for PersistentTrie<T> {
+    fn drop(&mut self) {
+        release(&mut self.root);
+    }
+}
+
+// These follow the path copying versions in `concurrent.rs`, but only copy the nodes that are
+// shared with another version.
+
+fn insert<T: Clone>(node: &mut PackedNode<T, Refcounted>, key: &[u8], value: T) -> Option<T> {
+    let alloc = &Refcounted;
+    if node.is_empty() {
+        let new_node = Node::new(key.to_owned(), NodeChildren::Empty, Some(value), alloc);
+        *node = PackedNode::new(new_node, alloc);
+        return None;
+    }
+    let prefix_len = node.prefix().len();
+    if let Some(i) = prefix::mismatch(node.prefix(), key) {
+        make_unique(node);
+        match key.get(i) {
+            Some(&key_byte) => node.branch_prefix(i, key_byte, &key[(i + 1)..], value, alloc),
+            None => node.split_prefix(i, value, alloc),
+        }
+        return None;
+    }
+    let mut copy = unshare(node);
+    let old_value = match key.get(prefix_len) {
+        None => copy.value.replace(value),
+        Some(&branch_byte) => match copy.children.get_mut(branch_byte) {
+            Some(child) => insert(child, &key[(prefix_len + 1)..], value),
+            None => {
+                let child_key = key[(prefix_len + 1)..].to_owned();
+                let new_child = Node::new(child_key, NodeChildren::Empty, Some(value), alloc);
+                let children = mem::replace(&mut copy.children, NodeChildren::Empty);
+                let mut pairs = children.into_pairs();
+                pairs.insert(branch_byte, PackedNode::new(new_child, alloc));
+                copy.children = NodeChildren::from_pairs(pairs);
+                None
+            }
+        },
+    };
+    *node = PackedNode::new(copy, alloc);
+    old_value
+}
+
+// Callers must check that `key` is present first, since this copies the path either way.
+fn remove<T: Clone>(node: &mut PackedNode<T, Refcounted>, key: &[u8]) -> Option<T> {
+    let alloc = &Refcounted;
+    let prefix_len = node.prefix().len();
+    let mut copy = unshare(node);
+    let removed_value = match key.get(prefix_len) {
+        None => copy.value.take()?,
+        Some(&branch_byte) => {
+            let child = copy.children.get_mut(branch_byte)?;
+            let removed_value = remove(child, &key[(prefix_len + 1)..])?;
+            if !child.is_empty() {
+                *node = PackedNode::new(copy, alloc);
+                return Some(removed_value);
+            }
+            removed_value
+        }
+    };
+
+    // Patch up the invariants described in `remove.rs`.
+    let Node { mut prefix, children, value, .. } = copy;
+    let pairs = children.into_pairs();
+    match (value.is_some(), pairs.len()) {
+        // Leave ourselves as empty to let the parent cleanup.
+        (false, 0) => (),
+        (false, 1) => {
+            let (child_byte, mut packed_child) = pairs.into_iter().next().unwrap();
+            let child = unshare(&mut packed_child);
+            prefix.push(child_byte);
+            prefix.extend_from_slice(child.prefix());
+            let new_node = Node::new(prefix, child.children, child.value, alloc);
+            *node = PackedNode::new(new_node, alloc);
+        }
+        _ => {
+            let new_node = Node::new(prefix, NodeChildren::from_pairs(pairs), value, alloc);
+            *node = PackedNode::new(new_node, alloc);
+        }
+    }
+    Some(removed_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PersistentTrie;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key/{}/{}", i % 7, i).into_bytes()
+    }
+
+    // Keep snapshots of a trie and a model of it as it changes, and check that every snapshot
+    // still matches the model from when it was taken.  The values are `Arc`s so we can tell if
+    // any of them leaked.
+    #[test]
+    fn test_persistent_snapshots() {
+        let values = (0..1000).map(Arc::new).collect::<Vec<_>>();
+        {
+            let mut t = PersistentTrie::new();
+            let mut model = BTreeMap::new();
+            let mut snapshots = vec![];
+            let mut rng = StdRng::seed_from_u64(0);
+            for n in 0..5000 {
+                let i = rng.gen_range(0, 1000);
+                if rng.gen_bool(0.3) {
+                    assert_eq!(t.remove(&key(i)), model.remove(&key(i)));
+                } else {
+                    let value = values[i as usize].clone();
+                    assert_eq!(t.insert(&key(i), value.clone()), model.insert(key(i), value));
+                }
+                if n % 250 == 0 {
+                    snapshots.push((t.snapshot(), model.clone()));
+                }
+                if n % 1000 == 0 {
+                    // Modifying a snapshot mustn't affect the trie it was taken from.
+                    let index = rng.gen_range(0, snapshots.len());
+                    let (s, s_model) = &mut snapshots[index];
+                    for j in 0..50 {
+                        let value = values[j as usize].clone();
+                        assert_eq!(s.insert(&key(j), value.clone()), s_model.insert(key(j), value));
+                    }
+                    snapshots.truncate(snapshots.len() / 2 + 1);
+                }
+            }
+            snapshots.push((t, model));
+            for (s, model) in &snapshots {
+                assert_eq!(s.check_invariants(), Ok(()));
+                assert!(s.iter().map(|(k, v)| (k, v.clone())).eq(model.clone().into_iter()));
+                for i in 0..1000 {
+                    assert_eq!(s.get(&key(i)), model.get(&key(i)));
+                }
+            }
+        }
+        assert!(values.iter().all(|v| Arc::strong_count(v) == 1));
+    }
+
+    #[test]
+    fn test_persistent_sharing() {
+        let mut t = PersistentTrie::new();
+        for i in 0..100u32 {
+            t.insert(format!("a{}", i).as_bytes(), i);
+            t.insert(format!("b{}", i).as_bytes(), i);
+        }
+        let s = t.snapshot();
+        assert_eq!(t.root.buffer_ptr(), s.root.buffer_ptr());
+        t.insert(b"b1000", 1000);
+        t.remove(b"b5");
+
+        // Only the path down to the "b" subtree was copied.
+        assert_ne!(t.root.buffer_ptr(), s.root.buffer_ptr());
+        let subtree_ptr = |t: &PersistentTrie<u32>, byte| t.root.lookup(byte).unwrap().buffer_ptr();
+        assert_eq!(subtree_ptr(&t, b'a'), subtree_ptr(&s, b'a'));
+        assert_ne!(subtree_ptr(&t, b'b'), subtree_ptr(&s, b'b'));
+        assert_eq!((t.get(b"b1000"), s.get(b"b1000")), (Some(&1000), None));
+        assert_eq!((t.get(b"b5"), s.get(b"b5")), (None, Some(&5)));
+
+        // Snapshots can be read from other threads while the trie keeps changing.
+        let reader = thread::spawn(move || s.iter().count());
+        for i in 0..100u32 {
+            t.remove(format!("a{}", i).as_bytes());
+        }
+        assert_eq!(reader.join().unwrap(), 200);
+        assert_eq!(t.iter().count(), 100);
+    }
+
+    #[test]
+    fn test_persistent_panicking_clone() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        #[derive(Debug)]
+        struct PanicOnClone(u32, Arc<()>);
+        impl Clone for PanicOnClone {
+            fn clone(&self) -> Self {
+                assert_ne!(self.0, 0, "cloning {}", self.0);
+                PanicOnClone(self.0, self.1.clone())
+            }
+        }
+
+        let live = Arc::new(());
+        {
+            let mut t = PersistentTrie::new();
+            for i in 0..100 {
+                t.insert(&key(i), PanicOnClone(i + 1, live.clone()));
+            }
+            t.insert(b"key/5/", PanicOnClone(0, live.clone()));
+            let s = t.snapshot();
+
+            // Copying the path to these keys has to clone the value at "key/5/", which panics
+            // after the nodes above it have been copied.
+            let value = PanicOnClone(1000, live.clone());
+            let insert = AssertUnwindSafe(|| t.insert(b"key/5/1000", value));
+            assert!(panic::catch_unwind(insert).is_err());
+            assert!(panic::catch_unwind(AssertUnwindSafe(|| t.remove(&key(5)))).is_err());
+            for t in [&t, &s] {
+                assert_eq!(t.check_invariants(), Ok(()));
+                assert_eq!(t.iter().count(), 101);
+                assert!((0..100).all(|i| t.get(&key(i)).map(|v| v.0) == Some(i + 1)));
+            }
+
+            // Once the snapshot's gone, nothing needs to be cloned.
+            drop(s);
+            assert_eq!(t.remove(&key(5)).map(|v| v.0), Some(6));
+            assert!(t.insert(b"key/5/1000", PanicOnClone(1000, live.clone())).is_none());
+        }
+        assert_eq!(Arc::strong_count(&live), 1);
+    }
+}
@@ -0,0 +1,136 @@
+use std::cmp;
+use std::ops::{Bound, RangeBounds};
+
+use packed_simd::{u8x16, u8x32};
+
+/// Compare a node's compressed `prefix` against the start of `key`.  Returns `None` if `key`
+/// starts with all of `prefix`, and otherwise the index of the first byte where they differ.  If
+/// `key` runs out before `prefix` does, this index is `key.len()`.
+pub fn mismatch(prefix: &[u8], key: &[u8]) -> Option<usize> {
+    let n = cmp::min(prefix.len(), key.len());
+    let mut i = 0;
+
+    // Most prefixes are short enough that we'll go through this loop at most once, but long shared
+    // prefixes (URLs, file paths) can be hundreds of bytes, and the unaligned loads are cheap.
+    while i + u8x32::lanes() <= n {
+        let a = u8x32::from_slice_unaligned(&prefix[i..(i + u8x32::lanes())]);
+        let b = u8x32::from_slice_unaligned(&key[i..(i + u8x32::lanes())]);
+        let equal = a.eq(b).bitmask();
+        if equal != !0 {
+            return Some(i + (!equal).trailing_zeros() as usize);
+        }
+        i += u8x32::lanes();
+    }
+    if i + u8x16::lanes() <= n {
+        let a = u8x16::from_slice_unaligned(&prefix[i..(i + u8x16::lanes())]);
+        let b = u8x16::from_slice_unaligned(&key[i..(i + u8x16::lanes())]);
+        let equal = a.eq(b).bitmask();
+        if equal != !0 {
+            return Some(i + (!equal).trailing_zeros() as usize);
+        }
+        i += u8x16::lanes();
+    }
+    while i < n {
+        if prefix[i] != key[i] {
+            return Some(i);
+        }
+        i += 1;
+    }
+
+    if n < prefix.len() {
+        Some(n)
+    } else {
+        None
+    }
+}
+
+/// Check whether `key` starts with all of `prefix`, short circuiting on `key`'s length before
+/// comparing any bytes.
+pub fn starts_with(key: &[u8], prefix: &[u8]) -> bool {
+    key.len() >= prefix.len() && mismatch(prefix, key).is_none()
+}
+
+/// Borrow `range`'s bounds as byte strings.
+pub fn byte_bounds<'a, K: AsRef<[u8]> + 'a>(
+    range: &'a impl RangeBounds<K>,
+) -> (Bound<&'a [u8]>, Bound<&'a [u8]>) {
+    let bytes_bound = |bound| match bound {
+        Bound::Included(k) => Bound::Included(K::as_ref(k)),
+        Bound::Excluded(k) => Bound::Excluded(K::as_ref(k)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (bytes_bound(range.start_bound()), bytes_bound(range.end_bound()))
+}
+
+/// Check where the keys starting with `prefix` fall relative to `range`: `Some(true)` if they're
+/// all inside of it, `Some(false)` if they're all outside of it, and `None` if it has some of
+/// them.  Every such key is at least `prefix`, and less than any key after `prefix` that `prefix`
+/// isn't a prefix of.
+pub fn range_contains_prefix(range: (Bound<&[u8]>, Bound<&[u8]>), prefix: &[u8]) -> Option<bool> {
+    let below_start = match range.0 {
+        Bound::Included(start) | Bound::Excluded(start) => {
+            prefix < start && !start.starts_with(prefix)
+        }
+        Bound::Unbounded => false,
+    };
+    let past_end = match range.1 {
+        Bound::Included(end) => prefix > end,
+        Bound::Excluded(end) => prefix >= end,
+        Bound::Unbounded => false,
+    };
+    if below_start || past_end {
+        return Some(false);
+    }
+    let after_start = match range.0 {
+        Bound::Included(start) => prefix >= start,
+        Bound::Excluded(start) => prefix > start,
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.1 {
+        Bound::Included(end) | Bound::Excluded(end) => prefix < end && !end.starts_with(prefix),
+        Bound::Unbounded => true,
+    };
+    if after_start && before_end {
+        return Some(true);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mismatch, starts_with};
+
+    fn scalar_mismatch(prefix: &[u8], key: &[u8]) -> Option<usize> {
+        for (i, &byte) in prefix.iter().enumerate() {
+            match key.get(i) {
+                Some(&key_byte) if key_byte == byte => continue,
+                _ => return Some(i),
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let prefix = (0..63).collect::<Vec<u8>>();
+        assert_eq!(mismatch(&prefix, &prefix), None);
+        assert_eq!(mismatch(&prefix[..10], &prefix), None);
+        assert_eq!(mismatch(&prefix, &prefix[..10]), Some(10));
+        assert_eq!(mismatch(&[], &prefix), None);
+        for i in 0..prefix.len() {
+            let mut key = prefix.clone();
+            key[i] ^= 0xff;
+            assert_eq!(mismatch(&prefix, &key), Some(i));
+            assert!(!starts_with(&key, &prefix));
+        }
+    }
+
+    #[quickcheck]
+    fn qc_mismatch(prefix: Vec<u8>, key: Vec<u8>, shared: usize) -> bool {
+        // Share some of the prefix with the key so we exercise the wide comparisons.
+        let shared = shared % (prefix.len() + 1);
+        let key = prefix[..shared].iter().chain(key.iter()).cloned().collect::<Vec<_>>();
+        let expected = scalar_mismatch(&prefix, &key);
+        mismatch(&prefix, &key) == expected && starts_with(&key, &prefix) == expected.is_none()
+    }
+}
@@ -0,0 +1,164 @@
+// Every node with children stores the number of values in its subtree, so finding a key's
+// position among the trie's keys, or the key at a position, is a single walk from the root: at
+// each node, the children before the one we descend into hold exactly the keys we skip over.
+// Counting the keys under a prefix just reads the count of the node holding them, and counting
+// a range adds up the counts of the subtrees inside it along the paths to its two ends.
+
+use std::cmp::Ordering;
+use std::ops::RangeBounds;
+
+use crate::allocator::Allocator;
+use crate::packed_node::PackedNode;
+use crate::prefix::byte_bounds;
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+impl<T, A, S: Summary<T>> PackedNode<T, A, S> {
+    // The `i`th value below this node in key order, pushing the key bytes from the start of
+    // this node's prefix down to the value onto `key`.
+    pub fn nth(&self, mut i: usize, key: &mut Vec<u8>) -> Option<&T> {
+        if i >= self.len() {
+            return None;
+        }
+        let mut node = self;
+        loop {
+            key.extend_from_slice(node.prefix());
+            if let Some(value) = node.value() {
+                if i == 0 {
+                    return Some(value);
+                }
+                i -= 1;
+            }
+            let mut children = node.children();
+            let (byte, child) = loop {
+                let (byte, child) = children.next().unwrap();
+                if i < child.len() {
+                    break (byte, child);
+                }
+                i -= child.len();
+            };
+            key.push(byte);
+            node = child;
+        }
+    }
+
+    // The number of keys below this node that are less than `key`, where both start right
+    // before this node's prefix.
+    pub fn rank(&self, mut key: &[u8]) -> usize {
+        let mut node = self;
+        let mut rank = 0;
+        loop {
+            let prefix = node.prefix();
+            let common = prefix.iter().zip(key).take_while(|(a, b)| a == b).count();
+            if common < prefix.len() {
+                // Either the whole subtree is on one side of `key`, or `key` ends within our
+                // prefix and comes before every key below us.
+                if key.get(common).map(|&k| prefix[common].cmp(&k)) == Some(Ordering::Less) {
+                    rank += node.len();
+                }
+                return rank;
+            }
+            let (&branch_byte, rest) = match key[prefix.len()..].split_first() {
+                None => return rank,
+                Some(p) => p,
+            };
+            rank += node.has_value() as usize;
+            let mut next = None;
+            for (byte, child) in node.children() {
+                if byte >= branch_byte {
+                    next = (byte == branch_byte).then_some(child);
+                    break;
+                }
+                rank += child.len();
+            }
+            match next {
+                Some(child) => node = child,
+                None => return rank,
+            }
+            key = rest;
+        }
+    }
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// The number of keys in the trie that are strictly less than `key`, which is the position
+    /// `key` has or would have in key order.  This takes a single walk down towards `key`.
+    pub fn rank(&self, key: &[u8]) -> usize {
+        self.root.rank(key)
+    }
+
+    /// The entry at position `i` in key order, or `None` if the trie has `i` or fewer entries.
+    /// Like `rank`, this walks straight down to the entry rather than iterating up to it.
+    pub fn nth(&self, i: usize) -> Option<(Vec<u8>, &T)> {
+        let mut key = vec![];
+        let value = self.root.nth(i, &mut key)?;
+        Some((key, value))
+    }
+
+    /// The number of keys that start with `prefix`.  This walks down to the node holding them
+    /// and reads the count it stores.  Every update keeps these counts up to date, so unlike
+    /// `FrozenTrie::count_prefix`, this never has to fall back to walking the subtree.
+    pub fn count_prefix(&self, prefix: &[u8]) -> usize {
+        match self.root.find_prefix(prefix) {
+            Some((node, _)) => node.len(),
+            None => 0,
+        }
+    }
+
+    /// The number of keys in `range`.  Like `summarize_range`, this only looks at the children
+    /// of the nodes along the paths to the range's two ends.
+    pub fn count_range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> usize {
+        let mut count = 0;
+        self.root.for_each_in_range(&mut vec![], byte_bounds(&range), &mut |node, whole| {
+            count += if whole { node.len() } else { 1 };
+        });
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers::{random_bound, random_key, random_updates};
+    use crate::Trie;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::ops::RangeBounds;
+
+    #[test]
+    fn test_rank_and_nth() {
+        let mut t = Trie::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!((t.rank(b"a"), t.nth(0)), (0, None));
+
+        let model = random_updates(&mut t, &mut rng, |t, model, rng| {
+            let key = random_key(rng);
+            assert_eq!(t.rank(&key), model.range(..key.clone()).count(), "{:?}", key);
+            let i = rng.gen_range(0, model.len() + 2);
+            let expected = model.iter().nth(i).map(|(k, v)| (k.clone(), v));
+            assert_eq!(t.nth(i), expected);
+        });
+        for (i, (key, value)) in model.iter().enumerate() {
+            assert_eq!(t.rank(key), i);
+            assert_eq!(t.nth(i), Some((key.clone(), value)));
+        }
+        assert_eq!(t.nth(model.len()), None);
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut t = Trie::new();
+        let mut rng = StdRng::seed_from_u64(1);
+        random_updates(&mut t, &mut rng, |t, model, rng| {
+            let mut prefix = random_key(rng);
+            prefix.truncate(rng.gen_range(0, 3));
+            let expected = model.keys().filter(|k| k.starts_with(&prefix)).count();
+            assert_eq!(t.count_prefix(&prefix), expected, "{:?}", prefix);
+
+            let range = (random_bound(rng), random_bound(rng));
+            let expected = model.keys().filter(|k| range.contains(k)).count();
+            assert_eq!(t.count_range(range.clone()), expected, "{:?}", range);
+        });
+        assert_eq!(t.count_range::<&[u8], _>(..), t.len());
+        assert_eq!(t.count_prefix(b""), t.len());
+    }
+}
@@ -0,0 +1,695 @@
+// A small regular expression engine for `Trie::search`.  Patterns get parsed into a syntax tree,
+// compiled into a Thompson NFA, and then turned into a DFA up front with the subset construction,
+// so running it over keys is a table lookup per byte.  We also work out which DFA states can
+// still reach a match and which can't leave one, so searches can skip dead subtrees and
+// complements of the regex can skip live ones.
+//
+// Regexes match whole keys, byte by byte.  Non-ASCII characters in the pattern match their UTF-8
+// encoding, but `.` and classes match single bytes.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::automaton::Automaton;
+use crate::bitset::Bitset;
+
+// Limits on how far a pattern can blow up, since counted repetitions copy their operand and the
+// subset construction can make exponentially many states.
+const MAX_REPEAT: u32 = 1000;
+const MAX_NFA_STATES: usize = 100_000;
+const MAX_DFA_STATES: usize = 10_000;
+// How tall a pattern's syntax tree can get, since parsing, compiling, and dropping it all recurse.
+const MAX_NESTING: usize = 250;
+
+/// Why `Regex::new` couldn't compile a pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegexError {
+    /// The pattern isn't valid syntax, at the given byte offset.
+    Syntax { offset: usize, reason: &'static str },
+    /// The pattern's automaton would be too large.
+    TooLarge,
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegexError::Syntax { offset, reason } => {
+                write!(f, "invalid regex at offset {}: {}", offset, reason)
+            }
+            RegexError::TooLarge => write!(f, "regex is too large"),
+        }
+    }
+}
+
+impl Error for RegexError {}
+
+enum Ast {
+    Class(Bitset),
+    Concat(Vec<Ast>),
+    Alternate(Vec<Ast>),
+    Repeat { ast: Box<Ast>, min: u32, max: Option<u32> },
+}
+
+fn class(bytes: impl IntoIterator<Item = u8>) -> Bitset {
+    let mut set = Bitset::new();
+    for byte in bytes {
+        set.set(byte);
+    }
+    set
+}
+
+fn negate(set: &Bitset) -> Bitset {
+    class((0..=255).filter(|&b| !set.contains(b)))
+}
+
+// Check that a syntax tree `height` levels tall isn't nested too deeply.
+fn nest(height: usize) -> Result<usize, RegexError> {
+    if height > MAX_NESTING {
+        return Err(RegexError::TooLarge);
+    }
+    Ok(height)
+}
+
+// Each parsing method returns the syntax tree it parsed along with the tree's height.
+struct Parser<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+    // The number of groups we're inside of.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error<R>(&self, reason: &'static str) -> Result<R, RegexError> {
+        Err(RegexError::Syntax { offset: self.pos, reason })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.pattern.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn alternation(&mut self) -> Result<(Ast, usize), RegexError> {
+        let (first, mut height) = self.concatenation()?;
+        let mut branches = vec![first];
+        while self.eat(b'|') {
+            let (branch, branch_height) = self.concatenation()?;
+            branches.push(branch);
+            height = cmp::max(height, branch_height);
+        }
+        if branches.len() == 1 {
+            return Ok((branches.pop().unwrap(), height));
+        }
+        Ok((Ast::Alternate(branches), nest(height + 1)?))
+    }
+
+    fn concatenation(&mut self) -> Result<(Ast, usize), RegexError> {
+        let mut items = vec![];
+        let mut height = 0;
+        while let Some(byte) = self.peek() {
+            if byte == b'|' || byte == b')' {
+                break;
+            }
+            let (item, item_height) = self.repetition()?;
+            items.push(item);
+            height = cmp::max(height, item_height);
+        }
+        Ok((Ast::Concat(items), nest(height + 1)?))
+    }
+
+    fn repetition(&mut self) -> Result<(Ast, usize), RegexError> {
+        let (mut ast, mut height) = self.atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some(b'*') | Some(b'+') | Some(b'?') => {
+                    self.pos += 1;
+                    match self.pattern[self.pos - 1] {
+                        b'*' => (0, None),
+                        b'+' => (1, None),
+                        _ => (0, Some(1)),
+                    }
+                }
+                Some(b'{') => {
+                    self.pos += 1;
+                    self.counts()?
+                }
+                _ => return Ok((ast, height)),
+            };
+            height = nest(height + 1)?;
+            ast = Ast::Repeat { ast: Box::new(ast), min, max };
+        }
+    }
+
+    // Parse the inside of a counted repetition like `{2}`, `{2,}`, or `{2,5}`, after the `{`.
+    fn counts(&mut self) -> Result<(u32, Option<u32>), RegexError> {
+        let min = self.number()?;
+        let max = if self.eat(b',') {
+            match self.peek() {
+                Some(b'}') => None,
+                _ => Some(self.number()?),
+            }
+        } else {
+            Some(min)
+        };
+        if !self.eat(b'}') {
+            return self.error("unclosed counted repetition");
+        }
+        if max.is_some_and(|max| max < min) {
+            return self.error("repetition's maximum is less than its minimum");
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<u32, RegexError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = std::str::from_utf8(&self.pattern[start..self.pos]).unwrap();
+        if digits.is_empty() {
+            return self.error("expected a repetition count");
+        }
+        // Counts too big for a `u32` are still just big counts.
+        match digits.parse() {
+            Ok(n) if n <= MAX_REPEAT => Ok(n),
+            _ => Err(RegexError::TooLarge),
+        }
+    }
+
+    fn atom(&mut self) -> Result<(Ast, usize), RegexError> {
+        let byte = match self.peek() {
+            Some(byte) => byte,
+            None => return self.error("expected an expression"),
+        };
+        self.pos += 1;
+        let ast = match byte {
+            b'(' => {
+                // Every group adds at least a level to the tree, so this also bounds how deeply
+                // parsing recurses.
+                if self.depth == MAX_NESTING {
+                    return Err(RegexError::TooLarge);
+                }
+                self.depth += 1;
+                let group = self.alternation()?;
+                self.depth -= 1;
+                if !self.eat(b')') {
+                    return self.error("unclosed group");
+                }
+                return Ok(group);
+            }
+            b'[' => Ast::Class(self.class()?),
+            b'.' => Ast::Class(class(0..=255)),
+            b'\\' => Ast::Class(self.escape()?),
+            b'*' | b'+' | b'?' | b'{' => {
+                self.pos -= 1;
+                return self.error("repetition without an expression to repeat");
+            }
+            b'^' | b'$' => {
+                self.pos -= 1;
+                return self.error("anchors aren't supported: regexes always match whole keys");
+            }
+            _ => {
+                // Keep a multibyte character together, so repeating it repeats all of it.
+                let mut bytes = vec![Ast::Class(class(Some(byte)))];
+                while self.peek().is_some_and(|b| b & 0xc0 == 0x80) {
+                    bytes.push(Ast::Class(class(self.peek())));
+                    self.pos += 1;
+                }
+                if bytes.len() == 1 {
+                    return Ok((bytes.pop().unwrap(), 1));
+                }
+                return Ok((Ast::Concat(bytes), 2));
+            }
+        };
+        Ok((ast, 1))
+    }
+
+    // Parse a backslash escape, after the backslash.
+    fn escape(&mut self) -> Result<Bitset, RegexError> {
+        let byte = match self.peek() {
+            Some(byte) => byte,
+            None => return self.error("pattern ends with a backslash"),
+        };
+        self.pos += 1;
+        let digits = b'0'..=b'9';
+        let word = digits.clone().chain(b'a'..=b'z').chain(b'A'..=b'Z').chain(Some(b'_'));
+        let space = b" \t\n\r\x0b\x0c".iter().cloned();
+        let set = match byte {
+            b'd' => class(digits),
+            b'D' => negate(&class(digits)),
+            b'w' => class(word),
+            b'W' => negate(&class(word)),
+            b's' => class(space),
+            b'S' => negate(&class(space)),
+            b'n' => class(Some(b'\n')),
+            b'r' => class(Some(b'\r')),
+            b't' => class(Some(b'\t')),
+            b'x' => {
+                let hex = self.pattern.get(self.pos..(self.pos + 2)).unwrap_or(&[]);
+                match hex {
+                    [high, low] if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                        let value = std::str::from_utf8(hex).unwrap();
+                        self.pos += 2;
+                        class(Some(u8::from_str_radix(value, 16).unwrap()))
+                    }
+                    _ => return self.error("expected two hex digits after \\x"),
+                }
+            }
+            b if b.is_ascii_punctuation() => class(Some(b)),
+            _ => {
+                self.pos -= 1;
+                return self.error("unknown escape");
+            }
+        };
+        Ok(set)
+    }
+
+    // Parse a bracketed class like `[a-z_]` or `[^/]`, after the `[`.
+    fn class(&mut self) -> Result<Bitset, RegexError> {
+        let negated = self.eat(b'^');
+        let mut set = Bitset::new();
+        let mut first = true;
+        loop {
+            let byte = match self.peek() {
+                Some(b']') if !first => break,
+                Some(byte) => byte,
+                None => return self.error("unclosed class"),
+            };
+            first = false;
+            if !byte.is_ascii() {
+                return self.error("classes can only hold ASCII characters");
+            }
+            self.pos += 1;
+            let low = match byte {
+                b'\\' => {
+                    let escaped = self.escape()?;
+                    for b in escaped.iter() {
+                        set.set(b);
+                    }
+                    continue;
+                }
+                b => b,
+            };
+            let is_range = self.peek() == Some(b'-')
+                && self.pattern.get(self.pos + 1).is_some_and(|&b| b != b']');
+            if !is_range {
+                set.set(low);
+                continue;
+            }
+            self.pos += 1;
+            let high = match self.peek() {
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = self.escape()?;
+                    let mut bytes = escaped.iter();
+                    match (bytes.next(), bytes.next()) {
+                        (Some(b), None) => b,
+                        _ => return self.error("class range ends with a class"),
+                    }
+                }
+                Some(b) if b.is_ascii() => {
+                    self.pos += 1;
+                    b
+                }
+                _ => return self.error("classes can only hold ASCII characters"),
+            };
+            if high < low {
+                return self.error("class range is out of order");
+            }
+            for b in low..=high {
+                set.set(b);
+            }
+        }
+        self.pos += 1;
+        Ok(if negated { negate(&set) } else { set })
+    }
+}
+
+enum NfaState {
+    // Read a byte in the set and go to the next state.
+    Byte(Bitset, usize),
+    // Go to both states without reading anything.
+    Split(usize, usize),
+    Match,
+}
+
+struct Nfa {
+    states: Vec<NfaState>,
+}
+
+impl Nfa {
+    fn push(&mut self, state: NfaState) -> Result<usize, RegexError> {
+        if self.states.len() >= MAX_NFA_STATES {
+            return Err(RegexError::TooLarge);
+        }
+        self.states.push(state);
+        Ok(self.states.len() - 1)
+    }
+
+    // Compile `ast` so that it continues to `next` once it's matched, and return where it
+    // starts.  Building backwards like this means we never have to patch up dangling edges,
+    // except for loops.
+    fn compile(&mut self, ast: &Ast, next: usize) -> Result<usize, RegexError> {
+        match ast {
+            Ast::Class(set) => self.push(NfaState::Byte(*set, next)),
+            Ast::Concat(items) => {
+                items.iter().rev().try_fold(next, |next, item| self.compile(item, next))
+            }
+            Ast::Alternate(branches) => {
+                let (last, rest) = branches.split_last().unwrap();
+                let mut start = self.compile(last, next)?;
+                for branch in rest.iter().rev() {
+                    let branch_start = self.compile(branch, next)?;
+                    start = self.push(NfaState::Split(branch_start, start))?;
+                }
+                Ok(start)
+            }
+            Ast::Repeat { ast, min, max } => {
+                let mut start = match max {
+                    // `x{2,4}` is `xx(x(x)?)?`.
+                    Some(max) => {
+                        let mut start = next;
+                        for _ in *min..*max {
+                            let body = self.compile(ast, start)?;
+                            start = self.push(NfaState::Split(body, next))?;
+                        }
+                        start
+                    }
+                    // `x{2,}` is `xxx*`, where the loop goes back to a split.
+                    None => {
+                        let split = self.push(NfaState::Split(next, next))?;
+                        let body = self.compile(ast, split)?;
+                        self.states[split] = NfaState::Split(body, next);
+                        split
+                    }
+                };
+                for _ in 0..*min {
+                    start = self.compile(ast, start)?;
+                }
+                Ok(start)
+            }
+        }
+    }
+
+    // Add the states reachable from `state` without reading anything to `set`.  Only states
+    // that read a byte or match go into the set itself, since the rest behave the same as the
+    // states they lead to.
+    fn closure(&self, state: usize, visited: &mut Visited, set: &mut Vec<usize>) {
+        let mut stack = vec![state];
+        while let Some(state) = stack.pop() {
+            if !visited.insert(state) {
+                continue;
+            }
+            match self.states[state] {
+                NfaState::Split(a, b) => {
+                    stack.push(b);
+                    stack.push(a);
+                }
+                _ => set.push(state),
+            }
+        }
+    }
+
+    // Split the bytes into classes that no `Byte` state tells apart, so the subset construction
+    // only has to follow one byte from each.  Returns each byte's class and a byte from each
+    // class.
+    fn byte_classes(&self) -> ([u8; 256], Vec<u8>) {
+        let mut classes = [0u8; 256];
+        let mut representatives = vec![0u8];
+        for state in &self.states {
+            let set = match *state {
+                NfaState::Byte(ref set, _) => set,
+                _ => continue,
+            };
+            // Split every class into the bytes in `set` and the ones that aren't.
+            let mut split = vec![[None; 2]; representatives.len()];
+            representatives.clear();
+            for byte in 0..=255u8 {
+                let halves = &mut split[classes[byte as usize] as usize];
+                let class = *halves[set.contains(byte) as usize].get_or_insert_with(|| {
+                    representatives.push(byte);
+                    (representatives.len() - 1) as u8
+                });
+                classes[byte as usize] = class;
+            }
+        }
+        (classes, representatives)
+    }
+}
+
+// The NFA states a closure has already been through.  Bumping the generation empties it without
+// touching every state again.
+struct Visited {
+    generations: Vec<u32>,
+    generation: u32,
+}
+
+impl Visited {
+    fn new(len: usize) -> Self {
+        Self { generations: vec![0; len], generation: 1 }
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+    }
+
+    fn insert(&mut self, state: usize) -> bool {
+        let new = self.generations[state] != self.generation;
+        self.generations[state] = self.generation;
+        new
+    }
+}
+
+/// A regular expression, compiled to a DFA, that matches whole keys.  This supports literals,
+/// `.`, classes like `[a-z]` and `[^/]`, the escapes `\d`, `\w`, `\s` (and their negations),
+/// `\n`, `\r`, `\t`, and `\xNN`, grouping with parentheses, `|`, and the repetitions `*`, `+`,
+/// `?`, and `{m,n}`.  Everything works on bytes: `.` and classes match a single byte, though
+/// non-ASCII characters elsewhere in the pattern match their UTF-8 encoding.
+#[derive(Clone, Debug)]
+pub struct Regex {
+    // `transitions[s][b]` is the state after reading `b` in state `s`.  State 0 is dead.
+    transitions: Vec<[u32; 256]>,
+    is_match: Vec<bool>,
+    can_match: Vec<bool>,
+    will_always_match: Vec<bool>,
+}
+
+const DEAD: u32 = 0;
+const START: u32 = 1;
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, RegexError> {
+        let mut parser = Parser { pattern: pattern.as_bytes(), pos: 0, depth: 0 };
+        let (ast, _) = parser.alternation()?;
+        if parser.pos < parser.pattern.len() {
+            return parser.error("unmatched closing parenthesis");
+        }
+        let mut nfa = Nfa { states: vec![NfaState::Match] };
+        let start = nfa.compile(&ast, 0)?;
+        Self::from_nfa(&nfa, start)
+    }
+
+    // The subset construction: each DFA state stands for the set of NFA states we could be in.
+    fn from_nfa(nfa: &Nfa, start: usize) -> Result<Self, RegexError> {
+        let (classes, representatives) = nfa.byte_classes();
+        let mut visited = Visited::new(nfa.states.len());
+        let mut sets = vec![vec![]];
+        let mut ids = HashMap::new();
+        ids.insert(vec![], DEAD);
+        let mut start_set = vec![];
+        nfa.closure(start, &mut visited, &mut start_set);
+        start_set.sort_unstable();
+        ids.insert(start_set.clone(), START);
+        sets.push(start_set);
+
+        let mut transitions = vec![];
+        let mut class_row = vec![DEAD; representatives.len()];
+        while transitions.len() < sets.len() {
+            let set = sets[transitions.len()].clone();
+            for (&byte, id) in representatives.iter().zip(class_row.iter_mut()) {
+                visited.clear();
+                let mut next = vec![];
+                for &state in &set {
+                    if let NfaState::Byte(ref bytes, to) = nfa.states[state] {
+                        if bytes.contains(byte) {
+                            nfa.closure(to, &mut visited, &mut next);
+                        }
+                    }
+                }
+                next.sort_unstable();
+                *id = match ids.get(&next) {
+                    Some(&id) => id,
+                    None => {
+                        if sets.len() >= MAX_DFA_STATES {
+                            return Err(RegexError::TooLarge);
+                        }
+                        let id = sets.len() as u32;
+                        ids.insert(next.clone(), id);
+                        sets.push(next);
+                        id
+                    }
+                };
+            }
+            transitions.push(classes.map(|class| class_row[class as usize]));
+        }
+
+        let is_match = sets
+            .iter()
+            .map(|set| set.iter().any(|&s| matches!(nfa.states[s], NfaState::Match)))
+            .collect::<Vec<_>>();
+        // States that can reach a match, and matching states that can't leave the matching
+        // states, found by walking back from the states we know about.
+        let mut predecessors = vec![vec![]; sets.len()];
+        for (s, row) in transitions.iter().enumerate() {
+            for &byte in &representatives {
+                predecessors[row[byte as usize] as usize].push(s);
+            }
+        }
+        let mut can_match = is_match.clone();
+        let mut stack = (0..sets.len()).filter(|&s| is_match[s]).collect::<Vec<_>>();
+        while let Some(s) = stack.pop() {
+            for &p in &predecessors[s] {
+                if !can_match[p] {
+                    can_match[p] = true;
+                    stack.push(p);
+                }
+            }
+        }
+        let mut will_always_match = is_match.clone();
+        let mut stack = (0..sets.len()).filter(|&s| !is_match[s]).collect::<Vec<_>>();
+        while let Some(s) = stack.pop() {
+            for &p in &predecessors[s] {
+                if will_always_match[p] {
+                    will_always_match[p] = false;
+                    stack.push(p);
+                }
+            }
+        }
+        Ok(Self { transitions, is_match, can_match, will_always_match })
+    }
+}
+
+impl Automaton for Regex {
+    type State = u32;
+
+    fn start(&self) -> u32 {
+        START
+    }
+
+    fn accept(&self, &state: &u32, byte: u8) -> u32 {
+        self.transitions[state as usize][byte as usize]
+    }
+
+    fn is_match(&self, &state: &u32) -> bool {
+        self.is_match[state as usize]
+    }
+
+    fn can_match(&self, &state: &u32) -> bool {
+        self.can_match[state as usize]
+    }
+
+    fn will_always_match(&self, &state: &u32) -> bool {
+        self.will_always_match[state as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Regex, RegexError};
+    use crate::automaton::Automaton;
+
+    fn is_match(regex: &Regex, key: &[u8]) -> bool {
+        let state = key.iter().fold(regex.start(), |s, &b| regex.accept(&s, b));
+        regex.is_match(&state)
+    }
+
+    #[test]
+    fn test_regex() {
+        let cases: &[(&str, &[&str], &[&str])] = &[
+            ("", &[""], &["a"]),
+            ("abc", &["abc"], &["", "ab", "abcd", "xabc"]),
+            ("a.c", &["abc", "a/c"], &["ac", "abbc"]),
+            ("a*", &["", "a", "aaaa"], &["b", "ab"]),
+            ("(ab)+", &["ab", "abab"], &["", "aba"]),
+            ("colou?r", &["color", "colour"], &["colouur"]),
+            ("a|bc|", &["a", "bc", ""], &["ab", "b"]),
+            ("[a-c_]+", &["a_b", "cab"], &["d", ""]),
+            ("[^/]*/[]x]", &["foo/]", "/x"], &["a/b/x", "a/b"]),
+            ("x{2,3}", &["xx", "xxx"], &["x", "xxxx"]),
+            ("x{2}y{1,}", &["xxy", "xxyyy"], &["xy", "xx"]),
+            ("(x{2})*", &["", "xxxx"], &["xxx"]),
+            ("(a|b){0,2}c", &["c", "abc", "bbc"], &["abac"]),
+            (r"\d+\.\d{2}", &["3.14", "10.00"], &["3.1", ".25"]),
+            (r"\w+\s\W", &["ab_1 !"], &["ab c"]),
+            (r"[\d-]+", &["1-2"], &["a"]),
+            (r"\x41\\", &["A\\"], &["A"]),
+            ("é+", &["é", "éé"], &["e", "\u{e9}\u{a9}"]),
+            ("(a*)*b", &["b", "aab"], &["a"]),
+        ];
+        for (pattern, matching, other) in cases {
+            let regex = Regex::new(pattern).unwrap();
+            for key in matching.iter() {
+                assert!(is_match(&regex, key.as_bytes()), "{} should match {:?}", pattern, key);
+            }
+            for key in other.iter() {
+                assert!(!is_match(&regex, key.as_bytes()), "{} matched {:?}", pattern, key);
+            }
+        }
+
+        // States know whether they can still match, or can't stop matching.
+        let regex = Regex::new("ab.*").unwrap();
+        let after = |key: &[u8]| key.iter().fold(regex.start(), |s, &b| regex.accept(&s, b));
+        assert!(regex.can_match(&after(b"a")) && !regex.will_always_match(&after(b"a")));
+        assert!(!regex.can_match(&after(b"b")));
+        assert!(regex.will_always_match(&after(b"abz")));
+
+        let syntax_error = |pattern: &str, offset: usize| match Regex::new(pattern) {
+            Err(RegexError::Syntax { offset: o, .. }) => assert_eq!(o, offset, "{}", pattern),
+            _ => panic!("{} should fail", pattern),
+        };
+        syntax_error("a(b", 3);
+        syntax_error("ab)", 2);
+        syntax_error("*a", 0);
+        syntax_error("a|+", 2);
+        syntax_error("[a-", 3);
+        syntax_error("[z-a]", 4);
+        syntax_error("x{3,2}", 6);
+        syntax_error("x{,2}", 2);
+        syntax_error("^a", 0);
+        syntax_error(r"\q", 1);
+        syntax_error(r"[é]", 1);
+        assert_eq!(Regex::new("x{1001}").unwrap_err(), RegexError::TooLarge);
+        assert_eq!(Regex::new("x{99999999999}").unwrap_err(), RegexError::TooLarge);
+        assert_eq!(Regex::new("x{1,99999999999}").unwrap_err(), RegexError::TooLarge);
+        // The DFA for this needs a state for each of the last 20 bytes it's seen.
+        assert_eq!(Regex::new("[ab]*a[ab]{20}").unwrap_err(), RegexError::TooLarge);
+
+        // Big but simple automata compile quickly, whether or not they fit.
+        let regex = Regex::new("(.{100}){99}").unwrap();
+        assert!(is_match(&regex, &[b'x'; 9900]) && !is_match(&regex, &[b'x'; 9899]));
+        assert!(regex.can_match(&regex.start()) && !regex.will_always_match(&regex.start()));
+        assert_eq!(Regex::new("(.{100}){100}").unwrap_err(), RegexError::TooLarge);
+        Regex::new("([a-m]|[h-z]|[^a-z]){100}").unwrap();
+
+        // Deeply nested patterns are too large rather than overflowing the stack.
+        let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(is_match(&Regex::new(&nested(100)).unwrap(), b"a"));
+        assert_eq!(Regex::new(&nested(100_000)).unwrap_err(), RegexError::TooLarge);
+        assert_eq!(Regex::new(&"(".repeat(100_000)).unwrap_err(), RegexError::TooLarge);
+        assert!(is_match(&Regex::new(&format!("a{}", "*".repeat(100))).unwrap(), b"aa"));
+        let stars = format!("a{}", "*".repeat(100_000));
+        assert_eq!(Regex::new(&stars).unwrap_err(), RegexError::TooLarge);
+        let groups = format!("{}a{}", "(".repeat(150), ")*".repeat(150));
+        assert_eq!(Regex::new(&groups).unwrap_err(), RegexError::TooLarge);
+        let groups = format!("{}a{}", "(".repeat(50), ")?".repeat(50));
+        assert!(is_match(&Regex::new(&groups).unwrap(), b""));
+    }
+}
@@ -19,25 +19,25 @@
 // value itself.  Therefore, we must continue up the parent chain, inductively
 // patching up our invariants.
 
+use crate::allocator::Allocator;
 use crate::node::{Node, NodeChildren};
 use crate::packed_node::PackedNode;
+use crate::prefix;
+use crate::summary::Summary;
 
-impl<T> PackedNode<T> {
-    pub fn remove(&mut self, key: &[u8]) -> Option<T> {
-        let mut key_iter = key.iter();
-
-        for &byte in self.prefix() {
-            match key_iter.next() {
-                Some(&key_byte) if key_byte == byte => continue,
-                _ => return None,
-            }
+impl<T, A: Allocator, S: Summary<T>> PackedNode<T, A, S> {
+    pub fn remove(&mut self, key: &[u8], alloc: &A) -> Option<T> {
+        if !prefix::starts_with(key, self.prefix()) {
+            return None;
         }
+        let mut key_iter = key[self.prefix().len()..].iter();
+
         let branch_byte = match key_iter.next() {
             None => {
                 if !self.has_value() {
                     return None;
                 }
-                let Node { mut prefix, children, value } = self.take();
+                let Node { mut prefix, children, value, .. } = self.take(alloc);
                 let value = value.unwrap();
                 let pairs = children.into_pairs();
                 match pairs.len() {
@@ -47,32 +47,34 @@ impl<T> PackedNode<T> {
                     },
                     1 => {
                         let (child_byte, mut packed_child) = pairs.into_iter().next().unwrap();
-                        let child = packed_child.take();
+                        let child = packed_child.take(alloc);
 
                         prefix.push(child_byte);
                         prefix.extend_from_slice(child.prefix());
 
-                        let new_node = Node::new(prefix, child.children, child.value);
-                        *self = PackedNode::new(new_node);
+                        let new_node = Node::new(prefix, child.children, child.value, alloc);
+                        *self = PackedNode::new(new_node, alloc);
                         return Some(value);
                     },
                     _ => {
                         let children = NodeChildren::from_pairs(pairs);
-                        *self = PackedNode::new(Node::new(prefix, children, None));
+                        *self = PackedNode::new(Node::new(prefix, children, None, alloc), alloc);
                         return Some(value);
                     },
                 }
             },
             Some(&k) => k,
         };
+        self.clear_summary();
         let next_node = self.lookup_mut(branch_byte)?;
-        let removed_value = next_node.remove(key_iter.as_slice())?;
+        let removed_value = next_node.remove(key_iter.as_slice(), alloc)?;
 
         if !next_node.is_empty() {
+            self.set_len(self.len() - 1);
             return Some(removed_value);
         }
 
-        let Node { mut prefix, children, value } = self.take();
+        let Node { mut prefix, children, value, .. } = self.take(alloc);
         let pairs = children.into_pairs();
         match (value.is_some(), pairs.len()) {
             (false, 0) => {
@@ -81,21 +83,21 @@ impl<T> PackedNode<T> {
             },
             (false, 1) => {
                 let (child_byte, mut packed_child) = pairs.into_iter().next().unwrap();
-                let child = packed_child.take();
+                let child = packed_child.take(alloc);
 
                 prefix.push(child_byte);
                 prefix.extend_from_slice(child.prefix());
 
-                let new_node = Node::new(prefix, child.children, child.value);
-                *self = PackedNode::new(new_node);
+                let new_node = Node::new(prefix, child.children, child.value, alloc);
+                *self = PackedNode::new(new_node, alloc);
                 return Some(removed_value);
             },
             // If we have a value, we can't deallocate ourselves or merge ourselves into a child.
             (true, _) | (false, _) => {
                 assert!(!pairs.contains_key(&branch_byte));
                 let children = NodeChildren::from_pairs(pairs);
-                let new_node = Node::new(prefix, children, value);
-                *self = PackedNode::new(new_node);
+                let new_node = Node::new(prefix, children, value, alloc);
+                *self = PackedNode::new(new_node, alloc);
                 return Some(removed_value);
             }
         }
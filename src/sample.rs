@@ -0,0 +1,182 @@
+// With the `rand` feature, tries can pick random entries.  Uniform sampling draws an index into
+// the trie's keys and walks straight down to it: every node with children stores the number of
+// values in its subtree, so we can skip over whole children until we find the one holding the
+// index.  Weighted sampling can't do that, since the weights aren't stored anywhere, so it keeps
+// a single weighted reservoir sample over all of the entries instead.
+
+use rand::Rng;
+
+use crate::allocator::Allocator;
+use crate::packed_node::PackedNode;
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+// Call `f` with every entry below `node`, in key order.  `key` holds the key bytes leading up
+// to `node`'s prefix.
+fn for_each<'a, T, A, S: Summary<T>>(
+    node: &'a PackedNode<T, A, S>,
+    key: &mut Vec<u8>,
+    f: &mut impl FnMut(&[u8], &'a T),
+) {
+    let key_len = key.len();
+    key.extend_from_slice(node.prefix());
+    if let Some(value) = node.value() {
+        f(key, value);
+    }
+    for (byte, child) in node.children() {
+        key.push(byte);
+        for_each(child, key, f);
+        key.pop();
+    }
+    key.truncate(key_len);
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Pick an entry uniformly at random, or return `None` if the trie is empty.  This takes a
+    /// single walk from the root to the chosen entry.
+    pub fn sample(&self, rng: &mut impl Rng) -> Option<(Vec<u8>, &T)> {
+        self.sample_prefix(&[], rng)
+    }
+
+    /// Pick an entry uniformly at random from the ones whose keys start with `prefix`, or return
+    /// `None` if there aren't any.
+    pub fn sample_prefix(&self, prefix: &[u8], rng: &mut impl Rng) -> Option<(Vec<u8>, &T)> {
+        let (node, depth) = self.root.find_prefix(prefix)?;
+        let mut key = prefix[..depth].to_owned();
+        let value = node.nth(rng.gen_range(0, node.len()), &mut key)?;
+        Some((key, value))
+    }
+
+    /// Pick an entry at random with probability proportional to `weight(value)`, or return
+    /// `None` if there are no entries with a positive weight.  Unlike `sample`, this visits every
+    /// entry, calling `weight` once for each.
+    ///
+    /// # Panics
+    /// Panics if a weight is negative or not finite.
+    pub fn sample_weighted(
+        &self,
+        rng: &mut impl Rng,
+        mut weight: impl FnMut(&T) -> f64,
+    ) -> Option<(Vec<u8>, &T)> {
+        let mut total = 0.0;
+        let mut chosen = None;
+        for_each(&self.root, &mut vec![], &mut |key, value| {
+            let w = weight(value);
+            assert!(w >= 0.0 && w.is_finite(), "Invalid sampling weight {}", w);
+            if w == 0.0 {
+                return;
+            }
+            // Replacing the current choice with probability `w / total` leaves each entry seen
+            // so far chosen with probability proportional to its weight.
+            total += w;
+            if rng.gen::<f64>() * total < w {
+                chosen = Some((key.to_owned(), value));
+            }
+        });
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Trie;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::BTreeMap;
+
+    // Pearson's chi-squared statistic for `counts` against `expected`.
+    fn chi_squared(counts: &BTreeMap<Vec<u8>, usize>, expected: &BTreeMap<Vec<u8>, f64>) -> f64 {
+        assert!(counts.keys().all(|k| expected.contains_key(k)));
+        expected
+            .iter()
+            .map(|(k, &e)| {
+                let observed = counts.get(k).cloned().unwrap_or(0) as f64;
+                (observed - e) * (observed - e) / e
+            })
+            .sum()
+    }
+
+    // The chi-squared distribution's upper 0.01% point for `df` degrees of freedom, using the
+    // Wilson-Hilferty approximation.
+    fn chi_squared_bound(df: usize) -> f64 {
+        let df = df as f64;
+        let z = 3.719;
+        let a = 2.0 / (9.0 * df);
+        df * (1.0 - a + z * a.sqrt()).powi(3)
+    }
+
+    fn sample_trie() -> Trie<u32> {
+        let mut t = Trie::new();
+        // A mix of prefix keys, long suffixes, and a Dense node.
+        for i in 0..200u32 {
+            t.insert(format!("k/{}", i * 7).as_bytes(), i);
+        }
+        for b in 0..=255u8 {
+            t.insert(&[0xff, b], 1000 + b as u32);
+        }
+        for len in 0..10 {
+            t.insert(&vec![b'a'; len * 9], 2000 + len as u32);
+        }
+        t
+    }
+
+    #[test]
+    fn test_sample_uniform() {
+        let t = sample_trie();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(t.len(), 466);
+
+        let check_uniform = |prefix: &[u8], rng: &mut StdRng| {
+            let keys = t.iter().map(|(k, _)| k).filter(|k| k.starts_with(prefix));
+            let keys = keys.collect::<Vec<_>>();
+            let draws = 200 * keys.len();
+            let mut counts = BTreeMap::new();
+            for _ in 0..draws {
+                let (key, value) = t.sample_prefix(prefix, rng).unwrap();
+                assert_eq!(t.get(&key), Some(value));
+                *counts.entry(key).or_insert(0) += 1;
+            }
+            let e = draws as f64 / keys.len() as f64;
+            let expected = keys.into_iter().map(|k| (k, e)).collect::<BTreeMap<_, _>>();
+            let stat = chi_squared(&counts, &expected);
+            assert!(stat < chi_squared_bound(expected.len() - 1), "{:?}: {}", prefix, stat);
+        };
+        check_uniform(b"", &mut rng);
+        check_uniform(b"k/1", &mut rng);
+        check_uniform(&[0xff], &mut rng);
+        check_uniform(b"aaaaaaaaaaaa", &mut rng);
+
+        assert_eq!(t.sample_prefix(b"k/139", &mut rng), Some((b"k/1393".to_vec(), &199)));
+        assert_eq!(t.sample_prefix(b"nope", &mut rng), None);
+        assert_eq!(t.sample_prefix(b"k/1333", &mut rng), None);
+        assert_eq!(Trie::<u32>::new().sample(&mut rng), None);
+    }
+
+    #[test]
+    fn test_sample_weighted() {
+        let mut t = Trie::new();
+        for i in 0..40u32 {
+            t.insert(format!("w/{}", i).as_bytes(), i);
+        }
+        // Every key but "w/0" has a weight proportional to its value.
+        let weight = |&v: &u32| v as f64;
+        let total = (0..40).sum::<u32>() as f64;
+        let draws = 20000;
+        let expected = (1..40u32).map(|i| {
+            (format!("w/{}", i).into_bytes(), i as f64 / total * draws as f64)
+        });
+        let expected = expected.collect::<BTreeMap<_, _>>();
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = BTreeMap::new();
+        for _ in 0..draws {
+            let (key, &value) = t.sample_weighted(&mut rng, weight).unwrap();
+            assert_eq!(t.get(&key), Some(&value));
+            *counts.entry(key).or_insert(0) += 1;
+        }
+        let stat = chi_squared(&counts, &expected);
+        assert!(stat < chi_squared_bound(expected.len() - 1), "{}", stat);
+
+        assert_eq!(t.sample_weighted(&mut rng, |_| 0.0), None);
+    }
+}
@@ -0,0 +1,183 @@
+// With the `serde` feature, a `Trie` serializes as a map from byte string keys to values, in key
+// order.  Human readable formats like JSON need string map keys, so there the keys are written as
+// strings, and serializing a key that isn't UTF-8 fails.  Other formats get the raw bytes.
+
+use std::cmp;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::str;
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, Serializer};
+
+use crate::allocator::Allocator;
+use crate::trie::Trie;
+
+struct Key<'a>(&'a [u8]);
+
+impl Serialize for Key<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(self.0);
+        }
+        match str::from_utf8(self.0) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => Err(ser::Error::custom(format_args!("key {:?} is not UTF-8", self.0))),
+        }
+    }
+}
+
+impl<T: Serialize, A: Allocator> Serialize for Trie<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Some formats, like bincode, need the length up front.
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (key, value) in self.iter() {
+            map.serialize_entry(&Key(&key), value)?;
+        }
+        map.end()
+    }
+}
+
+struct KeyBuf(Vec<u8>);
+
+struct KeyVisitor;
+
+impl<'de> Visitor<'de> for KeyVisitor {
+    type Value = KeyBuf;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a byte string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<KeyBuf, E> {
+        Ok(KeyBuf(v.as_bytes().to_owned()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<KeyBuf, E> {
+        Ok(KeyBuf(v.into_bytes()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<KeyBuf, E> {
+        Ok(KeyBuf(v.to_owned()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<KeyBuf, E> {
+        Ok(KeyBuf(v))
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<KeyBuf, S::Error> {
+        let mut key = Vec::with_capacity(cmp::min(seq.size_hint().unwrap_or(0), 4096));
+        while let Some(byte) = seq.next_element()? {
+            key.push(byte);
+        }
+        Ok(KeyBuf(key))
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(KeyVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(KeyVisitor)
+        }
+    }
+}
+
+struct TrieVisitor<T, A> {
+    marker: PhantomData<fn() -> (T, A)>,
+}
+
+impl<'de, T, A> Visitor<'de> for TrieVisitor<T, A>
+where
+    T: Deserialize<'de>,
+    A: Allocator + Default,
+{
+    type Value = Trie<T, A>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a map with byte string keys")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Trie<T, A>, M::Error> {
+        // Don't trust the size hint too much, since it comes from the input.
+        let mut entries = Vec::with_capacity(cmp::min(map.size_hint().unwrap_or(0), 4096));
+        while let Some((KeyBuf(key), value)) = map.next_entry()? {
+            entries.push((key, value));
+        }
+        // We serialize in key order, so the input is usually sorted already.  Otherwise, sort it
+        // so we can still bulk load, keeping the last value for duplicate keys like `insert`
+        // would.
+        if !entries.windows(2).all(|w| w[0].0 < w[1].0) {
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            // `dedup_by` keeps the earlier of two duplicates, so move the later value into it.
+            entries.dedup_by(|later, earlier| {
+                let duplicate = later.0 == earlier.0;
+                if duplicate {
+                    mem::swap(&mut later.1, &mut earlier.1);
+                }
+                duplicate
+            });
+        }
+        Ok(Trie::from_sorted_in(entries, A::default()))
+    }
+}
+
+impl<'de, T, A> Deserialize<'de> for Trie<T, A>
+where
+    T: Deserialize<'de>,
+    A: Allocator + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(TrieVisitor { marker: PhantomData })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Counting, Trie};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_serde_json() {
+        let mut t = Trie::new();
+        for i in 0..1000u32 {
+            t.insert(format!("{}", i * 37 % 1009).as_bytes(), i);
+        }
+        let json = serde_json::to_string(&t).unwrap();
+        let model = serde_json::from_str::<BTreeMap<String, u32>>(&json).unwrap();
+        assert!(t.iter().map(|(k, &v)| (String::from_utf8(k).unwrap(), v)).eq(model));
+
+        // Sorted input is bulk loaded, so every node is only allocated once.
+        let t2 = serde_json::from_str::<Trie<u32, Counting>>(&json).unwrap();
+        assert!(t.iter().eq(t2.iter()));
+        assert_eq!(t2.allocator().live_allocations(), t2.allocator().total_allocations());
+
+        let unsorted = r#"{"b": 1, "a": 2, "ab": 3, "b": 4}"#;
+        let t3 = serde_json::from_str::<Trie<u32>>(unsorted).unwrap();
+        let entries = t3.iter().map(|(k, &v)| (k, v)).collect::<Vec<_>>();
+        assert_eq!(entries, vec![(b"a".to_vec(), 2), (b"ab".to_vec(), 3), (b"b".to_vec(), 4)]);
+
+        let mut t4 = Trie::new();
+        t4.insert(&[0xff], 1);
+        assert!(serde_json::to_string(&t4).is_err());
+    }
+
+    #[test]
+    fn test_serde_bincode() {
+        let mut t = Trie::new();
+        for i in 0..1000u32 {
+            t.insert(&i.to_be_bytes()[1..], format!("{}", i));
+        }
+        t.insert(&[], "empty".to_owned());
+        let bytes = bincode::serialize(&t).unwrap();
+        let t2 = bincode::deserialize::<Trie<String>>(&bytes).unwrap();
+        assert!(t.iter().eq(t2.iter()));
+        assert_eq!(t2.check_invariants(), Ok(()));
+
+        // The encoding is the same as a `BTreeMap` with byte string keys.
+        let model = bincode::deserialize::<BTreeMap<Vec<u8>, String>>(&bytes).unwrap();
+        assert!(t.iter().map(|(k, v)| (k, v.clone())).eq(model));
+    }
+}
@@ -0,0 +1,217 @@
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
+use std::ptr::{self, NonNull};
+
+use crate::allocator::{Allocator, Global};
+
+// Slabs are aligned to their size so we can find a block's slab by masking its address.
+const SLAB_SIZE: usize = 32 * 1024;
+// The slab header is padded out so blocks stay aligned to `CLASS_GRANULARITY`.
+const SLAB_HEADER_SIZE: usize = 16;
+const CLASS_GRANULARITY: usize = 16;
+// Apart from nodes with very long prefixes, node sizes are bounded by the Dense table's 256 slots,
+// so nearly every node fits in a size class.  Anything larger (or more aligned) goes straight to
+// `Global`.
+const MAX_BLOCK_SIZE: usize = 4096;
+const NUM_CLASSES: usize = MAX_BLOCK_SIZE / CLASS_GRANULARITY;
+
+struct SlabHeader {
+    live: usize,
+}
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+/// A size-class allocator that recycles node buffers through per-class free lists instead of
+/// returning them to the system allocator.  Since inserts and removes free one node and then
+/// allocate another of a similar size, this takes a lot of pressure off of the global allocator.
+/// Completely unused slabs are only released by `shrink_to_fit` or when the `Slab` is dropped.
+pub struct Slab {
+    free_lists: Vec<Cell<*mut FreeBlock>>,
+    slabs: RefCell<Vec<NonNull<SlabHeader>>>,
+}
+
+impl Slab {
+    pub fn new() -> Self {
+        Self {
+            free_lists: (0..NUM_CLASSES).map(|_| Cell::new(ptr::null_mut())).collect(),
+            slabs: RefCell::new(vec![]),
+        }
+    }
+
+    /// Total size of the slabs currently held, whether their blocks are in use or not.
+    pub fn allocated_bytes(&self) -> usize {
+        self.slabs.borrow().len() * SLAB_SIZE
+    }
+
+    /// Release slabs that don't have any live blocks back to the system allocator.
+    pub fn shrink_to_fit(&self) {
+        for head in &self.free_lists {
+            let mut kept = ptr::null_mut();
+            let mut cur = head.get();
+            while !cur.is_null() {
+                let next = unsafe { (*cur).next };
+                if unsafe { (*slab_of(cur.cast())).live } != 0 {
+                    unsafe { (*cur).next = kept };
+                    kept = cur;
+                }
+                cur = next;
+            }
+            head.set(kept);
+        }
+        self.slabs.borrow_mut().retain(|&slab| {
+            if unsafe { (*slab.as_ptr()).live } != 0 {
+                return true;
+            }
+            unsafe { alloc::dealloc(slab.as_ptr().cast(), slab_layout()) };
+            false
+        });
+    }
+
+    fn grow(&self, class: usize) -> Option<()> {
+        let slab = NonNull::new(unsafe { alloc::alloc(slab_layout()) })?.cast::<SlabHeader>();
+        unsafe { slab.as_ptr().write(SlabHeader { live: 0 }) };
+        self.slabs.borrow_mut().push(slab);
+
+        let base = slab.as_ptr().cast::<u8>();
+        let block_size = block_size(class);
+        let head = &self.free_lists[class];
+        let mut offset = SLAB_HEADER_SIZE;
+        while offset + block_size <= SLAB_SIZE {
+            let block = unsafe { base.add(offset).cast::<FreeBlock>() };
+            unsafe { block.write(FreeBlock { next: head.get() }) };
+            head.set(block);
+            offset += block_size;
+        }
+        Some(())
+    }
+}
+
+// The free lists and slabs are owned exclusively by this `Slab`, so it can move between threads.
+// It's not `Sync`, since allocating through `&Slab` isn't synchronized.
+unsafe impl Send for Slab {}
+
+impl Default for Slab {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
+fn size_class(layout: Layout) -> Option<usize> {
+    if layout.size() > MAX_BLOCK_SIZE || layout.align() > CLASS_GRANULARITY {
+        return None;
+    }
+    Some((layout.size() + CLASS_GRANULARITY - 1) / CLASS_GRANULARITY - 1)
+}
+
+fn block_size(class: usize) -> usize {
+    (class + 1) * CLASS_GRANULARITY
+}
+
+fn slab_of(block: *mut u8) -> *mut SlabHeader {
+    let offset = block as usize & (SLAB_SIZE - 1);
+    block.wrapping_sub(offset).cast()
+}
+
+unsafe impl Allocator for Slab {
+    fn alloc_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let class = match size_class(layout) {
+            Some(class) => class,
+            None => return Global.alloc_zeroed(layout),
+        };
+        if self.free_lists[class].get().is_null() {
+            self.grow(class)?;
+        }
+        let block = self.free_lists[class].get();
+        unsafe {
+            self.free_lists[class].set((*block).next);
+            (*slab_of(block.cast())).live += 1;
+            ptr::write_bytes(block.cast::<u8>(), 0, layout.size());
+        }
+        NonNull::new(block.cast())
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        let class = match size_class(layout) {
+            Some(class) => class,
+            None => return Global.dealloc(ptr, layout),
+        };
+        let block = ptr.as_ptr().cast::<FreeBlock>();
+        block.write(FreeBlock { next: self.free_lists[class].get() });
+        self.free_lists[class].set(block);
+        (*slab_of(ptr.as_ptr())).live -= 1;
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        for slab in self.slabs.get_mut().drain(..) {
+            unsafe { alloc::dealloc(slab.as_ptr().cast(), slab_layout()) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Slab;
+    use crate::{Counting, Trie};
+
+    #[test]
+    fn test_slab_recycling() {
+        let keys = (0..5000u32)
+            .map(|i| format!("slab/{}/{}", i % 13, i).into_bytes())
+            .collect::<Vec<_>>();
+        let slab = Slab::new();
+        let counting = Counting::new(&slab);
+        let mut t = Trie::new_in(&counting);
+        for k in &keys {
+            t.insert(k, k.len() as u64);
+        }
+        let (full_live, full_slabs) = (counting.live_bytes(), slab.allocated_bytes());
+        assert!(full_live > 0 && full_slabs >= full_live);
+
+        // Churning through removes and reinserts frees and reallocates blocks of different size
+        // classes, so it may need a few more slabs at first.  But a class only grows when all of
+        // its blocks are live, so repeating the same churn reuses those slabs.
+        let mut churned_slabs = None;
+        for _ in 0..3 {
+            for k in keys.iter().step_by(3) {
+                assert_eq!(t.remove(k), Some(k.len() as u64));
+            }
+            for k in keys.iter().step_by(3) {
+                assert!(t.insert(k, k.len() as u64).is_none());
+            }
+            assert_eq!(counting.live_bytes(), full_live);
+            let slabs = *churned_slabs.get_or_insert(slab.allocated_bytes());
+            assert_eq!(slab.allocated_bytes(), slabs);
+            assert!(slabs <= 2 * full_slabs);
+        }
+        for k in &keys {
+            assert_eq!(t.get(k), Some(&(k.len() as u64)));
+        }
+
+        for k in &keys {
+            assert_eq!(t.remove(k), Some(k.len() as u64));
+        }
+        assert_eq!(counting.live_bytes(), 0);
+        assert!(slab.allocated_bytes() > 0);
+        slab.shrink_to_fit();
+        assert_eq!(slab.allocated_bytes(), 0);
+
+        t.insert(b"again", 1);
+        assert_eq!(t.get(b"again"), Some(&1));
+        drop(t);
+
+        let mut t = Trie::with_slab();
+        t.insert(b"again", 1);
+        assert!(t.allocator().allocated_bytes() > 0);
+        assert_eq!(t.remove(b"again"), Some(1));
+        t.shrink_to_fit();
+        assert_eq!(t.allocator().allocated_bytes(), 0);
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+
+use crate::allocator::Allocator;
+use crate::header::NodeChildrenType;
+use crate::packable::Header;
+use crate::packed_node::{PackedNode, SLOT_SIZE};
+use crate::summary::Summary;
+use crate::trie::Trie;
+
+/// Number of heap allocated nodes of one `NodeChildrenType` and the bytes they use.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NodeTypeStats {
+    pub nodes: usize,
+    pub bytes: usize,
+}
+
+/// A breakdown of where a trie's memory goes, as computed by `Trie::memory_stats`.  Byte counts
+/// are for the node buffers we request from the allocator and don't include any of the
+/// allocator's own overhead.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryStats {
+    /// Leaves that couldn't be stored inline in their parent.
+    pub empty: NodeTypeStats,
+    pub pairs: NodeTypeStats,
+    pub sparse: NodeTypeStats,
+    pub dense: NodeTypeStats,
+    /// Leaves stored directly in their parent's child slot, which don't use any extra memory.
+    pub inline_leaves: usize,
+
+    pub header_bytes: usize,
+    pub prefix_bytes: usize,
+    /// Branch bytes stored in Pairs nodes.
+    pub pairs_key_bytes: usize,
+    pub sparse_bitset_bytes: usize,
+    /// Child pointer slots, including the empty ones in Dense nodes.
+    pub slot_bytes: usize,
+    /// Subtree value counts stored in nodes with children.
+    pub count_bytes: usize,
+    /// Subtree summaries stored in nodes with children, for tries with a `Summary`.
+    pub summary_bytes: usize,
+    pub value_bytes: usize,
+    /// Padding for aligning child slots, summaries, and values.
+    pub padding_bytes: usize,
+    /// Unused slots in Dense nodes' tables.
+    pub dense_empty_slots: usize,
+
+    /// Number of nodes at each depth, counting the root as depth zero.
+    pub depth_histogram: Vec<usize>,
+    /// Number of nodes with each compressed prefix length.
+    pub prefix_len_histogram: Vec<usize>,
+    /// Number of nodes with each number of children.
+    pub fanout_histogram: Vec<usize>,
+}
+
+impl MemoryStats {
+    /// Total bytes allocated for nodes.
+    pub fn total_bytes(&self) -> usize {
+        self.empty.bytes + self.pairs.bytes + self.sparse.bytes + self.dense.bytes
+    }
+
+    /// Total number of nodes, including inline leaves.
+    pub fn total_nodes(&self) -> usize {
+        self.empty.nodes + self.pairs.nodes + self.sparse.nodes + self.dense.nodes
+            + self.inline_leaves
+    }
+
+    /// Bytes spent on empty slots in Dense nodes.
+    pub fn dense_wasted_bytes(&self) -> usize {
+        self.dense_empty_slots * SLOT_SIZE
+    }
+
+    pub(crate) fn record<T, A, S: Summary<T>>(&mut self, node: &PackedNode<T, A, S>, depth: usize) {
+        if node.is_empty() {
+            return;
+        }
+        bump(&mut self.depth_histogram, depth);
+        bump(&mut self.prefix_len_histogram, node.prefix().len());
+
+        let header = match node.header() {
+            Some(header) => header,
+            None => {
+                self.inline_leaves += 1;
+                bump(&mut self.fanout_histogram, 0);
+                return;
+            }
+        };
+        let size = header.layout().size();
+        let type_stats = match header.children_type() {
+            NodeChildrenType::Empty => &mut self.empty,
+            NodeChildrenType::Pairs => &mut self.pairs,
+            NodeChildrenType::Sparse => &mut self.sparse,
+            NodeChildrenType::Dense => &mut self.dense,
+        };
+        type_stats.nodes += 1;
+        type_stats.bytes += size;
+
+        let value_len = header.value_range().map(|r| r.len()).unwrap_or(0);
+        let used = header.header_range().len()
+            + header.prefix_range().len()
+            + header.index_len()
+            + header.slots_range().len()
+            + header.count_range().len()
+            + header.summary_range().len()
+            + value_len;
+        self.header_bytes += header.header_range().len();
+        self.prefix_bytes += header.prefix_range().len();
+        match header.children_type() {
+            NodeChildrenType::Pairs => self.pairs_key_bytes += header.index_len(),
+            NodeChildrenType::Sparse => self.sparse_bitset_bytes += header.index_len(),
+            NodeChildrenType::Dense => self.dense_empty_slots += 256 - header.num_children(),
+            NodeChildrenType::Empty => (),
+        }
+        self.slot_bytes += header.slots_range().len();
+        self.count_bytes += header.count_range().len();
+        self.summary_bytes += header.summary_range().len();
+        self.value_bytes += value_len;
+        self.padding_bytes += size - used;
+        bump(&mut self.fanout_histogram, header.num_children());
+
+        for (_, child) in node.children() {
+            self.record(child, depth + 1);
+        }
+    }
+}
+
+fn bump(histogram: &mut Vec<usize>, i: usize) {
+    if histogram.len() <= i {
+        histogram.resize(i + 1, 0);
+    }
+    histogram[i] += 1;
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "total: {} bytes in {} nodes", self.total_bytes(), self.total_nodes())?;
+        for (name, s) in &[
+            ("empty", self.empty),
+            ("pairs", self.pairs),
+            ("sparse", self.sparse),
+            ("dense", self.dense),
+        ] {
+            writeln!(f, "  {}: {} nodes, {} bytes", name, s.nodes, s.bytes)?;
+        }
+        writeln!(f, "  inline: {} leaves", self.inline_leaves)?;
+        writeln!(
+            f,
+            "headers: {}, prefixes: {}, pairs keys: {}, sparse bitsets: {}, slots: {}, \
+             counts: {}, summaries: {}, values: {}, padding: {}",
+            self.header_bytes,
+            self.prefix_bytes,
+            self.pairs_key_bytes,
+            self.sparse_bitset_bytes,
+            self.slot_bytes,
+            self.count_bytes,
+            self.summary_bytes,
+            self.value_bytes,
+            self.padding_bytes,
+        )?;
+        writeln!(
+            f,
+            "dense empty slots: {} ({} bytes)",
+            self.dense_empty_slots,
+            self.dense_wasted_bytes()
+        )?;
+        writeln!(f, "depth: {:?}", self.depth_histogram)?;
+        writeln!(f, "prefix length: {:?}", self.prefix_len_histogram)?;
+        write!(f, "fanout: {:?}", self.fanout_histogram)
+    }
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Walk the trie and tally up its memory usage and shape.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        stats.record(&self.root, 0);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::summary::Summary;
+    use crate::{Counting, Trie};
+
+    #[test]
+    fn test_memory_stats() {
+        let alloc = Counting::default();
+        let mut t = Trie::new_in(&alloc);
+        for i in 0..20000u32 {
+            // Leaves with the longer suffixes won't fit inline next to their `u32` values.
+            let mut key = vec![(i / 250) as u8, (i % 250) as u8];
+            key.extend((0..(i % 5)).map(|j| j as u8));
+            t.insert(&key, i);
+        }
+        let stats = t.memory_stats();
+        let report = stats.to_string();
+        assert!(report.starts_with(&format!("total: {} bytes", stats.total_bytes())));
+
+        assert_eq!(stats.total_bytes(), alloc.live_bytes());
+        assert_eq!(stats.total_nodes() - stats.inline_leaves, alloc.live_allocations());
+        assert_eq!(
+            stats.total_bytes(),
+            stats.header_bytes
+                + stats.prefix_bytes
+                + stats.pairs_key_bytes
+                + stats.sparse_bitset_bytes
+                + stats.slot_bytes
+                + stats.count_bytes
+                + stats.summary_bytes
+                + stats.value_bytes
+                + stats.padding_bytes
+        );
+        assert!(stats.inline_leaves > 0);
+        assert!(stats.empty.nodes > 0);
+        assert!(stats.sparse.nodes > 0);
+        assert!(stats.dense.nodes > 0);
+        assert_eq!(stats.value_bytes, (20000 - stats.inline_leaves) * 4);
+        for histogram in &[
+            &stats.depth_histogram,
+            &stats.prefix_len_histogram,
+            &stats.fanout_histogram,
+        ] {
+            assert_eq!(histogram.iter().sum::<usize>(), stats.total_nodes());
+        }
+        assert_eq!(Trie::<()>::new().memory_stats(), Default::default());
+    }
+
+    #[test]
+    fn test_memory_stats_summary() {
+        #[derive(Clone)]
+        struct Sum(u64);
+
+        impl Summary<u32> for Sum {
+            fn empty() -> Self {
+                Sum(0)
+            }
+
+            fn of(value: &u32) -> Self {
+                Sum(*value as u64)
+            }
+
+            fn combine(&self, other: &Self) -> Self {
+                Sum(self.0 + other.0)
+            }
+        }
+
+        let alloc = Counting::default();
+        let mut t = Trie::<u32, _, Sum>::with_summary_in(&alloc);
+        for i in 0..1000u32 {
+            t.insert(&[(i / 100) as u8, (i % 100) as u8, 0, 0, 0], i);
+        }
+        let stats = t.memory_stats();
+        assert_eq!(stats.total_bytes(), alloc.live_bytes());
+        // Every node with children stores an `Option<Sum>`.
+        let parents = stats.pairs.nodes + stats.sparse.nodes + stats.dense.nodes;
+        assert_eq!(stats.summary_bytes, parents * 16);
+        assert_eq!(
+            stats.total_bytes(),
+            stats.header_bytes
+                + stats.prefix_bytes
+                + stats.pairs_key_bytes
+                + stats.sparse_bitset_bytes
+                + stats.slot_bytes
+                + stats.count_bytes
+                + stats.summary_bytes
+                + stats.value_bytes
+                + stats.padding_bytes
+        );
+    }
+}
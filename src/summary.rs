@@ -0,0 +1,351 @@
+// Tries can keep an aggregate of the values below each node, like their sum or maximum, so that
+// questions about all of the values under a prefix or in a key range don't have to visit each
+// of them.  Nodes with children store their subtree's summary next to its count, and leaves
+// compute theirs from their value when asked.  A range of keys splits into whole subtrees plus
+// the nodes along the paths to its two ends, so summarizing one only combines the summaries of
+// the children along those two paths.
+
+use std::ops::{Bound, RangeBounds};
+
+use crate::allocator::{Allocator, Global};
+use crate::packed_node::PackedNode;
+use crate::prefix::{self, byte_bounds};
+use crate::trie::Trie;
+
+/// An aggregate of a set of values that can be built up from the aggregates of its parts, such
+/// as their count, sum, or maximum.  Summaries get combined in key order, so `combine` has to be
+/// associative but needn't be commutative.
+pub trait Summary<T>: Clone {
+    /// The summary of no values, which leaves any summary it's combined with unchanged.
+    fn empty() -> Self;
+
+    /// The summary of a single value.
+    fn of(value: &T) -> Self;
+
+    /// The summary of `self`'s values followed by `other`'s.
+    fn combine(&self, other: &Self) -> Self;
+}
+
+// Tries without a summary use `()`, which nodes don't spend any space on.
+impl<T> Summary<T> for () {
+    fn empty() -> Self {}
+
+    fn of(_: &T) -> Self {}
+
+    fn combine(&self, _: &Self) -> Self {}
+}
+
+impl<T, A, S: Summary<T>> PackedNode<T, A, S> {
+    // The summary of every value in this node's subtree.  Nodes whose summary hasn't been filled
+    // in yet, because a `Summary` panicked partway through an update, compute it from their
+    // children instead.
+    pub fn summary(&self) -> S {
+        match self.stored_summary() {
+            Some(Some(summary)) => summary.clone(),
+            _ => Self::summarize(self.value(), self.children().map(|(_, child)| child)),
+        }
+    }
+
+    // Summarize a node from its own value and its children, in key order.
+    pub fn summarize<'a>(value: Option<&T>, children: impl Iterator<Item = &'a Self>) -> S
+    where
+        Self: 'a,
+    {
+        let init = value.map(S::of).unwrap_or_else(S::empty);
+        children.fold(init, |summary, child| summary.combine(&child.summary()))
+    }
+
+    // Empty this node's summary slot before changing anything below it, so that it never holds
+    // a stale summary, even if we unwind before `update_summary` gets to it.
+    pub fn clear_summary(&mut self) {
+        if let Some(stored) = self.stored_summary_mut() {
+            drop(stored.take());
+        }
+    }
+
+    // Fill in the empty summary slots at and below this node, bottom up.  Mutations clear the
+    // slots along the path they change and pack new nodes with empty slots, and never run user
+    // code themselves, so the trie is already in its new state if a `Summary` panics here.  A
+    // node's summary is only filled in once its children's are, so this only descends into the
+    // nodes that were changed.
+    pub fn update_summary(&mut self) {
+        if !matches!(self.stored_summary(), Some(None)) {
+            return;
+        }
+        for child in self.slots_mut() {
+            child.update_summary();
+        }
+        let summary = Self::summarize(self.value(), self.children().map(|(_, child)| child));
+        *self.stored_summary_mut().unwrap() = Some(summary);
+    }
+
+    // Visit the entries below this node whose keys fall in `range`, in key order, where `key`
+    // holds the bytes leading up to the node.  Subtrees that lie entirely inside of the range
+    // are passed to `f` as a whole, with `true`, without visiting their entries.  The nodes along
+    // the range's edges are passed with `false` when just their own value is in it, so this only
+    // descends along the paths to the range's two ends.
+    pub fn for_each_in_range<'a>(
+        &'a self,
+        key: &mut Vec<u8>,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+        f: &mut impl FnMut(&'a Self, bool),
+    ) {
+        if self.is_empty() {
+            return;
+        }
+        let key_len = key.len();
+        key.extend_from_slice(self.prefix());
+
+        match prefix::range_contains_prefix(range, key) {
+            Some(true) => f(self, true),
+            Some(false) => (),
+            None => {
+                if self.has_value() && range.contains(&key[..]) {
+                    f(self, false);
+                }
+                for (byte, child) in self.children() {
+                    key.push(byte);
+                    child.for_each_in_range(key, range, f);
+                    key.pop();
+                }
+            }
+        }
+        key.truncate(key_len);
+    }
+}
+
+impl<T, S: Summary<T>> Trie<T, Global, S> {
+    /// Create a trie that keeps an `S` summary of the values below each of its nodes.
+    pub fn with_summary() -> Self {
+        Self::with_summary_in(Global)
+    }
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    /// Like `with_summary`, but allocating nodes from `alloc`.
+    pub fn with_summary_in(alloc: A) -> Self {
+        Self {
+            root: PackedNode::empty(),
+            alloc,
+        }
+    }
+
+    /// The summary of every value in the trie.
+    pub fn summary(&self) -> S {
+        self.root.summary()
+    }
+
+    /// The summary of the values whose keys start with `prefix`.  This walks down to the node
+    /// holding them and returns the summary it stores.
+    pub fn summarize_prefix(&self, prefix: &[u8]) -> S {
+        match self.root.find_prefix(prefix) {
+            Some((node, _)) => node.summary(),
+            None => S::empty(),
+        }
+    }
+
+    /// The summary of the values whose keys fall in `range`.  This combines the stored
+    /// summaries of the subtrees inside the range, so it only looks at the children of the
+    /// nodes along the paths to the range's two ends.
+    pub fn summarize_range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> S {
+        let mut summary = S::empty();
+        self.root.for_each_in_range(&mut vec![], byte_bounds(&range), &mut |node, whole| {
+            let part = if whole { node.summary() } else { node.value().map(S::of).unwrap() };
+            summary = summary.combine(&part);
+        });
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Summary;
+    use crate::packed_node::PackedNode;
+    use crate::test_helpers::{random_bound, random_key, random_updates};
+    use crate::{Bump, Counting, Trie};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+    use std::ops::RangeBounds;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    // Keeping the first value checks that summaries get combined in key order.
+    #[derive(Clone, Debug, PartialEq)]
+    struct Stats {
+        count: usize,
+        sum: u64,
+        max: Option<u32>,
+        first: Option<u32>,
+    }
+
+    thread_local! {
+        // How many more values `Stats` can summarize or combine before panicking.
+        static FUEL: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    fn spend_fuel() {
+        if let Some(fuel) = FUEL.with(Cell::get) {
+            FUEL.with(|f| f.set(fuel.checked_sub(1)));
+            assert_ne!(fuel, 0, "out of fuel");
+        }
+    }
+
+    impl Summary<u32> for Stats {
+        fn empty() -> Self {
+            Stats { count: 0, sum: 0, max: None, first: None }
+        }
+
+        fn of(&value: &u32) -> Self {
+            spend_fuel();
+            Stats { count: 1, sum: value as u64, max: Some(value), first: Some(value) }
+        }
+
+        fn combine(&self, other: &Self) -> Self {
+            spend_fuel();
+            Stats {
+                count: self.count + other.count,
+                sum: self.sum + other.sum,
+                max: self.max.max(other.max),
+                first: self.first.or(other.first),
+            }
+        }
+    }
+
+    fn summarize<'a>(entries: impl Iterator<Item = (&'a Vec<u8>, &'a u32)>) -> Stats {
+        entries.fold(Stats::empty(), |s, (_, v)| s.combine(&Stats::of(v)))
+    }
+
+    // Check that every summary a node stores is up to date.  With `complete`, check that
+    // they've all been filled in too.
+    fn check_stored<A>(node: &PackedNode<u32, A, Stats>, complete: bool) {
+        if let Some(stored) = node.stored_summary() {
+            let children = node.children().map(|(_, child)| child);
+            let expected = PackedNode::summarize(node.value(), children);
+            match stored {
+                Some(summary) => assert_eq!(summary, &expected),
+                None => assert!(!complete, "summary wasn't filled in"),
+            }
+        }
+        for (_, child) in node.children() {
+            check_stored(child, complete);
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut t = Trie::<u32, _, Stats>::with_summary();
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = random_updates(&mut t, &mut rng, |t, model, rng| {
+            t.check_invariants().unwrap();
+            check_stored(&t.root, true);
+            assert_eq!(t.summary(), summarize(model.iter()));
+
+            let mut prefix = random_key(rng);
+            prefix.truncate(rng.gen_range(0, 3));
+            let expected = summarize(model.iter().filter(|(k, _)| k.starts_with(&prefix)));
+            assert_eq!(t.summarize_prefix(&prefix), expected, "{:?}", prefix);
+
+            let range = (random_bound(rng), random_bound(rng));
+            let expected = summarize(model.iter().filter(|(k, _)| range.contains(k)));
+            assert_eq!(t.summarize_range(range.clone()), expected, "{:?}", range);
+        });
+        assert_eq!(t.summarize_range(b"x".to_vec()..), summarize(model.range(b"x".to_vec()..)));
+    }
+
+    // A `Summary` that panics partway through an update leaves the trie changed, with the
+    // summaries it didn't get to left to be computed when asked for, and doesn't leak anything.
+    #[test]
+    fn test_panicking_summary() {
+        let counting = Counting::default();
+        let mut t = Trie::<u32, _, Stats>::with_summary_in(&counting);
+        let mut model = BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut panics = 0;
+
+        for i in 0..500u32 {
+            let key = random_key(&mut rng);
+            let remove = rng.gen_range(0, 3) == 0;
+            FUEL.with(|f| f.set(Some(rng.gen_range(0, 20))));
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                if remove {
+                    assert_eq!(t.remove(&key), model.remove(&key));
+                } else {
+                    assert_eq!(t.insert(&key, i), model.insert(key.clone(), i));
+                }
+            }));
+            let panicked = FUEL.with(Cell::get).is_none();
+            FUEL.with(|f| f.set(None));
+            if panicked {
+                assert!(result.is_err());
+                panics += 1;
+                // Only the summaries are behind, so the change itself went through.
+                if remove {
+                    model.remove(&key);
+                } else {
+                    model.insert(key, i);
+                }
+            } else {
+                result.unwrap();
+            }
+
+            t.check_invariants().unwrap();
+            check_stored(&t.root, !panicked);
+            assert!(t.iter().map(|(k, &v)| (k, v)).eq(model.clone().into_iter()));
+            assert_eq!(t.summary(), summarize(model.iter()));
+        }
+        assert!(panics > 50);
+        drop(t);
+        assert_eq!(counting.live_allocations(), 0);
+    }
+
+    // Summaries live in the node buffers, so they have to be dropped along with them.
+    #[test]
+    fn test_summary_drop() {
+        #[derive(Clone)]
+        struct Tracked {
+            _tracker: Rc<()>,
+        }
+
+        thread_local!(static TRACKER: Rc<()> = Rc::new(()));
+
+        impl Summary<u64> for Tracked {
+            fn empty() -> Self {
+                Tracked { _tracker: TRACKER.with(Rc::clone) }
+            }
+
+            fn of(_: &u64) -> Self {
+                Self::empty()
+            }
+
+            fn combine(&self, _: &Self) -> Self {
+                Self::empty()
+            }
+        }
+
+        let counting = Counting::default();
+        let mut t = Trie::<u64, _, Tracked>::with_summary_in(&counting);
+        for i in 0..1000u64 {
+            t.insert(format!("{}", i * 37).as_bytes(), i);
+        }
+        for i in 0..500u64 {
+            t.remove(format!("{}", i * 74).as_bytes());
+        }
+        assert_eq!(t.len(), 500);
+        assert!(TRACKER.with(Rc::strong_count) > 1);
+        drop(t);
+        assert_eq!(TRACKER.with(Rc::strong_count), 1);
+        assert_eq!(counting.live_allocations(), 0);
+
+        // `Bump` never frees nodes one by one, but the summaries in them still need dropping.
+        let bump = Bump::new();
+        let mut t = Trie::<u64, _, Tracked>::with_summary_in(&bump);
+        for i in 0..1000u64 {
+            t.insert(format!("{}", i * 37).as_bytes(), i);
+        }
+        assert!(TRACKER.with(Rc::strong_count) > 1);
+        drop(t);
+        assert_eq!(TRACKER.with(Rc::strong_count), 1);
+    }
+}
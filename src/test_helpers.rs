@@ -0,0 +1,59 @@
+// Helpers for tests that check a trie against a `BTreeMap` model through random updates.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use crate::summary::Summary;
+use crate::{Global, Trie};
+
+pub fn random_key(rng: &mut StdRng) -> Vec<u8> {
+    match rng.gen_range(0, 4) {
+        // Short keys over a small alphabet split, branch, and merge nodes, and make for lots of
+        // keys that are prefixes of each other.
+        0 | 1 => (0..rng.gen_range(0, 6)).map(|_| rng.gen_range(b'a', b'd')).collect(),
+        // Keys under "x" fill up Sparse and Dense nodes.
+        2 => vec![b'x', rng.gen()],
+        // Long keys have long prefixes, with a short chain of nodes below them.  Insert and
+        // remove recurse once per node, so a long chain would overflow a test thread's stack.
+        _ => {
+            let mut key = vec![b'l'; 70];
+            key.push(rng.gen_range(b'a', b'c'));
+            key.extend(vec![b'm'; rng.gen_range(0, 10)]);
+            key
+        }
+    }
+}
+
+pub fn random_bound(rng: &mut StdRng) -> Bound<Vec<u8>> {
+    match rng.gen_range(0, 3) {
+        0 => Bound::Included(random_key(rng)),
+        1 => Bound::Excluded(random_key(rng)),
+        _ => Bound::Unbounded,
+    }
+}
+
+// Make 3000 random inserts and removes to `t`, checking them against a model, and call `check`
+// after every tenth one.  Returns the model.
+pub fn random_updates<S: Summary<u32>>(
+    t: &mut Trie<u32, Global, S>,
+    rng: &mut StdRng,
+    mut check: impl FnMut(&Trie<u32, Global, S>, &BTreeMap<Vec<u8>, u32>, &mut StdRng),
+) -> BTreeMap<Vec<u8>, u32> {
+    let mut model = BTreeMap::new();
+    for i in 0..3000u32 {
+        let key = random_key(rng);
+        if rng.gen_range(0, 3) == 0 {
+            assert_eq!(t.remove(&key), model.remove(&key));
+        } else {
+            let value = rng.gen_range(0, 1000);
+            assert_eq!(t.insert(&key, value), model.insert(key, value));
+        }
+        if i % 10 == 0 {
+            check(t, &model, rng);
+        }
+    }
+    model
+}
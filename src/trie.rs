@@ -1,47 +1,106 @@
+use crate::allocator::{Allocator, Global};
 use crate::packed_node::PackedNode;
+use crate::prefix;
+use crate::slab::Slab;
+use crate::summary::Summary;
 use std::io;
+use std::mem;
 
-pub struct Trie<T> {
-    pub(crate) root: PackedNode<T>,
+pub struct Trie<T, A: Allocator = Global, S: Summary<T> = ()> {
+    pub(crate) root: PackedNode<T, A, S>,
+    pub(crate) alloc: A,
 }
 
 impl<T> Trie<T> {
     pub fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<T> Trie<T, Slab> {
+    /// Create a trie that recycles its node allocations through its own `Slab` allocator.
+    pub fn with_slab() -> Self {
+        Self::new_in(Slab::new())
+    }
+
+    /// Return slabs that aren't holding any nodes to the system allocator.
+    pub fn shrink_to_fit(&mut self) {
+        self.alloc.shrink_to_fit()
+    }
+}
+
+impl<T, A: Allocator> Trie<T, A> {
+    pub fn new_in(alloc: A) -> Self {
         Self {
             root: PackedNode::empty(),
+            alloc,
+        }
+    }
+
+    // Values are summarized when they're inserted, so tries with a summary can't hand out
+    // mutable references to them.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut T> {
+        let mut cur = &mut self.root;
+        let mut key = key;
+        loop {
+            let node_prefix_len = cur.prefix().len();
+            if !prefix::starts_with(key, cur.prefix()) {
+                return None;
+            }
+            let (&branch_byte, rest) = match key[node_prefix_len..].split_first() {
+                None => return cur.value_mut(),
+                Some(p) => p,
+            };
+            key = rest;
+            cur = cur.lookup_mut(branch_byte)?;
         }
     }
+}
+
+impl<T, A: Allocator, S: Summary<T>> Trie<T, A, S> {
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    /// The number of keys in the trie.  Nodes keep count of the values below them, so this
+    /// doesn't need to walk the trie.
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_empty()
+    }
 
     pub fn get(&self, key: &[u8]) -> Option<&T> {
         let mut cur = &self.root;
-        let mut key_iter = key.iter();
+        let mut key = key;
         loop {
-            for byte in cur.prefix() {
-                match key_iter.next() {
-                    Some(key_byte) if key_byte != byte => return None,
-                    None => return None,
-                    Some(..) => continue,
-                }
+            let node_prefix = cur.prefix();
+            if !prefix::starts_with(key, node_prefix) {
+                return None;
             }
-            let branch_byte = match key_iter.next() {
+            let (&branch_byte, rest) = match key[node_prefix.len()..].split_first() {
                 None => return cur.value(),
-                Some(&k) => k,
+                Some(p) => p,
             };
+            key = rest;
             cur = cur.lookup(branch_byte)?;
         }
     }
 
-    pub fn get_mut(&self, key: &[u8]) -> Option<&mut T> {
-        self.get(key)
-            .map(|p| unsafe { &mut *(p as *const _ as *mut _) })
-    }
-
     pub fn insert(&mut self, key: &[u8], value: T) -> Option<T> {
-        self.root.insert(key, value)
+        let old_value = self.root.insert(key, value, &self.alloc);
+        self.root.update_summary();
+        self.debug_check_invariants();
+        old_value
     }
 
     pub fn remove(&mut self, key: &[u8]) -> Option<T> {
-        self.root.remove(key)
+        let value = self.root.remove(key, &self.alloc);
+        self.root.update_summary();
+        self.debug_check_invariants();
+        value
     }
 
     pub fn debug(&self, out: &mut impl io::Write) -> io::Result<()> {
@@ -49,9 +108,22 @@ impl<T> Trie<T> {
     }
 }
 
+impl<T, A: Allocator, S: Summary<T>> Drop for Trie<T, A, S> {
+    fn drop(&mut self) {
+        // If neither the allocator nor the values and summaries need any cleanup, we don't have
+        // to visit the nodes at all: the allocator frees them all at once when it's dropped.
+        if A::NOOP_DEALLOC && !mem::needs_drop::<T>() && !mem::needs_drop::<S>() {
+            mem::forget(mem::replace(&mut self.root, PackedNode::empty()));
+            return;
+        }
+        self.root.drop_in(&self.alloc);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Trie;
+    use std::collections::BTreeSet;
     use std::io;
 
 
@@ -87,4 +159,155 @@ mod tests {
 
         eprintln!("root {:?}", t.debug(&mut io::stdout().lock()));
     }
+
+    #[test]
+    fn test_inline_values() {
+        let mut t = Trie::new();
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| format!("{}", i * 7).into_bytes()).collect();
+        for (i, k) in keys.iter().enumerate() {
+            assert!(t.insert(k, i as u32).is_none());
+        }
+        assert!(!t.root.is_inline());
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(t.get(k), Some(&(i as u32)));
+        }
+        assert!(t.iter().map(|(_, &v)| keys[v as usize].clone()).eq(t.iter().map(|(k, _)| k)));
+
+        for (i, k) in keys.iter().enumerate().step_by(2) {
+            assert_eq!(t.insert(k, i as u32 + 1), Some(i as u32));
+            assert_eq!(t.remove(k), Some(i as u32 + 1));
+        }
+        for (i, k) in keys.iter().enumerate() {
+            let expected = if i % 2 == 0 { None } else { Some(i as u32) };
+            assert_eq!(t.get(k).cloned(), expected);
+        }
+
+        // A single short key ends up inline in the root itself.
+        let mut t = Trie::new();
+        t.insert(b"ab", 1u16);
+        assert!(t.root.is_inline());
+        assert_eq!(t.get(b"ab"), Some(&1));
+        assert_eq!(t.remove(b"ab"), Some(1));
+        assert!(t.root.is_empty());
+    }
+
+    #[test]
+    fn test_long_prefixes() {
+        let base = format!("https://example.com/{}/", "very/long/path/".repeat(20));
+        let keys = (0..100u32)
+            .map(|i| format!("{}{}/{}", base, i % 3, "x".repeat(i as usize)).into_bytes())
+            .collect::<Vec<_>>();
+        let mut t = Trie::new();
+        for (i, k) in keys.iter().enumerate() {
+            assert!(t.insert(k, i).is_none());
+        }
+        // The shared prefix lives in a single node rather than a chain of 63 byte ones.
+        assert_eq!(t.root.prefix(), base.as_bytes());
+        assert_eq!(t.check_invariants(), Ok(()));
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(t.get(k), Some(&i));
+        }
+        assert!(t.iter().map(|(k, _)| k).eq(keys.iter().cloned().collect::<BTreeSet<_>>()));
+
+        for (i, k) in keys.iter().enumerate().skip(1) {
+            assert_eq!(t.remove(k), Some(i));
+        }
+        // Removing everything else merges the whole key back into the root.
+        assert_eq!(t.root.prefix(), &keys[0][..]);
+        assert_eq!(t.get(&keys[0]), Some(&0));
+    }
+
+    #[test]
+    fn test_dense_refill() {
+        // Refilling an emptied Dense slot happens in place, and still has to count the child.
+        let mut t = Trie::new();
+        for i in 0..=255u8 {
+            t.insert(&[i, i], ());
+        }
+        assert_eq!(t.remove(&[7, 7]), Some(()));
+        t.insert(&[7, 7], ());
+        let mut out = vec![];
+        t.debug(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.lines().next().unwrap().ends_with("children_type: Dense(256) }"), "{}", out);
+    }
+
+    #[test]
+    fn test_inline_drops() {
+        use std::cell::Cell;
+
+        thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+        struct CountDrops(u8);
+        impl Drop for CountDrops {
+            fn drop(&mut self) {
+                DROPS.with(|d| d.set(d.get() + 1));
+            }
+        }
+
+        let mut t = Trie::new();
+        for i in 0..100u8 {
+            t.insert(&[i / 10, i % 10], CountDrops(i));
+        }
+        assert_eq!(DROPS.with(|d| d.get()), 0);
+        assert_eq!(t.remove(&[0, 0]).map(|v| v.0), Some(0));
+        assert_eq!(DROPS.with(|d| d.get()), 1);
+        drop(t);
+        assert_eq!(DROPS.with(|d| d.get()), 100);
+    }
+
+    #[test]
+    fn test_inline_drops_in() {
+        use crate::Counting;
+        use std::cell::Cell;
+
+        thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+        struct CountDrops(u8);
+        impl Drop for CountDrops {
+            fn drop(&mut self) {
+                DROPS.with(|d| d.set(d.get() + 1));
+            }
+        }
+
+        let counting = Counting::default();
+        let mut t = Trie::new_in(&counting);
+        for i in 0..100u8 {
+            t.insert(&[i / 10, i % 10], CountDrops(i));
+        }
+        // The leaves are all inline, so only the root and the ten nodes below it are allocated.
+        assert_eq!(counting.live_allocations(), 11);
+        assert_eq!(t.remove(&[0, 0]).map(|v| v.0), Some(0));
+        assert_eq!(DROPS.with(|d| d.get()), 1);
+        drop(t);
+        // Dropping the trie frees its nodes through `counting` and drops the inline values.
+        assert_eq!(DROPS.with(|d| d.get()), 100);
+        assert_eq!(counting.live_allocations(), 0);
+    }
+
+    #[test]
+    fn test_panicking_drop() {
+        use crate::Counting;
+        use std::panic::{self, AssertUnwindSafe};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct PanicOnDrop(u32);
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::SeqCst);
+                if self.0 == 500 {
+                    panic!("dropping {}", self.0);
+                }
+            }
+        }
+
+        let counting = Counting::default();
+        let mut t = Trie::new_in(&counting);
+        for i in 0..1000 {
+            t.insert(format!("{}", i).as_bytes(), PanicOnDrop(i));
+        }
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| drop(t))).is_err());
+        // The panic didn't stop the rest of the trie from being freed.
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1000);
+        assert_eq!(counting.live_allocations(), 0);
+    }
 }